@@ -0,0 +1,134 @@
+/* بسم الله الرحمن الرحيم
+
+   Aati - Cross-platform Package Manager written in Rust.
+   Copyright (C) 2023  Husayn Haras <haras@disroot.org>
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of version 3 of the GNU General Public License
+   as published by the Free Software Foundation.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use colored::Colorize;
+
+use super::remove;
+use crate::{
+    error::AatiError,
+    types::{InstallReason, LockFile, Package},
+    utils::{get_aati_lock, prompt_yn},
+};
+
+pub fn command(force: bool, quiet: bool) -> Result<(), AatiError> {
+    let lock_file: LockFile = toml::from_str(&get_aati_lock()?).unwrap();
+
+    let orphans = get_orphans(&lock_file.package);
+
+    if orphans.is_empty() {
+        if !quiet {
+            println!("{}", "+ No orphaned packages to remove".bright_green());
+        }
+
+        return Ok(());
+    }
+
+    let orphans = remove::order_for_removal(&orphans, &lock_file.package);
+
+    if !quiet {
+        println!(
+            "{}",
+            "+ Automatically installed packages that are no longer needed:".bright_green()
+        );
+
+        for package_name in &orphans {
+            let package = lock_file
+                .package
+                .iter()
+                .find(|pkg| &pkg.name == package_name)
+                .unwrap();
+
+            println!(
+                "{}   {}/{}-{}",
+                "+".bright_green(),
+                package.source,
+                package.name,
+                package.version
+            );
+        }
+    }
+
+    if force || prompt_yn("/ Are you sure you want to remove them?")? {
+        for package_name in &orphans {
+            if !quiet {
+                println!(
+                    "{}",
+                    format!("+ Removing '{}'...", package_name).bright_green()
+                );
+            }
+
+            remove::command(package_name, true, quiet)?;
+        }
+
+        if !quiet {
+            println!(
+                "{}",
+                "+ Finished removing orphaned packages!".bright_green()
+            );
+        }
+    } else if !quiet {
+        println!("{}", "+ Transaction aborted".bright_green());
+    }
+
+    Ok(())
+}
+
+// Automatically installed packages that aren't needed by any package that's
+// staying installed
+pub fn get_orphans(installed_packages: &[Package]) -> Vec<String> {
+    let mut orphans: Vec<String> = Vec::new();
+
+    while let Some(orphan) = installed_packages.iter().find(|pkg| {
+        pkg.reason == InstallReason::Automatic
+            && !orphans.contains(&pkg.name)
+            && installed_packages.iter().all(|other_pkg| {
+                other_pkg.name == pkg.name
+                    || orphans.contains(&other_pkg.name)
+                    || !other_pkg.dependencies.contains(&pkg.name)
+            })
+    }) {
+        orphans.push(orphan.name.clone());
+    }
+
+    orphans
+}
+
+#[test]
+fn test_get_orphans() {
+    let package = |name: &str, reason: InstallReason, dependencies: &[&str]| Package {
+        reason,
+        dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+        ..Package::test(name)
+    };
+
+    let mut installed_packages = vec![
+        package("libbase", InstallReason::Automatic, &[]),
+        package("libfoo", InstallReason::Automatic, &["libbase"]),
+        package("app", InstallReason::Explicit, &["libfoo"]),
+        package("libold", InstallReason::Automatic, &[]),
+    ];
+
+    assert_eq!(get_orphans(&installed_packages), vec!["libold".to_string()]);
+
+    installed_packages.retain(|pkg| pkg.name != "app");
+
+    assert_eq!(
+        get_orphans(&installed_packages),
+        vec!["libfoo".to_string(), "libbase".into(), "libold".into()]
+    );
+}
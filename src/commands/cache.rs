@@ -0,0 +1,433 @@
+/* بسم الله الرحمن الرحيم
+
+   Aati - Cross-platform Package Manager written in Rust.
+   Copyright (C) 2023  Husayn Haras <haras@disroot.org>
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of version 3 of the GNU General Public License
+   as published by the Free Software Foundation.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use colored::Colorize;
+use humansize::{format_size, BINARY};
+use std::{
+    fs::{read_dir, remove_file},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use toml::Value;
+
+use super::{get, install::parse_filename};
+use crate::{
+    error::AatiError,
+    utils::{compare_versions, get_cache_path_buf, is_offline, parse_aati_config},
+};
+
+// A tarball kept at ~/.aati/cache/<repo>/<target>/<name>-<version>.tar.lz4
+pub struct CachedPackage {
+    pub repo: String,
+    pub target: String,
+    pub name: String,
+    pub version: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+// Where the tarball of an extract_package() result is cached
+pub fn get_cached_path(extracted_package: &[String]) -> PathBuf {
+    get_cache_path_buf()
+        .join(&extracted_package[0])
+        .join(&extracted_package[3])
+        .join(format!(
+            "{}-{}.tar.lz4",
+            extracted_package[1], extracted_package[2]
+        ))
+}
+
+// Offline, only packages that are in the cache can be installed. Checked
+// before anything is removed to make room for them.
+pub fn check_available(extracted_package: &[String]) -> Result<(), AatiError> {
+    if is_offline()? && !is_available(extracted_package)? {
+        return Err(AatiError::NotCached {
+            package_name: extracted_package[1].clone(),
+            version: extracted_package[2].clone(),
+        });
+    }
+
+    Ok(())
+}
+
+// Whether the cached tarball of a package matches the checksum its
+// repository lists, so a truncated or corrupted one doesn't count
+pub fn is_available(extracted_package: &[String]) -> Result<bool, AatiError> {
+    Ok(match get::get_checksum(extracted_package)? {
+        Some(checksum) => get::is_cached(&get_cached_path(extracted_package), &checksum),
+        None => false,
+    })
+}
+
+pub fn list() -> Result<(), AatiError> {
+    let cached_packages = get_cached_packages()?;
+
+    println!("{}", "+ Cached Packages:".bright_green());
+
+    if !cached_packages.is_empty() {
+        for cached_package in &cached_packages {
+            println!(
+                "{}   {}/{}/{}-{} ({})",
+                "+".bright_green(),
+                cached_package.repo,
+                cached_package.target,
+                cached_package.name,
+                cached_package.version,
+                format_size(cached_package.size, BINARY)
+            );
+        }
+
+        println!(
+            "{}",
+            format!(
+                "+ Total: {}",
+                format_size(cached_packages.iter().map(|p| p.size).sum::<u64>(), BINARY)
+            )
+            .bright_green()
+        );
+    } else {
+        println!("    None!");
+    }
+
+    Ok(())
+}
+
+// Delete cached tarballs, keeping the newest `keep` versions of each package
+pub fn clean(keep: Option<usize>, quiet: bool) -> Result<(), AatiError> {
+    let cached_packages = get_cached_packages()?;
+    let to_be_removed = select_old_versions(&cached_packages, keep.unwrap_or(0));
+
+    let freed_size = delete(&to_be_removed, 119)?;
+
+    if !quiet {
+        println!(
+            "{}",
+            format!(
+                "+ Removed {} cached package(s), freeing {}",
+                to_be_removed.len(),
+                format_size(freed_size, BINARY)
+            )
+            .bright_green()
+        );
+    }
+
+    Ok(())
+}
+
+// Delete the least recently used tarballs until the cache fits in the
+// `max_size_mib` set under [cache] in rc.toml. It's done once a whole
+// operation is over, as tarballs downloaded ahead are still waiting to be
+// installed until then. Failing to do so doesn't fail the operation.
+pub fn trim(quiet: bool) {
+    if let Err(error) = trim_to_max_size(quiet) {
+        eprintln!("{}", format!("- {}", error).bright_red());
+    }
+}
+
+fn trim_to_max_size(quiet: bool) -> Result<(), AatiError> {
+    let aati_config: Value = parse_aati_config(147)?;
+
+    let max_size = match aati_config
+        .get("cache")
+        .and_then(|cache| cache.get("max_size_mib"))
+        .and_then(|max_size| max_size.as_integer())
+    {
+        Some(max_size_mib) if max_size_mib >= 0 => max_size_mib as u64 * 1024 * 1024,
+        _ => return Ok(()),
+    };
+
+    let mut cached_packages = get_cached_packages()?;
+    cached_packages.sort_by_key(|p| p.modified);
+
+    let mut total_size: u64 = cached_packages.iter().map(|p| p.size).sum();
+    let mut to_be_removed = Vec::new();
+
+    for cached_package in &cached_packages {
+        if total_size <= max_size {
+            break;
+        }
+
+        total_size -= cached_package.size;
+        to_be_removed.push(cached_package);
+    }
+
+    if !to_be_removed.is_empty() {
+        let freed_size = delete(&to_be_removed, 120)?;
+
+        if !quiet {
+            println!(
+                "{}",
+                format!(
+                    "+ Trimmed the package cache by {}",
+                    format_size(freed_size, BINARY)
+                )
+                .bright_green()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn delete(cached_packages: &[&CachedPackage], code: u16) -> Result<u64, AatiError> {
+    let mut freed_size = 0;
+
+    for cached_package in cached_packages {
+        match remove_file(&cached_package.path) {
+            Ok(_) => freed_size += cached_package.size,
+            Err(error) => {
+                return Err(AatiError::DeleteFile {
+                    code,
+                    path: cached_package.path.clone(),
+                    error,
+                });
+            }
+        }
+    }
+
+    Ok(freed_size)
+}
+
+// Every version of a package except its newest `keep` ones
+pub fn select_old_versions(cached_packages: &[CachedPackage], keep: usize) -> Vec<&CachedPackage> {
+    let mut sorted_packages: Vec<&CachedPackage> = cached_packages.iter().collect();
+
+    sorted_packages.sort_by(|first, second| {
+        (&first.repo, &first.target, &first.name)
+            .cmp(&(&second.repo, &second.target, &second.name))
+            .then_with(|| compare_versions(&second.version, &first.version))
+    });
+
+    let mut old_versions = Vec::new();
+    let mut kept = 0;
+
+    for (index, cached_package) in sorted_packages.iter().enumerate() {
+        let is_same_package = index > 0 && {
+            let previous = sorted_packages[index - 1];
+
+            (&previous.repo, &previous.target, &previous.name)
+                == (
+                    &cached_package.repo,
+                    &cached_package.target,
+                    &cached_package.name,
+                )
+        };
+
+        kept = if is_same_package { kept } else { 0 };
+
+        if kept < keep {
+            kept += 1;
+        } else {
+            old_versions.push(*cached_package);
+        }
+    }
+
+    old_versions
+}
+
+pub fn get_cached_packages() -> Result<Vec<CachedPackage>, AatiError> {
+    let cache_path_buf = get_cache_path_buf();
+    let mut cached_packages = Vec::new();
+
+    if !cache_path_buf.exists() {
+        return Ok(cached_packages);
+    }
+
+    for repo_dir in list_directory(&cache_path_buf)? {
+        for target_dir in list_directory(&repo_dir)? {
+            for tarball_path in list_directory(&target_dir)? {
+                let filename = tarball_path.file_name().unwrap().to_string_lossy();
+
+                // Leftovers that aren't tarballs are none of the cache's business
+                let package = match parse_filename(&filename) {
+                    Ok(package) => package,
+                    Err(_) => continue,
+                };
+
+                let metadata = match tarball_path.metadata() {
+                    Ok(metadata) if metadata.is_file() => metadata,
+                    _ => continue,
+                };
+
+                cached_packages.push(CachedPackage {
+                    repo: repo_dir.file_name().unwrap().to_string_lossy().into(),
+                    target: target_dir.file_name().unwrap().to_string_lossy().into(),
+                    name: package.name,
+                    version: package.version,
+                    size: metadata.len(),
+                    modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    path: tarball_path,
+                });
+            }
+        }
+    }
+
+    cached_packages.sort_by(|first, second| first.path.cmp(&second.path));
+
+    Ok(cached_packages)
+}
+
+fn list_directory(path: &Path) -> Result<Vec<PathBuf>, AatiError> {
+    match read_dir(path) {
+        Ok(entries) => Ok(entries.flatten().map(|entry| entry.path()).collect()),
+        Err(error) => Err(AatiError::ReadDirectory {
+            code: 118,
+            path: path.to_path_buf(),
+            error,
+        }),
+    }
+}
+
+#[test]
+fn test_select_old_versions() {
+    let cached_package = |name: &str, version: &str| CachedPackage {
+        repo: "testing".into(),
+        target: "any".into(),
+        name: name.into(),
+        version: version.into(),
+        path: PathBuf::from(format!("{}-{}.tar.lz4", name, version)),
+        size: 0,
+        modified: SystemTime::UNIX_EPOCH,
+    };
+
+    let cached_packages = vec![
+        cached_package("tool", "0.9.0"),
+        cached_package("tool", "0.10.0"),
+        cached_package("tool", "1.0.0"),
+        cached_package("other", "2.0.0"),
+    ];
+
+    let old_versions = |keep| {
+        select_old_versions(&cached_packages, keep)
+            .iter()
+            .map(|p| format!("{}-{}", p.name, p.version))
+            .collect::<Vec<String>>()
+    };
+
+    assert_eq!(old_versions(2), vec!["tool-0.9.0"]);
+    assert_eq!(old_versions(1), vec!["tool-0.10.0", "tool-0.9.0"]);
+    assert_eq!(old_versions(0).len(), 4);
+}
+
+#[test]
+fn test_trim_evicts_least_recently_used() {
+    use std::{
+        fs::{create_dir_all, remove_dir_all, write, File},
+        time::Duration,
+    };
+
+    use crate::utils::{get_aati_config_path_buf, use_test_home};
+
+    let (_guard, home) = use_test_home("cache-trim");
+
+    write(
+        get_aati_config_path_buf(),
+        "[sources]\nrepos = []\n\n[cache]\nmax_size_mib = 1",
+    )
+    .unwrap();
+
+    let target_directory = get_cache_path_buf().join("testing").join("any");
+    create_dir_all(&target_directory).unwrap();
+
+    // 1200 KiB in all, used in the order they're listed in
+    let tarballs = ["old-0.1.0", "used-0.1.0", "new-0.1.0"]
+        .map(|name| target_directory.join(format!("{}.tar.lz4", name)));
+
+    for (age, tarball) in tarballs.iter().rev().enumerate() {
+        write(tarball, vec![0; 400 * 1024]).unwrap();
+        File::options()
+            .write(true)
+            .open(tarball)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60 * (age as u64 + 1)))
+            .unwrap();
+    }
+
+    // Downloads that aren't over yet aren't part of the cache
+    let part_path = target_directory.join("pending-0.1.0.tar.lz4.part");
+    write(&part_path, vec![0; 400 * 1024]).unwrap();
+
+    trim_to_max_size(true).unwrap();
+
+    assert!(!tarballs[0].exists());
+    assert!(tarballs[1].exists());
+    assert!(tarballs[2].exists());
+    assert!(part_path.exists());
+
+    let _ = remove_dir_all(&home);
+}
+
+#[test]
+fn test_offline_needs_cached_package() {
+    use ring::digest;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use crate::utils::{get_aati_config_path_buf, get_repo_config_path_buf, use_test_home};
+
+    let (_guard, home) = use_test_home("cache-offline");
+
+    write(
+        get_aati_config_path_buf(),
+        "offline = true\n\n[sources]\nrepos = []",
+    )
+    .unwrap();
+
+    let extracted_package: Vec<String> = ["testing", "tool", "0.1.0", "any"]
+        .map(String::from)
+        .to_vec();
+
+    let sha256 = hex::encode(digest::digest(&digest::SHA256, b"tarball"));
+
+    write(get_repo_config_path_buf("testing"), format!("[repo]
+name = \"testing\"
+maintainer = \"Husayn Haras\"
+description = \"APR made for testing check_available()\"
+
+[index]
+packages = [
+    {{ name = \"tool\", target = \"any\", versions = [
+        {{ tag = \"0.1.0\", checksum = \"sha256:{}\" }},
+    ], author = \"Husayn Haras\", description = \"Only installable offline once it's cached\", url = \"https://example.com\" }},
+]", sha256)).unwrap();
+
+    match check_available(&extracted_package) {
+        Err(AatiError::NotCached {
+            package_name,
+            version,
+        }) => assert_eq!((package_name.as_str(), version.as_str()), ("tool", "0.1.0")),
+        result => panic!("expected the package to be missing, got {:?}", result),
+    }
+
+    let cached_path = get_cached_path(&extracted_package);
+    create_dir_all(cached_path.parent().unwrap()).unwrap();
+
+    // A download that was cut short isn't good enough
+    write(&cached_path, "tarb").unwrap();
+
+    assert!(matches!(
+        check_available(&extracted_package),
+        Err(AatiError::NotCached { .. })
+    ));
+
+    write(&cached_path, "tarball").unwrap();
+
+    assert!(check_available(&extracted_package).is_ok());
+
+    let _ = remove_dir_all(&home);
+}
@@ -21,12 +21,14 @@ use std::{
     env::temp_dir,
     fs::{remove_file, File},
     io::Write,
-    process::{exit, Command, Stdio},
+    process::{Command, Stdio},
 };
 
 use toml::Value;
 
-pub fn display(changelog: &Vec<Value>, latest_only: bool) {
+use crate::error::AatiError;
+
+pub fn display(changelog: &Vec<Value>, latest_only: bool) -> Result<(), AatiError> {
     let is_colored = !cfg!(windows) || latest_only;
 
     let parsed_changelog = format_changelog(changelog, latest_only, is_colored);
@@ -38,47 +40,24 @@ pub fn display(changelog: &Vec<Value>, latest_only: bool) {
         let mut temp_changelog = match File::create(&temp_changelog_path) {
             Ok(temp_changelog) => temp_changelog,
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO CREATE FILE '{}'! ERROR[59]: {}",
-                        &temp_changelog_path.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-                exit(1);
+                return Err(AatiError::CreateFile {
+                    code: 59,
+                    path: temp_changelog_path,
+                    error,
+                });
             }
         };
 
         match temp_changelog.write_all(parsed_changelog.as_bytes()) {
             Ok(_) => {}
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO WRITE INTO FILE '{}'! ERROR[103]: {}",
-                        &temp_changelog_path.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-                match remove_file(&temp_changelog_path) {
-                    Ok(_) => {}
-                    Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "- FAILED TO DELETE FILE '{}'! ERROR[103]: {}",
-                                &temp_changelog_path.display(),
-                                error
-                            )
-                            .bright_red()
-                        );
-                        exit(1);
-                    }
-                }
-                exit(1);
+                let _ = remove_file(&temp_changelog_path);
+
+                return Err(AatiError::WriteFile {
+                    code: 103,
+                    path: temp_changelog_path,
+                    error,
+                });
             }
         }
 
@@ -100,21 +79,18 @@ pub fn display(changelog: &Vec<Value>, latest_only: bool) {
         match remove_file(&temp_changelog_path) {
             Ok(_) => {}
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO DELETE FILE '{}'! ERROR[102]: {}",
-                        &temp_changelog_path.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-                exit(1);
+                return Err(AatiError::DeleteFile {
+                    code: 102,
+                    path: temp_changelog_path,
+                    error,
+                });
             }
         }
     } else {
         println!("{}", parsed_changelog);
     }
+
+    Ok(())
 }
 
 pub fn format_changelog(versions: &Vec<Value>, latest_only: bool, is_colored: bool) -> String {
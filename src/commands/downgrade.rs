@@ -0,0 +1,263 @@
+/* بسم الله الرحمن الرحيم
+
+   Aati - Cross-platform Package Manager written in Rust.
+   Copyright (C) 2023  Husayn Haras <haras@disroot.org>
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of version 3 of the GNU General Public License
+   as published by the Free Software Foundation.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use colored::Colorize;
+use std::{cmp::Ordering, slice};
+use toml::Value;
+
+use super::{cache, get, hold, remove};
+use crate::{
+    error::AatiError,
+    types::LockFile,
+    utils::{
+        compare_versions, get_aati_lock, get_repo_config, is_supported, parse_aati_config,
+        prompt_yn,
+    },
+};
+
+// Swap an installed package for another version listed in its repository,
+// the one right before the installed version if none is given
+pub fn command(
+    package_name: &str,
+    version: Option<&str>,
+    force: bool,
+    quiet: bool,
+) -> Result<(), AatiError> {
+    let lock_file: LockFile = toml::from_str(&get_aati_lock()?).unwrap();
+
+    let installed_package = match lock_file
+        .package
+        .iter()
+        .find(|pkg| pkg.name == package_name)
+    {
+        Some(installed_package) => installed_package,
+        None => return Err(AatiError::NotInstalled(package_name.into())),
+    };
+
+    if installed_package.source == "local" {
+        return Err(AatiError::PackageNotInRepo(package_name.into()));
+    }
+
+    let repo_toml: Value = get_repo_config(&installed_package.source)?.parse().unwrap();
+
+    let available_package = match repo_toml["index"]["packages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|pkg| {
+            pkg["name"].as_str().unwrap() == package_name
+                && is_supported(pkg["target"].as_str().unwrap())
+        }) {
+        Some(available_package) => available_package,
+        None => return Err(AatiError::PackageNotInRepo(package_name.into())),
+    };
+
+    let versions = available_package["versions"].as_array().unwrap();
+
+    let version = match version {
+        Some(version) => {
+            if !versions
+                .iter()
+                .any(|v| v["tag"].as_str().unwrap() == version)
+            {
+                return Err(AatiError::NoMatchingVersion {
+                    package_name: package_name.into(),
+                    requirement: version.into(),
+                });
+            }
+
+            version
+        }
+
+        None => match get_previous_version(versions, &installed_package.version) {
+            Some(previous_version) => previous_version["tag"].as_str().unwrap(),
+            None => return Err(AatiError::NoOlderVersion(package_name.into())),
+        },
+    };
+
+    if version == installed_package.version {
+        if !quiet {
+            println!(
+                "{}",
+                format!(
+                    "+ Version {} of '{}' is already installed!",
+                    version, package_name
+                )
+                .bright_blue()
+            );
+        }
+
+        return Ok(());
+    }
+
+    if force
+        || prompt_yn(&format!(
+            "/ Are you sure you want to replace {}/{}-{} with version {}?",
+            installed_package.source, package_name, installed_package.version, version
+        ))?
+    {
+        let extracted_package: Vec<String> = vec![
+            installed_package.source.clone(),
+            package_name.into(),
+            version.into(),
+            available_package["target"].as_str().unwrap().into(),
+        ];
+
+        let aati_config: Value = parse_aati_config(148)?;
+        let mut added_repos: Vec<Value> = Vec::new();
+
+        for repo_info in aati_config["sources"]["repos"].as_array().unwrap() {
+            added_repos.push(
+                get_repo_config(repo_info["name"].as_str().unwrap())?
+                    .parse::<Value>()
+                    .unwrap(),
+            );
+        }
+
+        // The new version is downloaded and verified before the installed
+        // one is removed
+        cache::check_available(&extracted_package)?;
+        get::download_packages(slice::from_ref(&extracted_package), &added_repos, quiet)?;
+        get::fetch_verified(&extracted_package, &added_repos, quiet)?;
+
+        remove::command(package_name, true, quiet)?;
+
+        // The requirement it was installed with is dropped, the version was
+        // picked by hand
+        get::command(
+            &extracted_package,
+            None,
+            installed_package.reason,
+            &[],
+            true,
+            quiet,
+        )?;
+
+        if installed_package.held {
+            hold::set_held(package_name, true)?;
+        }
+    } else if !quiet {
+        println!("{}", "+ Transaction aborted".bright_green());
+    }
+
+    Ok(())
+}
+
+// The newest listed version that's older than the installed one
+pub fn get_previous_version<'a>(
+    versions: &'a [Value],
+    installed_version: &str,
+) -> Option<&'a Value> {
+    versions
+        .iter()
+        .filter(|version| {
+            compare_versions(version["tag"].as_str().unwrap(), installed_version) == Ordering::Less
+        })
+        .reduce(|previous, version| {
+            match compare_versions(
+                version["tag"].as_str().unwrap(),
+                previous["tag"].as_str().unwrap(),
+            ) {
+                Ordering::Greater => version,
+                _ => previous,
+            }
+        })
+}
+
+#[test]
+fn test_get_previous_version() {
+    let versions: Vec<Value> = vec![
+        "tag = \"1.1.0\"".parse().unwrap(),
+        "tag = \"0.9.0\"".parse().unwrap(),
+        "tag = \"1.0.0\"".parse().unwrap(),
+        "tag = \"0.10.0\"".parse().unwrap(),
+    ];
+
+    let previous_tag = |installed_version: &str| {
+        get_previous_version(&versions, installed_version)
+            .map(|version| version["tag"].as_str().unwrap().to_string())
+    };
+
+    assert_eq!(previous_tag("1.1.0"), Some("1.0.0".into()));
+    assert_eq!(previous_tag("1.0.0"), Some("0.10.0".into()));
+    assert_eq!(previous_tag("0.9.0"), None);
+}
+
+#[test]
+fn test_bad_signature_keeps_installed_version() {
+    use ring::digest;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use crate::{
+        types::Package,
+        utils::{
+            get_aati_config_path_buf, get_aati_lock_path_buf, get_repo_config_path_buf, hash_file,
+            use_test_home,
+        },
+    };
+
+    let (_guard, home) = use_test_home("downgrade-signature");
+
+    write(
+        get_aati_config_path_buf(),
+        format!(
+            "offline = true\n\n[sources]\nrepos = [{{ name = \"testing\", url = \"http://localhost:1\", key = \"{}\" }}]",
+            "0".repeat(64)
+        ),
+    )
+    .unwrap();
+
+    let extracted_package: Vec<String> = ["testing", "tool", "0.1.0", "any"]
+        .map(String::from)
+        .to_vec();
+    let cached_path = cache::get_cached_path(&extracted_package);
+    create_dir_all(cached_path.parent().unwrap()).unwrap();
+    write(&cached_path, "tarball").unwrap();
+
+    let sha256 = hash_file(&cached_path, &digest::SHA256).unwrap();
+
+    write(get_repo_config_path_buf("testing"), format!("[repo]
+name = \"testing\"
+maintainer = \"Husayn Haras\"
+description = \"APR made for testing downgrade::command()\"
+
+[index]
+packages = [
+    {{ name = \"tool\", target = \"any\", versions = [
+        {{ tag = \"0.1.0\", checksum = \"sha256:{}\", signature = \"{}\" }},
+        {{ tag = \"0.2.0\", checksum = \"checksum-placeholder\", signature = \"signature-placeholder\" }},
+    ], author = \"Husayn Haras\", description = \"Its older version has a forged signature\", url = \"https://example.com\" }},
+]", sha256, "0".repeat(128))).unwrap();
+
+    let lock_file = LockFile {
+        package: vec![Package {
+            version: "0.2.0".into(),
+            ..Package::test("tool")
+        }],
+    };
+    let lock_file_str = toml::to_string(&lock_file).unwrap();
+    write(get_aati_lock_path_buf(), &lock_file_str).unwrap();
+
+    assert!(matches!(
+        command("tool", Some("0.1.0"), true, true),
+        Err(AatiError::BadSignature(_))
+    ));
+    assert_eq!(get_aati_lock().unwrap(), lock_file_str.trim());
+
+    let _ = remove_dir_all(&home);
+}
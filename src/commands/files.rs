@@ -0,0 +1,59 @@
+/* بسم الله الرحمن الرحيم
+
+   Aati - Cross-platform Package Manager written in Rust.
+   Copyright (C) 2023  Husayn Haras <haras@disroot.org>
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of version 3 of the GNU General Public License
+   as published by the Free Software Foundation.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use colored::Colorize;
+use humansize::{format_size, BINARY};
+
+use crate::{error::AatiError, types::LockFile, utils::get_aati_lock};
+
+pub fn command(package_name: &str) -> Result<(), AatiError> {
+    let lock_file: LockFile = toml::from_str(&get_aati_lock()?).unwrap();
+
+    let package = match lock_file
+        .package
+        .iter()
+        .find(|pkg| pkg.name == package_name)
+    {
+        Some(package) => package,
+        None => return Err(AatiError::NotInstalled(package_name.into())),
+    };
+
+    println!(
+        "{}",
+        format!(
+            "+ Files installed by {}/{}-{}:",
+            package.source, package.name, package.version
+        )
+        .bright_green()
+    );
+
+    if !package.files.is_empty() {
+        for file in &package.files {
+            println!(
+                "{}   {} ({})",
+                "+".bright_green(),
+                file.path,
+                format_size(file.size, BINARY)
+            );
+        }
+    } else {
+        println!("  None! The package either installs no files or was installed by an older version of Aati");
+    }
+
+    Ok(())
+}
@@ -22,17 +22,23 @@ use std::{
     fs::{create_dir_all, read_to_string, File},
     io::Write,
     path::PathBuf,
-    process::exit,
 };
 use toml::Value;
 
 use crate::{
     config::{HOMEPAGE_URL, POSSIBLE_TARGETS},
+    error::AatiError,
+    utils::get_latest_version,
     version::get_version,
 };
 
-pub fn command(repo_url: &str, manifest_path_buf: PathBuf, parent_directory: PathBuf, quiet: bool) {
-    match read_to_string(manifest_path_buf) {
+pub fn command(
+    repo_url: &str,
+    manifest_path_buf: PathBuf,
+    parent_directory: PathBuf,
+    quiet: bool,
+) -> Result<(), AatiError> {
+    match read_to_string(&manifest_path_buf) {
         Ok(repo_toml) => match repo_toml.parse::<Value>() {
             Ok(repo_config) => {
                 let available_packages = repo_config["index"]["packages"].as_array().unwrap();
@@ -106,33 +112,22 @@ pub fn command(repo_url: &str, manifest_path_buf: PathBuf, parent_directory: Pat
                     let mut file = match File::create(&filepath) {
                         Ok(file) => file,
                         Err(error) => {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                    "- FAILED TO CREATE FILE '{}'! ERROR[14]: {}",
-                                    filepath.display(),
-                                    error
-                                )
-                                .bright_red()
-                            );
-                            exit(1);
+                            return Err(AatiError::CreateFile {
+                                code: 14,
+                                path: filepath,
+                                error,
+                            });
                         }
                     };
 
                     match file.write_all(filehtml.as_bytes()) {
                         Ok(_) => {}
                         Err(error) => {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                    "- FAILED TO WRITE INTO FILE '{}'! ERROR[87]: {}",
-                                    filepath.display(),
-                                    error
-                                )
-                                .bright_red()
-                            );
-
-                            exit(1);
+                            return Err(AatiError::WriteFile {
+                                code: 87,
+                                path: filepath,
+                                error,
+                            });
                         }
                     }
 
@@ -143,18 +138,22 @@ pub fn command(repo_url: &str, manifest_path_buf: PathBuf, parent_directory: Pat
                         );
                     }
                 }
-            }
 
-            Err(error) => {
-                eprintln!("{}", format!("ERROR[12]: {}", error).bright_red());
-                exit(1);
+                Ok(())
             }
+
+            Err(error) => Err(AatiError::ParseToml {
+                code: 12,
+                path: manifest_path_buf,
+                error: Box::new(error),
+            }),
         },
 
-        Err(error) => {
-            eprintln!("{}", format!("ERROR[13]: {}", error).bright_red());
-            exit(1);
-        }
+        Err(error) => Err(AatiError::ReadFile {
+            code: 13,
+            path: manifest_path_buf,
+            error,
+        }),
     }
 }
 
@@ -210,10 +209,10 @@ pub fn generate_apr_html(
                 ));
                 for package in available_packages {
                     let package_name = package["name"].as_str().unwrap();
-                    let package_version = package["versions"].as_array().unwrap().first().unwrap()
-                        ["tag"]
-                        .as_str()
-                        .unwrap();
+                    let package_version =
+                        get_latest_version(package["versions"].as_array().unwrap()).unwrap()["tag"]
+                            .as_str()
+                            .unwrap();
                     let package_target = package["target"].as_str().unwrap();
                     if target == package_target {
                         body.push_str(&format!(
@@ -260,7 +259,9 @@ pub fn generate_apr_html(
             let package_name = package["name"].as_str().unwrap();
             let package_target = package["target"].as_str().unwrap();
             let package_versions = package["versions"].as_array().unwrap();
-            let package_version = package_versions.first().unwrap()["tag"].as_str().unwrap();
+            let package_version = get_latest_version(package_versions).unwrap()["tag"]
+                .as_str()
+                .unwrap();
             let package_author = package["author"].as_str().unwrap();
             let package_description = package["description"].as_str().unwrap();
             let package_url = package["url"].as_str().unwrap();
@@ -298,7 +299,7 @@ pub fn generate_apr_html(
             ));
             body.push_str("<p>Available versions:</p>");
 
-            body.push_str("<table><tr><th>Version</th><th>Changes</th><th>SHA256 Checksum</th><th>Release date</th></tr>");
+            body.push_str("<table><tr><th>Version</th><th>Changes</th><th>Checksum</th><th>Release date</th></tr>");
             for version in package_versions {
                 let version_table = version.as_table().unwrap();
                 let tag = version_table.get("tag").unwrap().as_str().unwrap();
@@ -374,8 +375,8 @@ pub fn generate_apr_html(
             body.push_str("<ul>");
             for package in available_packages {
                 let package_name = package["name"].as_str().unwrap();
-                let package_version = package["versions"].as_array().unwrap().first().unwrap()
-                    ["tag"]
+                let package_version = get_latest_version(package["versions"].as_array().unwrap())
+                    .unwrap()["tag"]
                     .as_str()
                     .unwrap();
                 let package_target = package["target"].as_str().unwrap();
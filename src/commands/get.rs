@@ -17,37 +17,120 @@
 */
 
 use std::{
-    env::temp_dir,
-    fs::{read_to_string, remove_dir_all, remove_file, File, OpenOptions},
-    io::{copy, Read, Write},
-    process::exit,
+    fs::{
+        create_dir_all, metadata, read_to_string, remove_dir_all, remove_file, rename, File,
+        OpenOptions,
+    },
+    io::copy,
+    path::{Path, PathBuf},
+    slice,
+    sync::{
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        Mutex,
+    },
+    thread,
+    time::SystemTime,
 };
 
 use crate::{
-    types::{LockFile, Package},
+    commands::{cache, mark},
+    config::DEFAULT_DOWNLOAD_WORKERS,
+    error::AatiError,
+    progress::{Progress, ProgressReader},
+    types::{InstallReason, Package, Pkgfile},
     utils::{
-        execute_lines, extract_package, get_aati_config, get_aati_lock, get_aati_lock_path_buf,
-        get_repo_config, is_supported, parse_pkgfile, prompt_yn,
+        add_to_lockfile, check_file_conflicts, execute_lines, extract_package, get_aati_lock,
+        get_pkgfile_dependencies, get_repo_config, get_script_policy, get_signed_message,
+        get_version_entry, hash_file, is_offline, is_supported, parse_aati_config, parse_pkgfile,
+        parse_public_key, prompt_yn, split_requirement, unpack_package, verify_signature, Checksum,
+        HashingWriter,
     },
 };
 use colored::Colorize;
 use humansize::{format_size, BINARY};
-use lz4::Decoder;
 use ring::digest;
-use tar::Archive;
 use toml::Value;
 
-pub fn command(package_name: &str, force: bool, quiet: bool) {
+// Get a package found using resolve() or extract_package(), along with the
+// dependencies it needs. `requirement` is the one it was asked for with,
+// which is kept in the Lockfile for upgrades to stay within.
+pub fn command(
+    extracted_package: &[String],
+    requirement: Option<&str>,
+    reason: InstallReason,
+    overwrite: &[String],
+    force: bool,
+    quiet: bool,
+) -> Result<(), AatiError> {
+    get_package(
+        extracted_package,
+        requirement,
+        reason,
+        &[],
+        overwrite,
+        force,
+        quiet,
+    )
+}
+
+// A package asked for by the user, found in the added repositories
+pub struct ResolvedPackage {
+    pub extracted_package: Vec<String>,
+    // The requirement it was asked for with, e.g. ^1.2 in foo@^1.2
+    pub requirement: Option<String>,
+}
+
+// Find the packages asked for in the added repositories. It's done once, so
+// that the user only chooses between repositories once and downloading them
+// ahead can't pick other packages than the ones installed afterwards.
+pub fn resolve(package_names: &[String]) -> Result<Vec<ResolvedPackage>, AatiError> {
+    let aati_config: Value = parse_aati_config(149)?;
+    let mut added_repos: Vec<Value> = Vec::new();
+
+    for repo_info in aati_config["sources"]["repos"].as_array().unwrap() {
+        added_repos.push(
+            get_repo_config(repo_info["name"].as_str().unwrap())?
+                .parse::<Value>()
+                .unwrap(),
+        );
+    }
+
+    let mut resolved_packages = Vec::new();
+
+    for package_name in package_names {
+        match extract_package(package_name, &added_repos)? {
+            Some(extracted_package) => resolved_packages.push(ResolvedPackage {
+                extracted_package,
+                requirement: split_requirement(package_name).1.map(|r| r.into()),
+            }),
+            None => return Err(AatiError::PackageNotFound(package_name.clone())),
+        }
+    }
+
+    Ok(resolved_packages)
+}
+
+// `chain` holds the packages whose installation is waiting on this one, so
+// that dependencies declared in PKGFILEs can't go around in circles
+fn get_package(
+    extracted_package: &[String],
+    requirement: Option<&str>,
+    reason: InstallReason,
+    chain: &[String],
+    overwrite: &[String],
+    force: bool,
+    quiet: bool,
+) -> Result<(), AatiError> {
     // Initialise some variables
 
-    let aati_lock: Value = get_aati_lock().parse().unwrap();
-    let aati_config: Value = get_aati_config().unwrap().parse().unwrap();
+    let aati_lock: Value = get_aati_lock()?.parse().unwrap();
+    let aati_config: Value = parse_aati_config(150)?;
     let repo_list = aati_config["sources"]["repos"].as_array().unwrap();
     let mut added_repos: Vec<Value> = Vec::new();
 
     for repo_info in repo_list {
         added_repos.push(
-            get_repo_config(repo_info["name"].as_str().unwrap())
+            get_repo_config(repo_info["name"].as_str().unwrap())?
                 .parse::<Value>()
                 .unwrap(),
         );
@@ -55,567 +138,1463 @@ pub fn command(package_name: &str, force: bool, quiet: bool) {
 
     let installed_packages = aati_lock["package"].as_array().unwrap();
 
-    if let Some(extracted_package) = extract_package(package_name, &added_repos) {
-        let repo_toml: Value = get_repo_config(extracted_package[0].as_str())
-            .parse()
-            .unwrap();
-        let available_packages = repo_toml["index"]["packages"].as_array().unwrap();
+    // 1. Make sure this Package isn't installed already
 
-        let mut is_installed = false;
-        let mut is_found = false;
-        let mut checksum = "";
+    if let Some(installed_package) = installed_packages
+        .iter()
+        .find(|pkg| pkg["name"].as_str().unwrap() == extracted_package[1])
+    {
+        println!(
+            "{}",
+            format!("+ Package '{}' is already installed!", extracted_package[1]).bright_blue()
+        );
 
-        for installed_package in installed_packages {
-            if installed_package["name"].as_str().unwrap() == extracted_package[1] {
-                is_installed = true;
+        // Asking for a package that was pulled in as a dependency means
+        // the user wants to keep it around
+        if reason == InstallReason::Explicit
+            && installed_package
+                .get("reason")
+                .and_then(|r| r.clone().try_into::<InstallReason>().ok())
+                == Some(InstallReason::Automatic)
+        {
+            mark::set_reason(&extracted_package[1], InstallReason::Explicit)?;
+
+            if !quiet {
+                println!(
+                    "{}",
+                    format!(
+                        "+ Marked '{}' as explicitly installed",
+                        extracted_package[1]
+                    )
+                    .bright_green()
+                );
             }
         }
 
-        // 1. Make sure this Package isn't installed already
+        return Ok(());
+    }
+
+    // 2. Work out which dependencies have to be installed before it
+
+    let dependencies = resolve_dependencies(extracted_package, &added_repos, installed_packages)?;
 
-        if is_installed {
+    if !dependencies.is_empty() {
+        if !quiet {
             println!(
                 "{}",
-                format!("+ Package '{}' is already installed!", extracted_package[1]).bright_blue()
+                format!(
+                    "+ Package '{}' depends on these packages which aren't installed:",
+                    extracted_package[1]
+                )
+                .bright_green()
             );
-            return;
-        } else {
-            for available_package in available_packages {
-                if available_package["name"].as_str().unwrap() == extracted_package[1] {
-                    for package_version in available_package["versions"].as_array().unwrap() {
-                        if package_version["tag"].as_str().unwrap() == extracted_package[2].clone()
-                            && is_supported(available_package["target"].as_str().unwrap())
-                        {
-                            is_found = true;
-                            checksum = package_version["checksum"].as_str().unwrap();
-                        }
-                    }
-                }
+
+            for dependency in &dependencies {
+                println!(
+                    "{}   {}/{}-{}",
+                    "+".bright_green(),
+                    dependency[0],
+                    dependency[1],
+                    dependency[2]
+                );
             }
         }
 
-        // 2. Make sure this Package is found in the Repository
+        let download_size = get_total_size(&plan_downloads(
+            &[extracted_package.to_vec()],
+            &added_repos,
+        )?);
 
-        if !is_found {
-            eprintln!(
+        if download_size > 0 && !quiet {
+            println!(
                 "{}",
                 format!(
-                    "- Package '{}' is not found on the Repository! Try: $ aati sync",
-                    extracted_package[1]
+                    "+ Total download size: {}",
+                    format_size(download_size, BINARY)
                 )
-                .bright_red()
+                .bright_green()
             );
-            exit(1);
         }
 
-        if !is_installed && is_found {
-            let name = extracted_package[1].clone();
-            let version = extracted_package[2].clone();
+        if !force && !prompt_yn("/ Do you want to install them as well?")? {
+            if !quiet {
+                println!("{}", "+ Transaction aborted".bright_green());
+            }
 
-            let aati_config: Value = get_aati_config().unwrap().parse().unwrap();
+            return Ok(());
+        }
 
-            let url = format!(
-                "{}/{}/{}/{}-{}.tar.lz4",
-                aati_config["sources"]["repos"]
-                    .as_array()
-                    .unwrap()
-                    .iter()
-                    .find(|r| r["name"].as_str().unwrap() == extracted_package[0])
-                    .unwrap()["url"]
-                    .as_str()
-                    .unwrap(),
-                extracted_package[3],
-                name,
-                name,
-                version
+        let dependency_chain = [chain, slice::from_ref(&extracted_package[1])].concat();
+
+        for dependency in &dependencies {
+            if !install(
+                dependency,
+                &added_repos,
+                &dependency_chain,
+                InstallReason::Automatic,
+                None,
+                overwrite,
+                force,
+                quiet,
+            )? {
+                return Ok(());
+            }
+        }
+    }
+
+    install(
+        extracted_package,
+        &added_repos,
+        chain,
+        reason,
+        requirement,
+        overwrite,
+        force,
+        quiet,
+    )?;
+
+    Ok(())
+}
+
+// Download, verify and install a single package that was found using
+// extract_package(). Returns whether the package ended up installed.
+#[allow(clippy::too_many_arguments)]
+fn install(
+    extracted_package: &[String],
+    added_repos: &[Value],
+    chain: &[String],
+    reason: InstallReason,
+    requirement: Option<&str>,
+    overwrite: &[String],
+    force: bool,
+    quiet: bool,
+) -> Result<bool, AatiError> {
+    // 3. Make sure this Package is found in the Repository
+
+    let checksum = match get_checksum(extracted_package)? {
+        Some(checksum) => checksum,
+        None => return Err(AatiError::PackageNotInRepo(extracted_package[1].clone())),
+    };
+
+    let name = extracted_package[1].clone();
+    let version = extracted_package[2].clone();
+
+    let url = get_package_url(extracted_package)?;
+
+    // Verified tarballs are kept in the cache, so reinstalling or going
+    // back to a version that was installed before doesn't download it again
+
+    let download_path = cache::get_cached_path(extracted_package);
+
+    let is_cached = is_cached(&download_path, &checksum);
+
+    if !is_cached && is_offline()? {
+        return Err(AatiError::NotCached {
+            package_name: name,
+            version,
+        });
+    }
+
+    let content_length = if is_cached {
+        metadata(&download_path).map(|m| m.len()).unwrap_or(0)
+    } else {
+        get_content_length(&url)?
+    };
+
+    let human_readable_size = format_size(content_length, BINARY);
+
+    // 4. Ask the user if he's sure that he wants to install it
+
+    if !force
+        && !prompt_yn(&format!(
+            "/ Are you sure you want to install {}/{}-{} ({})?",
+            extracted_package[0], name, version, human_readable_size
+        ))?
+    {
+        if !quiet {
+            println!("{}", "+ Transaction aborted".bright_green());
+        }
+
+        return Ok(false);
+    }
+
+    if is_cached {
+        if !quiet {
+            println!(
+                "{}",
+                format!(
+                    "+ Using the cached package ({})...",
+                    download_path.display()
+                )
+                .bright_green()
             );
+        }
+    } else {
+        // 5. Download the LZ4 compressed package into the cache and verify it
 
-            match ureq::head(&url).call() {
-                Ok(head_response) => {
-                    let content_length = head_response
-                        .headers()
-                        .get("Content-Length")
-                        .map(|value| value.len())
-                        .unwrap_or(0);
-
-                    let human_readable_size = format_size(content_length, BINARY);
-
-                    // 3. Ask the user if he's sure that he wants to install it
-
-                    if force
-                        || prompt_yn(
-                            format!(
-                                "/ Are you sure you want to install {}/{}-{} ({})?",
-                                extracted_package[0], name, version, human_readable_size
-                            )
-                            .as_str(),
-                        )
-                    {
-                        if !quiet {
-                            println!(
-                                "{}",
-                                format!("+ Downloading ({})...", url)
-                                    .as_str()
-                                    .bright_green()
-                            );
-                        }
+        let progress = Progress::new(Some(content_length), quiet);
+        let fetched = fetch(extracted_package, &url, &checksum, &progress, quiet);
 
-                        // 4. Download the LZ4 compressed package
-
-                        match ureq::get(url.as_str()).call() {
-                            Ok(mut response) => {
-                                let mut reader = response.body_mut().as_reader();
-
-                                let download_path = std::env::temp_dir()
-                                    .join(format!("{}-{}.tar.lz4", name, version));
-
-                                let mut downloaded_file = match OpenOptions::new()
-                                    .create(true)
-                                    .truncate(true)
-                                    .read(true)
-                                    .write(true)
-                                    .open(&download_path)
-                                {
-                                    Ok(file) => file,
-                                    Err(error) => {
-                                        eprintln!(
-                                            "{}",
-                                            format!(
-                                                "- FAILED TO CREATE FILE '{}'! ERROR[29]: {}",
-                                                &download_path.display(),
-                                                error
-                                            )
-                                            .bright_red()
-                                        );
-
-                                        exit(1);
-                                    }
-                                };
-
-                                // 5. Save the LZ4 compressed package
-
-                                match copy(&mut reader, &mut downloaded_file) {
-                                    Ok(_) => {}
-                                    Err(error) => {
-                                        eprintln!(
-                                            "{}",
-                                            format!(
-                                                "- FAILED TO WRITE INTO DOWNLOADED FILE '{}'! ERROR[30]: {}",
-                                                &download_path.display(),
-                                                error
-                                            )
-                                            .bright_red()
-                                        );
-
-                                        exit(1);
-                                    }
-                                }
-
-                                if !quiet {
-                                    println!("{}", "+ Finished downloading!".bright_green());
-                                }
-                                // 6. Define two readers for the LZ4 compressed package:
-                                //   - One for the checksum verification function
-                                //   - and another for the LZ4 Decoder
-
-                                let mut checksum_reader = match File::open(&download_path) {
-                                    Ok(file) => file,
-                                    Err(error) => {
-                                        eprintln!(
-                                            "{}",
-                                            format!(
-                                                "- FAILED TO OPEN DOWNLOADED FILE '{}' FOR READING! ERROR[31]: {}",
-                                                &download_path.display(),
-                                                error
-                                            )
-                                            .bright_red()
-                                        );
-
-                                        exit(1);
-                                    }
-                                };
-
-                                let lz4_reader = match File::open(&download_path) {
-                                    Ok(file) => file,
-                                    Err(error) => {
-                                        eprintln!(
-                                            "{}",
-                                            format!(
-                                                "- FAILED TO OPEN DOWNLOADED FILE '{}' FOR READING! ERROR[32]: {}",
-                                                &download_path.display(),
-                                                error
-                                            )
-                                            .bright_red()
-                                        );
-
-                                        exit(1);
-                                    }
-                                };
-
-                                let mut body = Vec::new();
-                                match checksum_reader.read_to_end(&mut body) {
-                                    Ok(_) => {}
-                                    Err(error) => {
-                                        eprintln!(
-                                            "{}",
-                                            format!(
-                                                "- FAILED TO READ DOWNLOADED FILE '{}'! ERROR[33]: {}",
-                                                &download_path.display(),
-                                                error
-                                            )
-                                            .bright_red()
-                                        );
-
-                                        exit(1);
-                                    }
-                                }
-
-                                // 7. Verify the SHA256 Checksum of the LZ4 compressed package
-
-                                if verify_checksum(&body, checksum.into()) {
-                                    if !quiet {
-                                        println!("{}", "+ Checksums match!".bright_green());
-                                    }
-
-                                    let mut tar_path_buf = temp_dir();
-                                    tar_path_buf.push(format!(
-                                        "{}-{}.tar",
-                                        extracted_package[1], extracted_package[2]
-                                    ));
-
-                                    let mut package_directory = temp_dir();
-                                    package_directory.push(format!(
-                                        "{}-{}",
-                                        extracted_package[1], extracted_package[2]
-                                    ));
-
-                                    let mut tarball = match File::create(&tar_path_buf) {
-                                        Ok(file) => file,
-                                        Err(error) => {
-                                            eprintln!(
-                                                "{}",
-                                                format!(
-                                                    "- FAILED TO CREATE FILE '{}'! ERROR[35]: {}",
-                                                    tar_path_buf.display(),
-                                                    error
-                                                )
-                                                .bright_red()
-                                            );
-
-                                            exit(1);
-                                        }
-                                    };
-
-                                    // 8. Decode the LZ4 compressed package, delete it, then save the uncompressed data into the installation directory
-
-                                    let mut decoder = match Decoder::new(lz4_reader) {
-                                        Ok(decoder) => decoder,
-                                        Err(error) => {
-                                            eprintln!(
-                                                "{}",
-                                                format!(
-                                            "- FAILED TO DECODE THE LZ4 COMPRESSED PACKAGE AT '{}'! ERROR[36]: {}",
-                                            download_path.display(),
-                                            error
-                                        )
-                                                .bright_red()
-                                            );
-
-                                            exit(1);
-                                        }
-                                    };
-
-                                    match remove_file(&download_path) {
-                                        Ok(_) => {}
-                                        Err(error) => {
-                                            eprintln!(
-                                                "{}",
-                                                format!(
-                                            "- FAILED TO DELETE DOWNLODED FILE '{}'! ERROR[37]: {}",
-                                            download_path.display(),
-                                            error
-                                        )
-                                                .bright_red()
-                                            );
-
-                                            exit(1);
-                                        }
-                                    }
-
-                                    match copy(&mut decoder, &mut tarball) {
-                                        Ok(_) => {}
-                                        Err(error) => {
-                                            eprintln!(
-                                                "{}",
-                                                format!(
-                                            "- FAILED TO WRITE INTO FILE '{}'! ERROR[38]: {}",
-                                            tar_path_buf.display(),
-                                            error
-                                        )
-                                                .bright_red()
-                                            );
-
-                                            exit(1);
-                                        }
-                                    }
-
-                                    let tarball = File::open(&tar_path_buf).unwrap();
-
-                                    let mut archive = Archive::new(tarball);
-
-                                    match archive.unpack(temp_dir()) {
-                                        Ok(_) => {}
-                                        Err(error) => {
-                                            eprintln!(
-                                                "{}",
-                                                format!(
-                                                    "- FAILED TO EXTRACT TARBALL '{}'! ERROR[89]: {}",
-                                                    tar_path_buf.display(),
-                                                    error
-                                                )
-                                                .bright_red()
-                                            );
-
-                                            exit(1);
-                                        }
-                                    }
-
-                                    match remove_file(tar_path_buf) {
-                                        Ok(_) => {}
-                                        Err(error) => {
-                                            eprintln!(
-                                                "{}",
-                                                format!(
-                                                    "- COULD NOT DELETE TEMPORARY PACKAGE TARBALL! ERROR[93]: {}",
-                                                    error
-                                                )
-                                                .as_str()
-                                                .bright_red()
-                                            );
-                                            exit(1);
-                                        }
-                                    }
-
-                                    let mut pkgfile_path_buf = package_directory.clone();
-                                    pkgfile_path_buf.push("PKGFILE");
-
-                                    let pkgfile = match read_to_string(&pkgfile_path_buf) {
-                                        Ok(contents) => contents,
-                                        Err(error) => {
-                                            eprintln!(
-                                                "{}",
-                                                format!(
-                                                    "- FAILED TO READ FILE '{}'! ERROR[90]: {}",
-                                                    pkgfile_path_buf.display(),
-                                                    error
-                                                )
-                                                .bright_red()
-                                            );
-
-                                            exit(1);
-                                        }
-                                    };
-
-                                    let parsed_pkgfile = parse_pkgfile(&pkgfile);
-
-                                    let selected_installation_lines = if cfg!(windows) {
-                                        if !parsed_pkgfile.win_installation_lines.is_empty() {
-                                            parsed_pkgfile.win_installation_lines.clone()
-                                        } else {
-                                            parsed_pkgfile.installation_lines.clone()
-                                        }
-                                    } else {
-                                        parsed_pkgfile.installation_lines.clone()
-                                    };
-
-                                    if force
-                                        || prompt_yn(&format!(
-                                            "+ Commands to be ran:\n  {}\n/ Do these commands seem safe to execute?",
-                                            selected_installation_lines.join("\n  ")
-                                        ))
-                                    {
-
-                                    execute_lines(
-                                        &selected_installation_lines,
-                                        &parsed_pkgfile.data,
-                                        Some(&package_directory),
-                                        quiet
-                                    );
-
-                                    match remove_dir_all(package_directory) {
-                                        Ok(_) => {}
-                                        Err(error) => {
-                                            eprintln!(
-                                                "{}",
-                                                format!(
-                                                    "- COULD NOT DELETE TEMPORARY PACKAGE DIRECTORY! ERROR[83]: {}",
-                                                    error
-                                                )
-                                                .as_str()
-                                                .bright_red()
-                                            );
-                                            exit(1);
-                                        }
-                                    }
-
-                                    if !quiet {
-                                    println!(
-                                        "{}",
-                                        "+ Adding Package to the Lockfile...".bright_green()
-                                    );
-                                }
-
-                                    // 9. Add this Package to the Lockfile
-
-                                    let aati_lock_path_buf = get_aati_lock_path_buf();
-
-                                    let lock_file_str = match read_to_string(&aati_lock_path_buf) {
-                                        Ok(contents) => contents,
-                                        Err(error) => {
-                                            eprintln!(
-                                                "{}",
-                                                format!(
-                                                "- FAILED TO READ LOCKFILE AT '{}'! ERROR[39]: {}",
-                                                &aati_lock_path_buf.display(),
-                                                error
-                                            )
-                                                .bright_red()
-                                            );
-
-                                            exit(1);
-                                        }
-                                    };
-                                    let mut lock_file: LockFile =
-                                        toml::from_str(&lock_file_str).unwrap();
-
-                                    let package = Package {
-                                        name,
-                                        version,
-                                        source: extracted_package[0].clone(),
-                                        target: extracted_package[3].clone(),
-                                        pkgfile: parsed_pkgfile.clone()
-                                    };
-
-                                    lock_file.package.push(package);
-
-                                    let mut file = match OpenOptions::new()
-                                        .write(true)
-                                        .truncate(true)
-                                        .open(&aati_lock_path_buf)
-                                    {
-                                        Ok(file) => file,
-                                        Err(error) => {
-                                            eprintln!(
-                                                    "{}",
-                                                    format!(
-                                                "- FAILED TO OPEN LOCKFILE AT '{}' FOR WRITING! ERROR[40]: {}",
-                                                &aati_lock_path_buf.display(),
-                                                error
-                                            )
-                                                    .bright_red()
-                                                );
-
-                                            exit(1);
-                                        }
-                                    };
-
-                                    let toml_str = toml::to_string(&lock_file).unwrap();
-                                    match file.write_all(toml_str.as_bytes()) {
-                                        Ok(_) => {}
-                                        Err(error) => {
-                                            eprintln!(
-                                                "{}",
-                                                format!(
-                                            "- FAILED TO WRITE INTO LOCKFILE AT '{}'! ERROR[41]: {}",
-                                            &aati_lock_path_buf.display(),
-                                            error
-                                        )
-                                                .bright_red()
-                                            );
-
-                                            exit(1);
-                                        }
-                                    }
-
-                                    if !quiet {
-                                    println!("{}", "+ Installation is complete!".bright_green());
-                                    }
-                                } else {
-                                    if !quiet {
-                                    println!("{}", "+ Transaction aborted".bright_green());
-                                    }
-
-                                    match remove_dir_all(&package_directory) {
-                                        Ok(_) => if !quiet {println!("{}", "+ Deleted temporary package directory".bright_green())},
-                                        Err(error) => {
-                                            eprintln!(
-                                                "{}",
-                                                format!(
-                                                    "- FAILED TO DELETE DIRECTORY '{}'! ERROR[86]: {}",
-                                                    package_directory.display(),
-                                                    error
-                                                )
-                                                .as_str()
-                                                .bright_red()
-                                            );
-                                            exit(1);
-                                        }
-                                    }
-                                }
-                                } else {
-                                    eprintln!(
-                                        "{}",
-                                        "- Checksums don't match! Installation is aborted"
-                                            .bright_red()
-                                    );
-
-                                    match remove_file(&download_path) {
-                                        Ok(_) => {}
-                                        Err(error) => {
-                                            eprintln!(
-                                                "{}",
-                                                format!(
-                                                    "- UNABLE DELETE FILE '{}'! ERROR[44]: {}",
-                                                    download_path.display(),
-                                                    error
-                                                )
-                                                .bright_red()
-                                            );
-
-                                            exit(1);
-                                        }
-                                    }
-                                }
-                            }
-
-                            Err(error) => {
-                                eprintln!(
-                                    "{}",
-                                    format!("- ERROR[1]: {}", error).as_str().bright_red()
-                                );
-                                exit(1);
-                            }
-                        };
-                    } else if !quiet {
-                        println!("{}", "+ Transaction aborted".bright_green());
-                    }
+        progress.finish();
+        fetched?;
+    }
+
+    // 6. Verify the package's signature against the repository's key
+
+    verify_package_signature(
+        extracted_package,
+        added_repos,
+        &download_path,
+        &checksum,
+        &url,
+        quiet,
+    )?;
+
+    // 7. Decode the LZ4 compressed package and unpack it in one go
+
+    let package = match File::open(&download_path) {
+        Ok(file) => file,
+        Err(error) => {
+            return Err(AatiError::OpenFile {
+                code: 32,
+                path: download_path,
+                error,
+            });
+        }
+    };
+
+    let extraction_directory = unpack_package(
+        package,
+        &download_path,
+        &extracted_package[1],
+        &extracted_package[2],
+    )?;
+
+    let package_directory =
+        extraction_directory.join(format!("{}-{}", extracted_package[1], extracted_package[2]));
+
+    let mut pkgfile_path_buf = package_directory.clone();
+    pkgfile_path_buf.push("PKGFILE");
+
+    let pkgfile = match read_to_string(&pkgfile_path_buf) {
+        Ok(contents) => contents,
+        Err(error) => {
+            let _ = remove_dir_all(&extraction_directory);
+
+            return Err(AatiError::ReadFile {
+                code: 90,
+                path: pkgfile_path_buf,
+                error,
+            });
+        }
+    };
+
+    let parsed_pkgfile = parse_pkgfile(&pkgfile);
+
+    let selected_installation_lines = if cfg!(windows) {
+        if !parsed_pkgfile.win_installation_lines.is_empty() {
+            parsed_pkgfile.win_installation_lines.clone()
+        } else {
+            parsed_pkgfile.installation_lines.clone()
+        }
+    } else {
+        parsed_pkgfile.installation_lines.clone()
+    };
+
+    // Nothing gets executed if the package would overwrite files
+    // it doesn't own
+
+    if let Err(error) = check_file_conflicts(
+        &name,
+        &selected_installation_lines,
+        &parsed_pkgfile.data,
+        overwrite,
+    ) {
+        let _ = remove_dir_all(&extraction_directory);
+
+        return Err(error);
+    }
+
+    // Dependencies from repo.toml are installed by now, but the
+    // ones only declared in the PKGFILE still have to be fetched
+
+    let mut dependencies: Vec<String> = get_dependencies(extracted_package, added_repos)?
+        .into_iter()
+        .map(|dependency| dependency[1].clone())
+        .collect();
+
+    match install_pkgfile_dependencies(
+        &parsed_pkgfile,
+        &[chain, slice::from_ref(&name)].concat(),
+        overwrite,
+        force,
+        quiet,
+    )? {
+        Some(pkgfile_dependencies) => {
+            for dependency in pkgfile_dependencies {
+                if !dependencies.contains(&dependency) {
+                    dependencies.push(dependency);
+                }
+            }
+        }
+
+        None => {
+            let _ = remove_dir_all(&extraction_directory);
+
+            return Err(AatiError::DependenciesNotInstalled(name));
+        }
+    }
+
+    if !force
+        && !prompt_yn(&format!(
+            "+ Commands to be ran:\n  {}\n/ Do these commands seem safe to execute?",
+            selected_installation_lines.join("\n  ")
+        ))?
+    {
+        if !quiet {
+            println!("{}", "+ Transaction aborted".bright_green());
+        }
+
+        match remove_dir_all(&extraction_directory) {
+            Ok(_) => {
+                if !quiet {
+                    println!("{}", "+ Deleted temporary package directory".bright_green())
+                }
+            }
+            Err(error) => {
+                return Err(AatiError::DeleteDirectory {
+                    code: 86,
+                    path: extraction_directory,
+                    error,
+                });
+            }
+        }
+
+        return Ok(false);
+    }
+
+    let (files, transaction) = match execute_lines(
+        &selected_installation_lines,
+        &parsed_pkgfile.data,
+        Some(&package_directory),
+        &get_script_policy(&extracted_package[0])?,
+        quiet,
+    ) {
+        Ok(result) => result,
+        Err(error) => {
+            let _ = remove_dir_all(&extraction_directory);
+
+            return Err(error);
+        }
+    };
+
+    if !quiet {
+        println!("{}", "+ Adding Package to the Lockfile...".bright_green());
+    }
+
+    // 8. Add this Package to the Lockfile, the files it installed are only
+    // kept if that works
+
+    let package = Package {
+        name,
+        version,
+        source: extracted_package[0].clone(),
+        target: extracted_package[3].clone(),
+        reason,
+        requirement: requirement.map(|r| r.into()),
+        held: false,
+        checksum: None,
+        dependencies,
+        files,
+        pkgfile: parsed_pkgfile.clone(),
+    };
+
+    if let Err(error) = add_to_lockfile(package, 39, 41) {
+        transaction.rollback(quiet);
+        let _ = remove_dir_all(&extraction_directory);
+
+        return Err(error);
+    }
+
+    transaction.commit();
+
+    match remove_dir_all(&extraction_directory) {
+        Ok(_) => {}
+        Err(error) => {
+            return Err(AatiError::DeleteDirectory {
+                code: 83,
+                path: extraction_directory,
+                error,
+            });
+        }
+    }
+
+    if !quiet {
+        println!("{}", "+ Installation is complete!".bright_green());
+    }
+
+    Ok(true)
+}
+
+// Download a package into the cache and verify its checksum
+fn fetch(
+    extracted_package: &[String],
+    url: &str,
+    checksum: &Checksum,
+    progress: &Progress,
+    quiet: bool,
+) -> Result<(), AatiError> {
+    let download_path = cache::get_cached_path(extracted_package);
+
+    if !quiet {
+        progress.println(
+            &format!("+ Downloading ({})...", url)
+                .bright_green()
+                .to_string(),
+        );
+    }
+
+    let (resumed, hash) = download(url, &download_path, checksum.algorithm, progress)?;
+
+    if !quiet {
+        progress.println(
+            &format!(
+                "+ Finished downloading {}-{}{}!",
+                extracted_package[1],
+                extracted_package[2],
+                if resumed > 0 { " (resumed)" } else { "" }
+            )
+            .bright_green()
+            .to_string(),
+        );
+    }
+
+    if hash != checksum.hash {
+        let length = metadata(&download_path).map(|m| m.len()).unwrap_or(0);
+
+        match remove_file(&download_path) {
+            Ok(_) => {}
+            Err(error) => {
+                return Err(AatiError::DeleteFile {
+                    code: 44,
+                    path: download_path,
+                    error,
+                });
+            }
+        }
+
+        // The part it was resumed from may have been what's broken
+        if resumed > 0 {
+            progress.rewind(length, resumed);
+
+            if !quiet {
+                progress.println(
+                    &"+ Checksums don't match, downloading it again from the start..."
+                        .yellow()
+                        .to_string(),
+                );
+            }
+
+            return fetch(extracted_package, url, checksum, progress, quiet);
+        }
+
+        return Err(AatiError::ChecksumMismatch(extracted_package[1].clone()));
+    }
+
+    if !quiet {
+        progress.println(
+            &format!("+ Checksums of {} match!", extracted_package[1])
+                .bright_green()
+                .to_string(),
+        );
+    }
+
+    Ok(())
+}
+
+// Make sure the tarball of a package is in the cache and that its checksum
+// and signature check out, before an installed version is swapped for it
+pub fn fetch_verified(
+    extracted_package: &[String],
+    added_repos: &[Value],
+    quiet: bool,
+) -> Result<(), AatiError> {
+    let checksum = match get_checksum(extracted_package)? {
+        Some(checksum) => checksum,
+        None => return Err(AatiError::PackageNotInRepo(extracted_package[1].clone())),
+    };
+
+    let url = get_package_url(extracted_package)?;
+    let download_path = cache::get_cached_path(extracted_package);
+
+    if !is_cached(&download_path, &checksum) {
+        if is_offline()? {
+            return Err(AatiError::NotCached {
+                package_name: extracted_package[1].clone(),
+                version: extracted_package[2].clone(),
+            });
+        }
+
+        let progress = Progress::new(Some(get_content_length(&url)?), quiet);
+        let fetched = fetch(extracted_package, &url, &checksum, &progress, quiet);
+
+        progress.finish();
+        fetched?;
+    }
+
+    verify_package_signature(
+        extracted_package,
+        added_repos,
+        &download_path,
+        &checksum,
+        &url,
+        quiet,
+    )
+}
+
+// A package that isn't installed or cached yet, planned to be downloaded
+pub struct Download {
+    pub extracted_package: Vec<String>,
+    pub url: String,
+    pub checksum: Checksum,
+    pub size: u64,
+}
+
+// Fill the cache with the given packages and the dependencies they still
+// need. Installing them afterwards one by one won't have to wait for the
+// network, and nothing has to be removed before the new versions are here.
+pub fn download_packages(
+    extracted_packages: &[Vec<String>],
+    added_repos: &[Value],
+    quiet: bool,
+) -> Result<(), AatiError> {
+    download_all(&plan_downloads(extracted_packages, added_repos)?, quiet)
+}
+
+// Everything the given packages and their dependencies need downloaded.
+// Offline, that's nothing: whatever isn't cached fails when it's installed.
+pub fn plan_downloads(
+    extracted_packages: &[Vec<String>],
+    added_repos: &[Value],
+) -> Result<Vec<Download>, AatiError> {
+    let mut downloads: Vec<Download> = Vec::new();
+
+    if is_offline()? {
+        return Ok(downloads);
+    }
+
+    let aati_lock: Value = get_aati_lock()?.parse().unwrap();
+    let installed_packages = aati_lock["package"].as_array().unwrap();
+
+    let mut planned: Vec<Vec<String>> = Vec::new();
+
+    for extracted_package in extracted_packages {
+        let mut packages =
+            resolve_dependencies(extracted_package, added_repos, installed_packages)?;
+        packages.push(extracted_package.clone());
+
+        for package in packages {
+            if !planned.contains(&package) {
+                planned.push(package);
+            }
+        }
+    }
+
+    for package in planned {
+        let is_installed = installed_packages.iter().any(|pkg| {
+            pkg["name"].as_str().unwrap() == package[1]
+                && pkg["version"].as_str().unwrap() == package[2]
+        });
+
+        let checksum = match get_checksum(&package)? {
+            Some(checksum) => checksum,
+            None => continue,
+        };
+
+        if !is_installed && !is_cached(&cache::get_cached_path(&package), &checksum) {
+            let url = get_package_url(&package)?;
+            let size = get_content_length(&url)?;
+
+            downloads.push(Download {
+                extracted_package: package,
+                url,
+                checksum,
+                size,
+            });
+        }
+    }
+
+    Ok(downloads)
+}
+
+// Download using the number of workers set under [downloads] in rc.toml.
+// Each worker takes the next package until none are left.
+pub fn download_all(downloads: &[Download], quiet: bool) -> Result<(), AatiError> {
+    let workers = get_download_workers()?.min(downloads.len());
+
+    if workers > 1 && !quiet {
+        println!(
+            "{}",
+            format!(
+                "+ Downloading {} packages using {} workers...",
+                downloads.len(),
+                workers
+            )
+            .bright_green()
+        );
+    }
+
+    let progress = Progress::new(Some(get_total_size(downloads)), quiet);
+    let next_download = AtomicUsize::new(0);
+    let errors: Mutex<Vec<AatiError>> = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let index = next_download.fetch_add(1, AtomicOrdering::Relaxed);
+
+                let download = match downloads.get(index) {
+                    Some(download) => download,
+                    None => break,
+                };
+
+                if let Err(error) = fetch(
+                    &download.extracted_package,
+                    &download.url,
+                    &download.checksum,
+                    &progress,
+                    quiet,
+                ) {
+                    errors.lock().unwrap().push(error);
                 }
+            });
+        }
+    });
+
+    progress.finish();
+
+    match errors.into_inner().unwrap().into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+pub fn get_total_size(downloads: &[Download]) -> u64 {
+    downloads.iter().map(|download| download.size).sum()
+}
+
+// Download the packages found using resolve() ahead of installing them.
+// Returns false if the user didn't want to.
+pub fn prefetch(
+    extracted_packages: &[Vec<String>],
+    force: bool,
+    quiet: bool,
+) -> Result<bool, AatiError> {
+    let aati_config: Value = parse_aati_config(151)?;
+    let mut added_repos: Vec<Value> = Vec::new();
+
+    for repo_info in aati_config["sources"]["repos"].as_array().unwrap() {
+        added_repos.push(
+            get_repo_config(repo_info["name"].as_str().unwrap())?
+                .parse::<Value>()
+                .unwrap(),
+        );
+    }
+
+    let downloads = plan_downloads(extracted_packages, &added_repos)?;
+
+    // A single package is downloaded when it's installed, after the user
+    // saw its size
+    if downloads.len() < 2 {
+        return Ok(true);
+    }
+
+    if !quiet {
+        println!("{}", "+ Packages to be downloaded:".bright_green());
+
+        for download in &downloads {
+            println!(
+                "{}   {}/{}-{} ({})",
+                "+".bright_green(),
+                download.extracted_package[0],
+                download.extracted_package[1],
+                download.extracted_package[2],
+                format_size(download.size, BINARY)
+            );
+        }
+
+        println!(
+            "{}",
+            format!(
+                "+ Total download size: {}",
+                format_size(get_total_size(&downloads), BINARY)
+            )
+            .bright_green()
+        );
+    }
+
+    if !force && !prompt_yn("/ Do you want to download them?")? {
+        if !quiet {
+            println!("{}", "+ Transaction aborted".bright_green());
+        }
+
+        return Ok(false);
+    }
+
+    download_all(&downloads, quiet)?;
+
+    Ok(true)
+}
+
+// The size a HEAD request reports for a package, 0 if it reports none
+fn get_content_length(url: &str) -> Result<u64, AatiError> {
+    match ureq::head(url).call() {
+        Ok(head_response) => Ok(head_response
+            .headers()
+            .get("Content-Length")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(0)),
+
+        Err(error) => Err(AatiError::Request {
+            code: 0,
+            url: url.into(),
+            error: error.to_string(),
+        }),
+    }
+}
+
+fn get_download_workers() -> Result<usize, AatiError> {
+    let aati_config: Value = parse_aati_config(152)?;
+
+    Ok(aati_config
+        .get("downloads")
+        .and_then(|downloads| downloads.get("workers"))
+        .and_then(|workers| workers.as_integer())
+        .filter(|workers| *workers > 0)
+        .map(|workers| workers as usize)
+        .unwrap_or(DEFAULT_DOWNLOAD_WORKERS))
+}
+
+// The checksum listed in repo.toml for the version of an extract_package()
+// result, if the repository has it for a supported target
+pub fn get_checksum(extracted_package: &[String]) -> Result<Option<Checksum>, AatiError> {
+    let repo_toml: Value = get_repo_config(extracted_package[0].as_str())?
+        .parse()
+        .unwrap();
+    let available_packages = repo_toml["index"]["packages"].as_array().unwrap();
+
+    let mut checksum = None;
+
+    for available_package in available_packages {
+        if available_package["name"].as_str().unwrap() == extracted_package[1] {
+            for package_version in available_package["versions"].as_array().unwrap() {
+                if package_version["tag"].as_str().unwrap() == extracted_package[2]
+                    && is_supported(available_package["target"].as_str().unwrap())
+                {
+                    let listed_checksum = package_version["checksum"].as_str().unwrap();
+
+                    checksum = match Checksum::parse(listed_checksum) {
+                        Some(checksum) => Some(checksum),
+                        None => {
+                            return Err(AatiError::InvalidChecksum {
+                                package_name: extracted_package[1].clone(),
+                                checksum: listed_checksum.into(),
+                            });
+                        }
+                    };
+                }
+            }
+        }
+    }
+
+    Ok(checksum)
+}
+
+// The key pinned in rc.toml for a repository, if it's signed
+fn get_repo_key(repo_name: &str) -> Result<Option<String>, AatiError> {
+    let aati_config: Value = parse_aati_config(153)?;
+
+    Ok(aati_config["sources"]["repos"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|r| r["name"].as_str().unwrap() == repo_name)
+        .and_then(|r| r.get("key"))
+        .and_then(|key| key.as_str())
+        .map(|key| key.to_string()))
+}
+
+// Check the signature of a package from a repository with a pinned key.
+// Packages from repositories without one are only verified by checksum.
+fn verify_package_signature(
+    extracted_package: &[String],
+    added_repos: &[Value],
+    download_path: &Path,
+    checksum: &Checksum,
+    url: &str,
+    quiet: bool,
+) -> Result<(), AatiError> {
+    let key = match get_repo_key(&extracted_package[0])? {
+        Some(key) => parse_public_key(&key)?,
+        None => return Ok(()),
+    };
+
+    let signature = match get_version_entry(extracted_package, added_repos)
+        .as_ref()
+        .and_then(|package_version| package_version.get("signature"))
+        .and_then(|signature| signature.as_str())
+    {
+        Some(signature) => signature.to_string(),
+        None => {
+            return Err(AatiError::UnsignedPackage {
+                package_name: extracted_package[1].clone(),
+                version: extracted_package[2].clone(),
+            });
+        }
+    };
+
+    // The package was hashed with the listed algorithm while it was
+    // downloaded, which only has to be done again if that wasn't SHA512
+    let sha512 = if checksum.algorithm == &digest::SHA512 {
+        checksum.hash.clone()
+    } else {
+        match hash_file(download_path, &digest::SHA512) {
+            Ok(hash) => hash,
+            Err(error) => {
+                return Err(AatiError::ReadFile {
+                    code: 129,
+                    path: download_path.to_path_buf(),
+                    error,
+                });
+            }
+        }
+    };
+
+    let message = get_signed_message(&extracted_package[1], &extracted_package[2], &sha512);
+
+    if !verify_signature(&key, message.as_bytes(), &signature) {
+        return Err(AatiError::BadSignature(url.into()));
+    }
+
+    if !quiet {
+        println!(
+            "{}",
+            format!("+ The signature of {} is valid!", extracted_package[1]).bright_green()
+        );
+    }
+
+    Ok(())
+}
+
+fn get_package_url(extracted_package: &[String]) -> Result<String, AatiError> {
+    let aati_config: Value = parse_aati_config(154)?;
+
+    Ok(format!(
+        "{}/{}/{}/{}-{}.tar.lz4",
+        aati_config["sources"]["repos"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|r| r["name"].as_str().unwrap() == extracted_package[0])
+            .unwrap()["url"]
+            .as_str()
+            .unwrap(),
+        extracted_package[3],
+        extracted_package[1],
+        extracted_package[1],
+        extracted_package[2]
+    ))
+}
+
+// Whether a verified copy of the package is in the cache already
+pub fn is_cached(download_path: &Path, checksum: &Checksum) -> bool {
+    match hash_file(download_path, checksum.algorithm) {
+        Ok(hash) if hash == checksum.hash => {
+            // Reusing it counts as a use, so trimming the cache keeps it longer
+            if let Ok(file) = OpenOptions::new().write(true).open(download_path) {
+                let _ = file.set_modified(SystemTime::now());
+            }
+
+            true
+        }
+
+        _ => false,
+    }
+}
+
+// Download into a `.part` file next to download_path, which is renamed once
+// it's complete. A part left behind by a dropped connection is resumed using
+// a Range request if the server supports them. Returns how many bytes it was
+// resumed from, 0 if it wasn't, and the digest of the whole package which is
+// computed while it's written.
+fn download(
+    url: &str,
+    download_path: &Path,
+    algorithm: &'static digest::Algorithm,
+    progress: &Progress,
+) -> Result<(u64, String), AatiError> {
+    if let Some(parent) = download_path.parent() {
+        if let Err(error) = create_dir_all(parent) {
+            return Err(AatiError::CreateDirectory {
+                code: 117,
+                path: parent.to_path_buf(),
+                error,
+            });
+        }
+    }
+
+    let mut part_path = download_path.as_os_str().to_owned();
+    part_path.push(".part");
+    let part_path = PathBuf::from(part_path);
+
+    let resume_from = metadata(&part_path).map(|m| m.len()).unwrap_or(0);
 
-                Err(error) => {
-                    eprintln!("{}", format!("- ERROR[0]: {}", error).as_str().bright_red());
-                    exit(1);
+    let mut request = ureq::get(url);
+
+    if resume_from > 0 {
+        request = request.header("Range", format!("bytes={}-", resume_from));
+    }
+
+    let mut response = match request.call() {
+        Ok(response) => response,
+
+        // The part is as long as the whole package already, or longer if
+        // the package was replaced since, so it's started over
+        Err(ureq::Error::StatusCode(416)) if resume_from > 0 => {
+            if let Err(error) = remove_file(&part_path) {
+                return Err(AatiError::DeleteFile {
+                    code: 121,
+                    path: part_path,
+                    error,
+                });
+            }
+
+            return download(url, download_path, algorithm, progress);
+        }
+
+        Err(error) => {
+            return Err(AatiError::Request {
+                code: 1,
+                url: url.into(),
+                error: error.to_string(),
+            });
+        }
+    };
+
+    // Servers that ignore Range send the whole package again
+    let is_resumed = response.status().as_u16() == 206;
+
+    // Appending anything but the rest of the part would corrupt it, so
+    // it's started over
+    if is_resumed
+        && resume_from > 0
+        && response
+            .headers()
+            .get("Content-Range")
+            .and_then(|content_range| content_range.to_str().ok())
+            .and_then(get_range_start)
+            != Some(resume_from)
+    {
+        drop(response);
+
+        if let Err(error) = remove_file(&part_path) {
+            return Err(AatiError::DeleteFile {
+                code: 121,
+                path: part_path,
+                error,
+            });
+        }
+
+        return download(url, download_path, algorithm, progress);
+    }
+
+    if is_resumed {
+        progress.resume(resume_from);
+    }
+
+    let mut reader = ProgressReader::new(response.body_mut().as_reader(), progress);
+
+    let downloaded_file = match OpenOptions::new()
+        .create(true)
+        .append(is_resumed)
+        .truncate(!is_resumed)
+        .write(true)
+        .open(&part_path)
+    {
+        Ok(file) => file,
+        Err(error) => {
+            return Err(AatiError::CreateFile {
+                code: 29,
+                path: part_path,
+                error,
+            });
+        }
+    };
+
+    let mut writer = HashingWriter::new(downloaded_file, algorithm);
+
+    if is_resumed {
+        let hashed = File::open(&part_path).and_then(|mut part| writer.hash_existing(&mut part));
+
+        if let Err(error) = hashed {
+            return Err(AatiError::ReadFile {
+                code: 126,
+                path: part_path,
+                error,
+            });
+        }
+    }
+
+    if let Err(error) = copy(&mut reader, &mut writer) {
+        return Err(AatiError::WriteFile {
+            code: 30,
+            path: part_path,
+            error,
+        });
+    }
+
+    let hash = writer.finish();
+
+    match rename(&part_path, download_path) {
+        Ok(_) => Ok((if is_resumed { resume_from } else { 0 }, hash)),
+        Err(error) => Err(AatiError::WriteFile {
+            code: 122,
+            path: download_path.to_path_buf(),
+            error,
+        }),
+    }
+}
+
+// The first byte of a `Content-Range: bytes <first>-<last>/<length>` header
+fn get_range_start(content_range: &str) -> Option<u64> {
+    content_range
+        .trim()
+        .strip_prefix("bytes ")?
+        .split_once('-')?
+        .0
+        .trim()
+        .parse()
+        .ok()
+}
+
+// Resolve the `depends` list of a package's version entry in repo.toml.
+// Dependencies without a repository prefix are looked up in the package's
+// own repository first, then in the rest of the added repositories.
+pub fn get_dependencies(
+    extracted_package: &[String],
+    added_repos: &[Value],
+) -> Result<Vec<Vec<String>>, AatiError> {
+    let mut dependencies = Vec::new();
+
+    let version_entry = match get_version_entry(extracted_package, added_repos) {
+        Some(version_entry) => version_entry,
+        None => return Ok(dependencies),
+    };
+
+    if let Some(depends) = version_entry.get("depends").and_then(|d| d.as_array()) {
+        for dependency in depends {
+            let dependency = dependency.as_str().unwrap();
+
+            match resolve_dependency(dependency, &extracted_package[0], added_repos)? {
+                Some(resolved_dependency) => dependencies.push(resolved_dependency),
+                None => {
+                    return Err(AatiError::DependencyNotFound {
+                        dependency: dependency.into(),
+                        package_name: extracted_package[1].clone(),
+                    });
                 }
             }
         }
+    }
+
+    Ok(dependencies)
+}
+
+fn resolve_dependency(
+    dependency: &str,
+    repo_name: &str,
+    added_repos: &[Value],
+) -> Result<Option<Vec<String>>, AatiError> {
+    let is_in_same_repo = !dependency.contains('/')
+        && added_repos
+            .iter()
+            .filter(|repo| repo["repo"]["name"].as_str().unwrap() == repo_name)
+            .flat_map(|repo| repo["index"]["packages"].as_array().unwrap())
+            .any(|pkg| {
+                let pkg_name = pkg["name"].as_str().unwrap();
+
+                is_supported(pkg["target"].as_str().unwrap())
+                    && (pkg_name == dependency
+                        || dependency
+                            .rsplit_once('-')
+                            .is_some_and(|(name, _)| pkg_name == name))
+            });
+
+    if is_in_same_repo {
+        extract_package(
+            &format!("{}/{}", repo_name, dependency),
+            &added_repos.to_vec(),
+        )
     } else {
-        eprintln!("{}", "- PACKAGE NOT FOUND!".bright_red());
-        exit(1);
+        extract_package(dependency, &added_repos.to_vec())
     }
 }
 
-pub fn verify_checksum(body: &[u8], checksum: String) -> bool {
-    let hash = digest::digest(&digest::SHA256, body);
-    let hex = hex::encode(hash.as_ref());
+// Work out every package that has to be installed before the given one,
+// ordered so that each package comes after its own dependencies
+pub fn resolve_dependencies(
+    extracted_package: &[String],
+    added_repos: &[Value],
+    installed_packages: &[Value],
+) -> Result<Vec<Vec<String>>, AatiError> {
+    let mut resolved: Vec<Vec<String>> = Vec::new();
+    let mut chain: Vec<String> = vec![extracted_package[1].clone()];
+
+    visit_dependencies(
+        extracted_package,
+        added_repos,
+        installed_packages,
+        &mut chain,
+        &mut resolved,
+    )?;
+
+    Ok(resolved)
+}
+
+fn visit_dependencies(
+    extracted_package: &[String],
+    added_repos: &[Value],
+    installed_packages: &[Value],
+    chain: &mut Vec<String>,
+    resolved: &mut Vec<Vec<String>>,
+) -> Result<(), AatiError> {
+    for dependency in get_dependencies(extracted_package, added_repos)? {
+        if installed_packages
+            .iter()
+            .any(|pkg| pkg["name"].as_str().unwrap() == dependency[1])
+            || resolved.iter().any(|pkg| pkg[1] == dependency[1])
+        {
+            continue;
+        }
+
+        if chain.contains(&dependency[1]) {
+            chain.push(dependency[1].clone());
+
+            return Err(AatiError::CircularDependency(chain.clone()));
+        }
+
+        chain.push(dependency[1].clone());
+        visit_dependencies(
+            &dependency,
+            added_repos,
+            installed_packages,
+            chain,
+            resolved,
+        )?;
+        chain.pop();
+
+        resolved.push(dependency);
+    }
+
+    Ok(())
+}
+
+// Make sure the dependencies declared in a PKGFILE are installed, getting the
+// missing ones from the added repositories. Returns the names of the dependency
+// packages, or None if the user aborted the installation of any of them.
+// `chain` is the packages being installed that led to this PKGFILE, ending
+// with its own package.
+pub fn install_pkgfile_dependencies(
+    pkgfile: &Pkgfile,
+    chain: &[String],
+    overwrite: &[String],
+    force: bool,
+    quiet: bool,
+) -> Result<Option<Vec<String>>, AatiError> {
+    let mut dependency_names = Vec::new();
+
+    for dependency in get_pkgfile_dependencies(pkgfile) {
+        let aati_lock: Value = get_aati_lock()?.parse().unwrap();
+        let installed_packages = aati_lock["package"].as_array().unwrap();
+
+        if installed_packages
+            .iter()
+            .any(|pkg| pkg["name"].as_str().unwrap() == dependency)
+        {
+            dependency_names.push(dependency);
+            continue;
+        }
+
+        let aati_config: Value = parse_aati_config(155)?;
+        let repo_list = aati_config["sources"]["repos"].as_array().unwrap();
+        let mut added_repos: Vec<Value> = Vec::new();
+
+        for repo_info in repo_list {
+            added_repos.push(
+                get_repo_config(repo_info["name"].as_str().unwrap())?
+                    .parse::<Value>()
+                    .unwrap(),
+            );
+        }
+
+        match extract_package(&dependency, &added_repos)? {
+            Some(extracted_package) => {
+                if !installed_packages
+                    .iter()
+                    .any(|pkg| pkg["name"].as_str().unwrap() == extracted_package[1])
+                {
+                    // It isn't in the Lockfile yet because it's waiting on
+                    // this very dependency
+                    if chain.contains(&extracted_package[1]) {
+                        let mut chain = chain.to_vec();
+                        chain.push(extracted_package[1].clone());
+
+                        return Err(AatiError::CircularDependency(chain));
+                    }
+
+                    if !quiet {
+                        println!(
+                            "{}",
+                            format!("+ Getting dependency '{}'...", dependency).bright_green()
+                        );
+                    }
+
+                    get_package(
+                        &extracted_package,
+                        split_requirement(&dependency).1,
+                        InstallReason::Automatic,
+                        chain,
+                        overwrite,
+                        force,
+                        quiet,
+                    )?;
+
+                    let aati_lock: Value = get_aati_lock()?.parse().unwrap();
+
+                    if !aati_lock["package"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .any(|pkg| pkg["name"].as_str().unwrap() == extracted_package[1])
+                    {
+                        return Ok(None);
+                    }
+                }
+
+                dependency_names.push(extracted_package[1].clone());
+            }
+
+            None => {
+                return Err(AatiError::PackageNotFound(dependency));
+            }
+        }
+    }
+
+    Ok(Some(dependency_names))
+}
+
+#[test]
+fn test_resolve_dependencies() {
+    use crate::utils::get_target;
+
+    let repo_toml = format!("[repo]
+name = \"testing\"
+maintainer = \"Husayn Haras\"
+description = \"APR made for testing the resolve_dependencies() function\"
+
+[index]
+packages = [
+    {{ name = \"app\", target = \"any\", versions = [
+        {{ tag = \"0.2.0\", checksum = \"checksum-placeholder\", depends = [\"libfoo\", \"libbar-0.1.0\"] }},
+    ], author = \"Husayn Haras\", description = \"Package that depends on two libraries\", url = \"https://example.com\" }},
+    {{ name = \"libfoo\", target = \"{}\", versions = [
+        {{ tag = \"1.0.0\", checksum = \"checksum-placeholder\", depends = [\"libbase\"] }},
+    ], author = \"Husayn Haras\", description = \"Library that depends on libbase\", url = \"https://example.com\" }},
+    {{ name = \"libbar\", target = \"any\", versions = [
+        {{ tag = \"0.2.0\", checksum = \"checksum-placeholder\", depends = [\"libbase\"] }},
+        {{ tag = \"0.1.0\", checksum = \"checksum-placeholder\" }},
+    ], author = \"Husayn Haras\", description = \"Library with no dependencies in 0.1.0\", url = \"https://example.com\" }},
+    {{ name = \"libbase\", target = \"any\", versions = [
+        {{ tag = \"0.1.0\", checksum = \"checksum-placeholder\" }},
+    ], author = \"Husayn Haras\", description = \"Library with no dependencies\", url = \"https://example.com\" }},
+]", get_target());
+
+    let repo_config: Value = repo_toml.parse().unwrap();
+    let added_repos = vec![repo_config];
+
+    let app: Vec<String> = vec!["testing".into(), "app".into(), "0.2.0".into(), "any".into()];
+
+    assert_eq!(
+        resolve_dependencies(&app, &added_repos, &[]).unwrap(),
+        vec![
+            vec![
+                "testing".to_string(),
+                "libbase".into(),
+                "0.1.0".into(),
+                "any".into()
+            ],
+            vec![
+                "testing".to_string(),
+                "libfoo".into(),
+                "1.0.0".into(),
+                get_target()
+            ],
+            vec![
+                "testing".to_string(),
+                "libbar".into(),
+                "0.1.0".into(),
+                "any".into()
+            ],
+        ]
+    );
+
+    let installed_packages: Vec<Value> = vec!["name = \"libbase\"".parse().unwrap()];
+
+    assert_eq!(
+        resolve_dependencies(&app, &added_repos, &installed_packages).unwrap(),
+        vec![
+            vec![
+                "testing".to_string(),
+                "libfoo".into(),
+                "1.0.0".into(),
+                get_target()
+            ],
+            vec![
+                "testing".to_string(),
+                "libbar".into(),
+                "0.1.0".into(),
+                "any".into()
+            ],
+        ]
+    );
+}
+
+#[test]
+fn test_get_range_start() {
+    assert_eq!(get_range_start("bytes 100-999/1000"), Some(100));
+    assert_eq!(get_range_start("bytes 0-0/1"), Some(0));
+    assert_eq!(get_range_start("bytes */1000"), None);
+    assert_eq!(get_range_start("items 100-999/1000"), None);
+}
+
+#[test]
+fn test_pkgfile_circular_dependency() {
+    use std::fs::write;
+
+    use crate::utils::{get_aati_config_path_buf, get_repo_config_path_buf, use_test_home};
+
+    let (_guard, home) = use_test_home("pkgfile-cycle");
+
+    write(
+        get_aati_config_path_buf(),
+        "[sources]\nrepos = [{ name = \"testing\", url = \"http://localhost:1\" }]",
+    )
+    .unwrap();
+    write(get_repo_config_path_buf("testing"), "[repo]
+name = \"testing\"
+maintainer = \"Husayn Haras\"
+description = \"APR made for testing install_pkgfile_dependencies()\"
+
+[index]
+packages = [
+    { name = \"liba\", target = \"any\", versions = [
+        { tag = \"0.1.0\", checksum = \"checksum-placeholder\" },
+    ], author = \"Husayn Haras\", description = \"Depends on libb in its PKGFILE\", url = \"https://example.com\" },
+    { name = \"libb\", target = \"any\", versions = [
+        { tag = \"0.1.0\", checksum = \"checksum-placeholder\" },
+    ], author = \"Husayn Haras\", description = \"Depends on liba in its PKGFILE\", url = \"https://example.com\" },
+]").unwrap();
+
+    // Installing liba got libb, whose PKGFILE asks for liba again
+    let pkgfile = parse_pkgfile("[data]\ndepends liba");
+    let chain = vec!["liba".to_string(), "libb".into()];
+
+    match install_pkgfile_dependencies(&pkgfile, &chain, &[], true, true) {
+        Err(AatiError::CircularDependency(chain)) => {
+            assert_eq!(
+                chain,
+                vec!["liba".to_string(), "libb".into(), "liba".into()]
+            )
+        }
+        result => panic!("expected a circular dependency, got {:?}", result),
+    }
+
+    let _ = remove_dir_all(&home);
+}
+
+#[test]
+fn test_download_resumes_part() {
+    use std::{env::temp_dir, fs::write, process};
+    use tiny_http::{Header, Response, Server, StatusCode};
+
+    let package: Vec<u8> = (0..=255u8).cycle().take(64 * 1024).collect();
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let url = format!(
+        "http://{}/resumed-0.1.0.tar.lz4",
+        server.server_addr().to_ip().unwrap()
+    );
+
+    // Sends what's left after the requested first byte, like `aati serve`
+    let served_package = package.clone();
+    let server_thread = thread::spawn(move || {
+        let request = server.recv().unwrap();
+        let range = request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("Range"))
+            .map(|header| header.value.to_string());
+        let first: usize = range
+            .as_deref()
+            .and_then(|range| range.strip_prefix("bytes="))
+            .and_then(|range| range.strip_suffix('-'))
+            .and_then(|first| first.parse().ok())
+            .unwrap();
+        let content_range = Header::from_bytes(
+            "Content-Range",
+            format!(
+                "bytes {}-{}/{}",
+                first,
+                served_package.len() - 1,
+                served_package.len()
+            ),
+        )
+        .unwrap();
+
+        request
+            .respond(
+                Response::from_data(&served_package[first..])
+                    .with_status_code(StatusCode(206))
+                    .with_header(content_range),
+            )
+            .unwrap();
 
-    hex == checksum
+        range
+    });
+
+    let directory = temp_dir().join(format!("aati-test-resume-{}", process::id()));
+    let _ = remove_dir_all(&directory);
+    create_dir_all(&directory).unwrap();
+
+    // A connection dropped halfway through left this behind
+    let download_path = directory.join("resumed-0.1.0.tar.lz4");
+    write(
+        directory.join("resumed-0.1.0.tar.lz4.part"),
+        &package[..40 * 1024],
+    )
+    .unwrap();
+
+    let progress = Progress::new(Some(package.len() as u64), true);
+    let (resumed, hash) = download(&url, &download_path, &digest::SHA512, &progress).unwrap();
+
+    assert_eq!(
+        server_thread.join().unwrap().as_deref(),
+        Some("bytes=40960-")
+    );
+    assert_eq!(resumed, 40 * 1024);
+    assert_eq!(std::fs::read(&download_path).unwrap(), package);
+    assert_eq!(hash, hex::encode(digest::digest(&digest::SHA512, &package)));
+    assert!(!directory.join("resumed-0.1.0.tar.lz4.part").exists());
+
+    let _ = remove_dir_all(&directory);
 }
@@ -0,0 +1,61 @@
+/* بسم الله الرحمن الرحيم
+
+   Aati - Cross-platform Package Manager written in Rust.
+   Copyright (C) 2023  Husayn Haras <haras@disroot.org>
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of version 3 of the GNU General Public License
+   as published by the Free Software Foundation.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use colored::Colorize;
+
+use crate::{
+    error::AatiError,
+    types::LockFile,
+    utils::{get_aati_lock, update_installed_package},
+};
+
+pub fn command(packages: Vec<String>, held: bool, quiet: bool) -> Result<(), AatiError> {
+    let lock_file: LockFile = toml::from_str(&get_aati_lock()?).unwrap();
+
+    for package_name in packages {
+        if lock_file.package.iter().any(|pkg| pkg.name == package_name) {
+            set_held(&package_name, held)?;
+
+            if !quiet {
+                if held {
+                    println!(
+                        "{}",
+                        format!("+ Holding '{}' at its installed version", package_name)
+                            .bright_green()
+                    );
+                } else {
+                    println!(
+                        "{}",
+                        format!("+ '{}' is no longer held", package_name).bright_green()
+                    );
+                }
+            }
+        } else {
+            eprintln!(
+                "{}",
+                format!("- Package '{}' is not installed!", package_name).bright_red()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub fn set_held(package_name: &str, held: bool) -> Result<(), AatiError> {
+    update_installed_package(package_name, |package| package.held = held)
+}
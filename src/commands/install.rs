@@ -17,45 +17,55 @@
 */
 
 use colored::Colorize;
-use lz4::Decoder;
 use std::{
-    collections::HashMap,
-    env::temp_dir,
-    fs::{read_to_string, remove_dir_all, remove_file, File, OpenOptions},
-    io::{copy, Write},
-    path::PathBuf,
-    process::exit,
+    fs::{read_to_string, remove_dir_all, File},
+    path::{Path, PathBuf},
 };
-use tar::Archive;
 use toml::Value;
 
 use crate::{
-    commands::remove,
-    types::{LockFile, Package, Pkgfile},
+    commands::{get, remove},
+    error::AatiError,
+    types::{InstallReason, Package},
     utils::{
-        execute_lines, get_aati_lock, get_aati_lock_path_buf, get_target, parse_pkgfile, prompt_yn,
+        add_to_lockfile, check_file_conflicts, execute_lines, get_aati_lock, get_script_policy,
+        get_signed_message, get_target, hash_file, parse_pkgfile, parse_public_key, prompt_yn,
+        unpack_package, verify_signature, Checksum,
     },
 };
+use ring::digest;
 
-pub fn command(filename: &str, force: bool, quiet: bool) {
+pub fn command(
+    filename: &str,
+    sha256: Option<&str>,
+    signature: Option<(&str, &str)>,
+    overwrite: &[String],
+    force: bool,
+    quiet: bool,
+) -> Result<(), AatiError> {
     let filename_path_buf = PathBuf::from(filename);
 
-    let parsed_package = parse_filename(filename_path_buf.file_name().unwrap().to_str().unwrap());
+    let parsed_package = parse_filename(filename_path_buf.file_name().unwrap().to_str().unwrap())?;
     let source = parsed_package.source.as_str();
     let name = parsed_package.name.as_str();
     let version = parsed_package.version.as_str();
 
-    let aati_lock: Value = get_aati_lock().parse().unwrap();
+    // Checked before anything is removed so that a bad package can't replace
+    // a working one
+    let verified_checksum =
+        verify_local_package(&filename_path_buf, name, version, sha256, signature, quiet)?;
+
+    let aati_lock: Value = get_aati_lock()?.parse().unwrap();
     let installed_packages = aati_lock["package"].as_array().unwrap();
 
     if installed_packages
         .iter()
         .any(|pkg| pkg["name"].as_str().unwrap() == name)
     {
-        if force || prompt_yn("There's a package with the same name already installed! Do you want to remove the original and proceed?") {
-            remove::command(name, force, quiet);
+        if force || prompt_yn("There's a package with the same name already installed! Do you want to remove the original and proceed?")? {
+            remove::command(name, force, quiet)?;
         } else {
-            exit(0);
+            return Ok(());
         }
     }
 
@@ -68,101 +78,12 @@ pub fn command(filename: &str, force: bool, quiet: bool) {
                         name, version
                     )
                     .as_str(),
-                )
+                )?
             {
-                let mut tar_path_buf = temp_dir();
-                tar_path_buf.push(format!("{}-{}.tar", name, version));
-
-                let mut package_directory = temp_dir();
-                package_directory.push(format!("{}-{}", name, version));
-
-                let mut tarball = match File::create(&tar_path_buf) {
-                    Ok(file) => file,
-                    Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "- FAILED TO CREATE FILE '{}'! ERROR[94]: {}",
-                                tar_path_buf.display(),
-                                error
-                            )
-                            .bright_red()
-                        );
-
-                        exit(1);
-                    }
-                };
-
-                let mut decoder = match Decoder::new(input_file) {
-                    Ok(decoder) => decoder,
-                    Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "- FAILED TO DECODE THE LZ4 COMPRESSED PACKAGE AT '{}'! ERROR[95]: {}",
-                                filename_path_buf.display(),
-                                error
-                            )
-                            .bright_red()
-                        );
-
-                        exit(1);
-                    }
-                };
-
-                match copy(&mut decoder, &mut tarball) {
-                    Ok(_) => {}
-                    Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "- FAILED TO WRITE INTO FILE '{}'! ERROR[97]: {}",
-                                tar_path_buf.display(),
-                                error
-                            )
-                            .bright_red()
-                        );
-
-                        exit(1);
-                    }
-                }
-
-                let tarball = File::open(&tar_path_buf).unwrap();
-
-                let mut archive = Archive::new(tarball);
-
-                match archive.unpack(temp_dir()) {
-                    Ok(_) => {}
-                    Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "- FAILED TO EXTRACT TARBALL '{}'! ERROR[81]: {}",
-                                tar_path_buf.display(),
-                                error
-                            )
-                            .bright_red()
-                        );
-
-                        exit(1);
-                    }
-                }
+                let extraction_directory =
+                    unpack_package(input_file, &filename_path_buf, name, version)?;
 
-                match remove_file(tar_path_buf) {
-                    Ok(_) => {}
-                    Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "- COULD NOT DELETE TEMPORARY PACKAGE TARBALL! ERROR[84]: {}",
-                                error
-                            )
-                            .as_str()
-                            .bright_red()
-                        );
-                        exit(1);
-                    }
-                }
+                let package_directory = extraction_directory.join(format!("{}-{}", name, version));
 
                 let mut pkgfile_path_buf = package_directory.clone();
                 pkgfile_path_buf.push("PKGFILE");
@@ -170,17 +91,13 @@ pub fn command(filename: &str, force: bool, quiet: bool) {
                 let pkgfile = match read_to_string(&pkgfile_path_buf) {
                     Ok(contents) => contents,
                     Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "- FAILED TO READ FILE '{}'! ERROR[82]: {}",
-                                pkgfile_path_buf.display(),
-                                error
-                            )
-                            .bright_red()
-                        );
-
-                        exit(1);
+                        let _ = remove_dir_all(&extraction_directory);
+
+                        return Err(AatiError::ReadFile {
+                            code: 82,
+                            path: pkgfile_path_buf,
+                            error,
+                        });
                     }
                 };
 
@@ -196,105 +113,93 @@ pub fn command(filename: &str, force: bool, quiet: bool) {
                     parsed_pkgfile.installation_lines.clone()
                 };
 
+                if let Err(error) = check_file_conflicts(
+                    name,
+                    &selected_installation_lines,
+                    &parsed_pkgfile.data,
+                    overwrite,
+                ) {
+                    let _ = remove_dir_all(&extraction_directory);
+
+                    return Err(error);
+                }
+
+                let dependencies = match get::install_pkgfile_dependencies(
+                    &parsed_pkgfile,
+                    &[name.to_string()],
+                    overwrite,
+                    force,
+                    quiet,
+                ) {
+                    Ok(Some(dependencies)) => dependencies,
+                    Ok(None) => {
+                        let _ = remove_dir_all(&extraction_directory);
+
+                        return Err(AatiError::DependenciesNotInstalled(name.into()));
+                    }
+                    Err(error) => {
+                        let _ = remove_dir_all(&extraction_directory);
+
+                        return Err(error);
+                    }
+                };
+
                 if force
                     || prompt_yn(&format!(
                         "+ Commands to be ran:\n  {}\n/ Do these commands seem safe to execute?",
                         selected_installation_lines.join("\n  ")
-                    ))
+                    ))?
                 {
-                    execute_lines(
+                    let (files, transaction) = match execute_lines(
                         &selected_installation_lines,
                         &parsed_pkgfile.data,
                         Some(&package_directory),
+                        &get_script_policy("local")?,
                         quiet,
-                    );
-
-                    match remove_dir_all(package_directory) {
-                        Ok(_) => {}
+                    ) {
+                        Ok(result) => result,
                         Err(error) => {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                    "- COULD NOT DELETE TEMPORARY PACKAGE DIRECTORY! ERROR[85]: {}",
-                                    error
-                                )
-                                .as_str()
-                                .bright_red()
-                            );
-                            exit(1);
+                            let _ = remove_dir_all(&extraction_directory);
+
+                            return Err(error);
                         }
-                    }
+                    };
 
                     if !quiet {
                         println!("{}", "+ Adding Package to the Lockfile...".bright_green());
                     }
 
-                    let aati_lock_path_buf = get_aati_lock_path_buf();
-
-                    let lock_file_str = match read_to_string(&aati_lock_path_buf) {
-                        Ok(contents) => contents,
-                        Err(error) => {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                    "- FAILED TO READ LOCKFILE AT '{}'! ERROR[98]: {}",
-                                    &aati_lock_path_buf.display(),
-                                    error
-                                )
-                                .bright_red()
-                            );
-
-                            exit(1);
-                        }
-                    };
-                    let mut lock_file: LockFile = toml::from_str(&lock_file_str).unwrap();
-
                     let package = Package {
                         name: name.into(),
                         version: version.into(),
                         source: source.into(),
                         target: get_target(),
+                        reason: InstallReason::Explicit,
+                        requirement: None,
+                        held: false,
+                        checksum: verified_checksum,
+                        dependencies,
+                        files,
                         pkgfile: parsed_pkgfile.clone(),
                     };
 
-                    lock_file.package.push(package);
+                    if let Err(error) = add_to_lockfile(package, 98, 2) {
+                        transaction.rollback(quiet);
+                        let _ = remove_dir_all(&extraction_directory);
 
-                    let mut file = match OpenOptions::new()
-                        .write(true)
-                        .truncate(true)
-                        .open(&aati_lock_path_buf)
-                    {
-                        Ok(file) => file,
-                        Err(error) => {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                    "- FAILED TO OPEN LOCKFILE AT '{}' FOR WRITING! ERROR[80]: {}",
-                                    &aati_lock_path_buf.display(),
-                                    error
-                                )
-                                .bright_red()
-                            );
+                        return Err(error);
+                    }
 
-                            exit(1);
-                        }
-                    };
+                    transaction.commit();
 
-                    let toml_str = toml::to_string(&lock_file).unwrap();
-                    match file.write_all(toml_str.as_bytes()) {
+                    match remove_dir_all(&extraction_directory) {
                         Ok(_) => {}
                         Err(error) => {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                    "- FAILED TO WRITE INTO LOCKFILE AT '{}'! ERROR[2]: {}",
-                                    &aati_lock_path_buf.display(),
-                                    error
-                                )
-                                .bright_red()
-                            );
-
-                            exit(1);
+                            return Err(AatiError::DeleteDirectory {
+                                code: 85,
+                                path: extraction_directory,
+                                error,
+                            });
                         }
                     }
 
@@ -306,7 +211,7 @@ pub fn command(filename: &str, force: bool, quiet: bool) {
                         println!("{}", "+ Transaction aborted".bright_green());
                     }
 
-                    match remove_dir_all(&package_directory) {
+                    match remove_dir_all(&extraction_directory) {
                         Ok(_) => {
                             if !quiet {
                                 println!(
@@ -316,17 +221,11 @@ pub fn command(filename: &str, force: bool, quiet: bool) {
                             }
                         }
                         Err(error) => {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                    "- FAILED TO DELETE DIRECTORY '{}'! ERROR[86]: {}",
-                                    package_directory.display(),
-                                    error
-                                )
-                                .as_str()
-                                .bright_red()
-                            );
-                            exit(1);
+                            return Err(AatiError::DeleteDirectory {
+                                code: 86,
+                                path: extraction_directory,
+                                error,
+                            });
                         }
                     }
                 }
@@ -336,25 +235,155 @@ pub fn command(filename: &str, force: bool, quiet: bool) {
         }
 
         Err(error) => {
-            eprintln!("{}", format!("- ERROR[11]: {}", error).bright_red());
-            exit(1);
+            return Err(AatiError::OpenFile {
+                code: 11,
+                path: filename_path_buf,
+                error,
+            });
         }
     }
+
+    Ok(())
+}
+
+// Check a local package against the SHA256 checksum given with --sha256, or
+// the one in a <file>.sha256 next to it, and the signature given with --sig.
+// Returns the checksum to record in the Lockfile if either was checked: the
+// SHA256 one, or else the SHA512 digest that the signature covers.
+fn verify_local_package(
+    package_path: &Path,
+    name: &str,
+    version: &str,
+    sha256: Option<&str>,
+    signature: Option<(&str, &str)>,
+    quiet: bool,
+) -> Result<Option<String>, AatiError> {
+    let mut checksum_path = package_path.as_os_str().to_owned();
+    checksum_path.push(".sha256");
+    let checksum_path = PathBuf::from(checksum_path);
+
+    // Sibling checksum files are usually sha256sum output: "<hex>  <filename>"
+    let expected_checksum = match sha256 {
+        Some(sha256) => Some(sha256.to_string()),
+        None if checksum_path.is_file() => match read_to_string(&checksum_path) {
+            Ok(contents) => Some(
+                contents
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+            ),
+            Err(error) => {
+                return Err(AatiError::ReadFile {
+                    code: 136,
+                    path: checksum_path,
+                    error,
+                });
+            }
+        },
+        None => None,
+    };
+
+    let mut verified_checksum = None;
+
+    if let Some(expected_checksum) = expected_checksum {
+        let checksum = match Checksum::parse(&expected_checksum)
+            .filter(|checksum| checksum.algorithm == &digest::SHA256)
+        {
+            Some(checksum) => checksum,
+            None => {
+                return Err(AatiError::InvalidChecksum {
+                    package_name: name.into(),
+                    checksum: expected_checksum,
+                });
+            }
+        };
+
+        let hash = match hash_file(package_path, checksum.algorithm) {
+            Ok(hash) => hash,
+            Err(error) => {
+                return Err(AatiError::ReadFile {
+                    code: 137,
+                    path: package_path.to_path_buf(),
+                    error,
+                });
+            }
+        };
+
+        if hash != checksum.hash {
+            return Err(AatiError::ChecksumMismatch(name.into()));
+        }
+
+        if !quiet {
+            println!(
+                "{}",
+                format!("+ Checksums of {} match!", name).bright_green()
+            );
+        }
+
+        verified_checksum = Some(format!("sha256:{}", hash));
+    }
+
+    if let Some((signature_path, key)) = signature {
+        let public_key = parse_public_key(key)?;
+
+        let signature = match read_to_string(signature_path) {
+            Ok(contents) => contents,
+            Err(error) => {
+                return Err(AatiError::ReadFile {
+                    code: 138,
+                    path: signature_path.into(),
+                    error,
+                });
+            }
+        };
+
+        let sha512 = match hash_file(package_path, &digest::SHA512) {
+            Ok(hash) => hash,
+            Err(error) => {
+                return Err(AatiError::ReadFile {
+                    code: 139,
+                    path: package_path.to_path_buf(),
+                    error,
+                });
+            }
+        };
+
+        let message = get_signed_message(name, version, &sha512);
+
+        if !verify_signature(&public_key, message.as_bytes(), &signature) {
+            return Err(AatiError::BadSignature(package_path.display().to_string()));
+        }
+
+        if !quiet {
+            println!(
+                "{}",
+                format!("+ The signature of {} is valid!", name).bright_green()
+            );
+        }
+
+        if verified_checksum.is_none() {
+            verified_checksum = Some(format!("sha512:{}", sha512));
+        }
+    }
+
+    Ok(verified_checksum)
 }
 
 pub fn use_pkgfile(
     path_str: &str,
     provided_name: Option<&String>,
     provided_version: Option<&String>,
+    overwrite: &[String],
     force: bool,
     quiet: bool,
-) -> Result<(), String> {
+) -> Result<(), AatiError> {
     let pkgfile_path_buf = PathBuf::from(path_str);
 
     if pkgfile_path_buf.exists() {
         match read_to_string(&pkgfile_path_buf) {
             Ok(pkgfile) => {
-                let aati_lock: Value = get_aati_lock().parse().unwrap();
+                let aati_lock: Value = get_aati_lock()?.parse().unwrap();
                 let installed_packages = aati_lock["package"].as_array().unwrap();
 
                 let package_directory = pkgfile_path_buf.parent().unwrap().to_path_buf();
@@ -366,10 +395,7 @@ pub fn use_pkgfile(
                 } else if let Some(name) = provided_name {
                     name
                 } else {
-                    return Err(
-                        "Package name not provided by the PKGFILE nor as a command line argument!"
-                            .into(),
-                    );
+                    return Err(AatiError::MissingPkgfileData("name"));
                 };
 
                 let version = if let Some(version) = parsed_pkgfile.data.get("version") {
@@ -377,121 +403,108 @@ pub fn use_pkgfile(
                 } else if let Some(version) = provided_version {
                     version
                 } else {
-                    return Err("Package version not provided by the PKGFILE nor as a command line argument!".into());
+                    return Err(AatiError::MissingPkgfileData("version"));
+                };
+
+                let selected_installation_lines = if cfg!(windows) {
+                    if !parsed_pkgfile.win_installation_lines.is_empty() {
+                        parsed_pkgfile.win_installation_lines.clone()
+                    } else {
+                        parsed_pkgfile.installation_lines.clone()
+                    }
+                } else {
+                    parsed_pkgfile.installation_lines.clone()
                 };
 
+                check_file_conflicts(
+                    name,
+                    &selected_installation_lines,
+                    &parsed_pkgfile.data,
+                    overwrite,
+                )?;
+
                 if installed_packages
                     .iter()
                     .any(|pkg| pkg["name"].as_str().unwrap() == name)
                 {
-                    if force || prompt_yn("There's a package with the same name already installed! Do you want to remove the original and proceed?") {
-                        remove::command(name, force, quiet);
+                    if force || prompt_yn("There's a package with the same name already installed! Do you want to remove the original and proceed?")? {
+                        remove::command(name, force, quiet)?;
                     } else {
                         return Ok(())
                     }
                 }
 
-                let selected_installation_lines = if cfg!(windows) {
-                    if !parsed_pkgfile.win_installation_lines.is_empty() {
-                        parsed_pkgfile.win_installation_lines.clone()
-                    } else {
-                        parsed_pkgfile.installation_lines.clone()
-                    }
-                } else {
-                    parsed_pkgfile.installation_lines.clone()
+                let dependencies = match get::install_pkgfile_dependencies(
+                    &parsed_pkgfile,
+                    &[name.to_string()],
+                    overwrite,
+                    force,
+                    quiet,
+                )? {
+                    Some(dependencies) => dependencies,
+                    None => return Err(AatiError::DependenciesNotInstalled(name.into())),
                 };
 
-                if force
+                let (files, transaction) = if force
                     || prompt_yn(&format!(
                         "+ Commands to be ran:\n  {}\n/ Do these commands seem safe to execute?",
                         selected_installation_lines.join("\n  ")
-                    ))
-                {
+                    ))? {
                     execute_lines(
                         &selected_installation_lines,
                         &parsed_pkgfile.data,
                         Some(&package_directory),
+                        &get_script_policy("local")?,
                         quiet,
-                    );
+                    )?
                 } else {
                     return Ok(());
-                }
+                };
 
                 if !quiet {
                     println!("{}", "+ Adding Package to the Lockfile...".bright_green());
                 }
 
-                let aati_lock_path_buf = get_aati_lock_path_buf();
-
-                let lock_file_str = match read_to_string(&aati_lock_path_buf) {
-                    Ok(contents) => contents,
-                    Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "- FAILED TO READ LOCKFILE AT '{}'! ERROR[98]: {}",
-                                &aati_lock_path_buf.display(),
-                                error
-                            )
-                            .bright_red()
-                        );
-
-                        exit(1);
-                    }
-                };
-                let mut lock_file: LockFile = toml::from_str(&lock_file_str).unwrap();
-
                 let package = Package {
                     name: name.into(),
                     version: version.into(),
                     source: "local".into(),
                     target: get_target(),
+                    reason: InstallReason::Explicit,
+                    requirement: None,
+                    held: false,
+                    checksum: None,
+                    dependencies,
+                    files,
                     pkgfile: parsed_pkgfile,
                 };
 
-                lock_file.package.push(package);
+                match add_to_lockfile(package, 98, 2) {
+                    Ok(_) => {
+                        transaction.commit();
 
-                let mut file = match OpenOptions::new()
-                    .write(true)
-                    .truncate(true)
-                    .open(&aati_lock_path_buf)
-                {
-                    Ok(file) => file,
-                    Err(error) => {
-                        return Err(format!(
-                            "- FAILED TO OPEN LOCKFILE AT '{}' FOR WRITING! ERROR[80]: {}",
-                            &aati_lock_path_buf.display(),
-                            error
-                        ))
+                        Ok(())
                     }
-                };
+                    Err(error) => {
+                        transaction.rollback(quiet);
 
-                let toml_str = toml::to_string(&lock_file).unwrap();
-                match file.write_all(toml_str.as_bytes()) {
-                    Ok(_) => Ok(()),
-                    Err(error) => Err(format!(
-                        "- FAILED TO WRITE INTO LOCKFILE AT '{}'! ERROR[2]: {}",
-                        &aati_lock_path_buf.display(),
-                        error
-                    )),
+                        Err(error)
+                    }
                 }
             }
 
-            Err(error) => Err(format!(
-                "Failed to read PKGFILE contents at '{}'! ERROR: {}",
-                pkgfile_path_buf.display(),
-                error
-            )),
+            Err(error) => Err(AatiError::ReadFile {
+                code: 114,
+                path: pkgfile_path_buf,
+                error,
+            }),
         }
     } else {
-        Err(format!(
-            "No PKGFILE found at '{}'",
-            pkgfile_path_buf.display()
-        ))
+        Err(AatiError::PkgfileNotFound(pkgfile_path_buf))
     }
 }
 
-pub fn parse_filename(mut filename: &str) -> Package {
+pub fn parse_filename(mut filename: &str) -> Result<Package, AatiError> {
     // Example Usage: parse_filename("dummy-package-0.1.0.tar.lz4");
 
     filename = filename.trim();
@@ -500,12 +513,10 @@ pub fn parse_filename(mut filename: &str) -> Package {
         let package = if let Some((package, _)) = filename.rsplit_once(".tar.lz4") {
             package
         } else {
-            eprintln!(
-                "{}",
-                format!("- FILE '{}' HAS AN INVALID FILENAME!", filename).bright_red()
-            );
-
-            exit(1);
+            return Err(AatiError::InvalidFilename(format!(
+                "FILE '{}' HAS AN INVALID FILENAME!",
+                filename
+            )));
         };
 
         // package's value is now: dummy-package-0.1.0
@@ -513,40 +524,25 @@ pub fn parse_filename(mut filename: &str) -> Package {
         let (name, version) = if let Some((name, version)) = package.rsplit_once('-') {
             (name, version)
         } else {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FILE '{}' DOESN'T CONTAIN A HYPHEN AS A SEPARATOR!",
-                    filename
-                )
-                .bright_red()
-            );
-
-            exit(1);
+            return Err(AatiError::InvalidFilename(format!(
+                "FILE '{}' DOESN'T CONTAIN A HYPHEN AS A SEPARATOR!",
+                filename
+            )));
         };
 
         // Now: name = "dummy-package", version = "0.1.0"
 
-        Package {
+        Ok(Package {
             name: name.into(),
             version: version.into(),
             source: "local".into(),
             target: get_target(),
-            pkgfile: Pkgfile {
-                data: HashMap::new(),
-                installation_lines: vec![],
-                win_installation_lines: vec![],
-                removal_lines: vec![],
-                win_removal_lines: vec![],
-            },
-        } //         ^^^^^ That's the name of the repo containing locally installed packages.
+            ..Default::default()
+        }) //        ^^^^^ That's the name of the repo containing locally installed packages.
     } else {
-        eprintln!(
-            "{}\n{}",
-            "- Unidentified file extension!".bright_red(),
-            "+ Note: Only .tar.lz4 files are installable.".bright_blue()
-        );
-        exit(1);
+        Err(AatiError::InvalidFilename(
+            "Unidentified file extension! Note: Only .tar.lz4 files are installable.".into(),
+        ))
     }
 }
 
@@ -558,13 +554,7 @@ fn test_parse_filename() {
         version: "0.3.3".into(),
         source: "local".into(),
         target: get_target(),
-        pkgfile: Pkgfile {
-            data: HashMap::new(),
-            installation_lines: vec![],
-            win_installation_lines: vec![],
-            removal_lines: vec![],
-            win_removal_lines: vec![],
-        },
+        ..Default::default()
     };
 
     let filename2 = "arsil-server-0.2.1.tar.lz4";
@@ -573,15 +563,186 @@ fn test_parse_filename() {
         version: "0.2.1".into(),
         source: "local".into(),
         target: get_target(),
-        pkgfile: Pkgfile {
-            data: HashMap::new(),
-            installation_lines: vec![],
-            win_installation_lines: vec![],
-            removal_lines: vec![],
-            win_removal_lines: vec![],
-        },
+        ..Default::default()
+    };
+
+    assert_eq!(parse_filename(filename1).unwrap(), expected_result1);
+    assert_eq!(parse_filename(filename2).unwrap(), expected_result2);
+}
+
+#[test]
+fn test_mismatch_keeps_installed_package() {
+    use std::fs::write;
+
+    use crate::{
+        types::LockFile,
+        utils::{get_aati_lock_path_buf, get_bin_path_buf, use_test_home},
+    };
+
+    let (_guard, home) = use_test_home("install-mismatch");
+
+    let installed_file = get_bin_path_buf().join("foo");
+    write(&installed_file, "working").unwrap();
+
+    let lock_file = LockFile {
+        package: vec![Package {
+            pkgfile: parse_pkgfile("[removal]\n  delete $bin_dir/foo"),
+            ..Package::test("foo")
+        }],
+    };
+    let lock_file_str = toml::to_string(&lock_file).unwrap();
+    write(get_aati_lock_path_buf(), &lock_file_str).unwrap();
+
+    let package_path = home.join("foo-0.2.0.tar.lz4");
+    write(&package_path, "tampered").unwrap();
+
+    let result = command(
+        package_path.to_str().unwrap(),
+        Some(&format!("sha256:{}", "0".repeat(64))),
+        None,
+        &[],
+        true,
+        true,
+    );
+
+    assert!(matches!(result, Err(AatiError::ChecksumMismatch(_))));
+    assert_eq!(
+        read_to_string(get_aati_lock_path_buf()).unwrap(),
+        lock_file_str
+    );
+    assert_eq!(read_to_string(&installed_file).unwrap(), "working");
+
+    let _ = remove_dir_all(&home);
+}
+
+#[test]
+fn test_lockfile_failure_rolls_back() {
+    use std::fs::{create_dir_all, write};
+
+    use crate::utils::{get_aati_lock_path_buf, get_bin_path_buf, use_test_home};
+
+    let (_guard, home) = use_test_home("install-rollback");
+
+    let package_directory = home.join("rolled-0.1.0");
+    create_dir_all(&package_directory).unwrap();
+    write(package_directory.join("rolled"), "#!/bin/sh").unwrap();
+    write(
+        package_directory.join("PKGFILE"),
+        "[data]\nname rolled\nversion 0.1.0\n\n[installation]\ninstall rolled $bin_dir/rolled\n\n[removal]\ndelete $bin_dir/rolled",
+    )
+    .unwrap();
+
+    // Nothing can be written where the new Lockfile is put before it's renamed
+    let mut temp_path = get_aati_lock_path_buf().into_os_string();
+    temp_path.push(".tmp");
+    create_dir_all(&temp_path).unwrap();
+
+    let result = use_pkgfile(
+        package_directory.join("PKGFILE").to_str().unwrap(),
+        None,
+        None,
+        &[],
+        true,
+        true,
+    );
+
+    assert!(matches!(result, Err(AatiError::WriteFile { code: 2, .. })));
+    assert!(!get_bin_path_buf().join("rolled").exists());
+
+    let _ = remove_dir_all(&home);
+}
+
+#[test]
+fn test_verify_local_package() {
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+    use std::{
+        env::temp_dir,
+        fs::{create_dir_all, remove_file, write},
+        process,
+    };
+
+    let directory = temp_dir().join(format!("aati-test-verify-{}", process::id()));
+    let _ = remove_dir_all(&directory);
+    create_dir_all(&directory).unwrap();
+
+    let package_path = directory.join("tool-0.1.0.tar.lz4");
+    write(&package_path, "tarball").unwrap();
+
+    let sha256 = hash_file(&package_path, &digest::SHA256).unwrap();
+    let sha512 = hash_file(&package_path, &digest::SHA512).unwrap();
+    let wrong_sha256 = "0".repeat(64);
+
+    let key_pair = Ed25519KeyPair::from_pkcs8(
+        Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .unwrap()
+            .as_ref(),
+    )
+    .unwrap();
+    let key = hex::encode(key_pair.public_key());
+
+    let signature_path = directory.join("tool-0.1.0.tar.lz4.sig");
+    let bad_signature_path = directory.join("bad.sig");
+    write(
+        &signature_path,
+        hex::encode(key_pair.sign(get_signed_message("tool", "0.1.0", &sha512).as_bytes())),
+    )
+    .unwrap();
+    write(
+        &bad_signature_path,
+        hex::encode(key_pair.sign(get_signed_message("tool", "0.2.0", &sha512).as_bytes())),
+    )
+    .unwrap();
+
+    let signature_str = signature_path.to_str().unwrap();
+    let bad_signature_str = bad_signature_path.to_str().unwrap();
+    let verify = |sha256: Option<&str>, signature: Option<(&str, &str)>| {
+        verify_local_package(&package_path, "tool", "0.1.0", sha256, signature, true)
     };
 
-    assert_eq!(parse_filename(filename1), expected_result1);
-    assert_eq!(parse_filename(filename2), expected_result2);
+    // A stale checksum file next to the package is overridden by --sha256
+    write(
+        directory.join("tool-0.1.0.tar.lz4.sha256"),
+        format!("{}  tool-0.1.0.tar.lz4\n", wrong_sha256),
+    )
+    .unwrap();
+
+    assert_eq!(
+        verify(Some(&sha256), None).unwrap(),
+        Some(format!("sha256:{}", sha256))
+    );
+
+    // The checksum is checked before the signature
+    assert!(matches!(
+        verify(None, Some((bad_signature_str, &key))),
+        Err(AatiError::ChecksumMismatch(_))
+    ));
+
+    write(
+        directory.join("tool-0.1.0.tar.lz4.sha256"),
+        format!("{}  tool-0.1.0.tar.lz4\n", sha256),
+    )
+    .unwrap();
+
+    assert!(matches!(
+        verify(None, Some((bad_signature_str, &key))),
+        Err(AatiError::BadSignature(_))
+    ));
+    assert_eq!(
+        verify(None, Some((signature_str, &key))).unwrap(),
+        Some(format!("sha256:{}", sha256))
+    );
+
+    // Without a checksum, the digest the signature covers is recorded
+    remove_file(directory.join("tool-0.1.0.tar.lz4.sha256")).unwrap();
+
+    assert_eq!(
+        verify(None, Some((signature_str, &key))).unwrap(),
+        Some(format!("sha512:{}", sha512))
+    );
+    assert_eq!(verify(None, None).unwrap(), None);
+
+    let _ = remove_dir_all(&directory);
 }
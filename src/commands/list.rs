@@ -17,12 +17,17 @@
 */
 
 use colored::Colorize;
+use std::cmp::Ordering;
 use toml::Value;
 
-use crate::utils::{get_aati_config, get_aati_lock, get_repo_config, is_supported};
+use crate::error::AatiError;
+use crate::utils::{
+    compare_versions, get_aati_lock, get_allowed_version, get_repo_config, is_supported,
+    parse_aati_config,
+};
 
-pub fn installed() {
-    let aati_lock: Value = get_aati_lock().parse().unwrap();
+pub fn installed() -> Result<(), AatiError> {
+    let aati_lock: Value = get_aati_lock()?.parse().unwrap();
     let installed_packages = aati_lock["package"].as_array().unwrap();
 
     println!("{}", "+ Installed Packages:".bright_green());
@@ -30,43 +35,41 @@ pub fn installed() {
     if !installed_packages.is_empty() {
         for installed_package in installed_packages {
             if installed_package["source"].as_str().unwrap() != "local" {
-                match get_repo_config(installed_package["source"].as_str().unwrap())
+                let is_outdated = get_repo_config(installed_package["source"].as_str().unwrap())?
                     .parse::<Value>()
                     .unwrap()["index"]["packages"]
                     .as_array()
                     .unwrap()
                     .iter()
-                    .find(|pkg| {
+                    .any(|pkg| {
                         pkg["name"] == installed_package["name"]
                             && is_supported(pkg["target"].as_str().unwrap())
-                            && pkg["versions"].as_array().unwrap().first().unwrap()["tag"]
-                                .as_str()
-                                .unwrap()
-                                != installed_package["version"].as_str().unwrap()
-                    }) {
-                    Some(_) => {
-                        println!(
-                            "{}   {}/{}-{}-{} {}",
-                            "+".bright_green(),
-                            installed_package["source"].as_str().unwrap(),
-                            installed_package["name"].as_str().unwrap(),
-                            installed_package["version"].as_str().unwrap(),
-                            installed_package["target"].as_str().unwrap(),
-                            "[outdated]".yellow()
-                        );
-                    }
-
-                    None => {
-                        println!(
-                            "{}   {}/{}-{}-{}",
-                            "+".bright_green(),
-                            installed_package["source"].as_str().unwrap(),
-                            installed_package["name"].as_str().unwrap(),
-                            installed_package["version"].as_str().unwrap(),
-                            installed_package["target"].as_str().unwrap()
-                        );
-                    }
+                            && get_allowed_version(
+                                installed_package,
+                                pkg["versions"].as_array().unwrap(),
+                            )
+                            .is_some_and(|version| {
+                                compare_versions(
+                                    version["tag"].as_str().unwrap(),
+                                    installed_package["version"].as_str().unwrap(),
+                                ) == Ordering::Greater
+                            })
+                    });
+
+                let mut line = format!(
+                    "{}   {}/{}-{}-{}",
+                    "+".bright_green(),
+                    installed_package["source"].as_str().unwrap(),
+                    installed_package["name"].as_str().unwrap(),
+                    installed_package["version"].as_str().unwrap(),
+                    installed_package["target"].as_str().unwrap()
+                );
+
+                if is_outdated {
+                    line.push_str(&format!(" {}", "[outdated]".yellow()));
                 }
+
+                println!("{}{}", line, held_marker(installed_package));
             }
         }
 
@@ -82,11 +85,12 @@ pub fn installed() {
             for installed_package in installed_packages {
                 if installed_package["source"].as_str().unwrap() == "local" {
                     println!(
-                        "{}   {}/{}-{}",
+                        "{}   {}/{}-{}{}",
                         "+".bright_green(),
                         installed_package["source"].as_str().unwrap(),
                         installed_package["name"].as_str().unwrap(),
-                        installed_package["version"].as_str().unwrap()
+                        installed_package["version"].as_str().unwrap(),
+                        held_marker(installed_package)
                     );
                 }
             }
@@ -94,13 +98,25 @@ pub fn installed() {
     } else {
         println!("  None! Install Packages using: $ aati get <package>");
     }
+
+    Ok(())
 }
 
-pub fn available() {
-    let aati_config: Value = get_aati_config().unwrap().parse().unwrap();
+fn held_marker(installed_package: &Value) -> String {
+    match installed_package
+        .get("held")
+        .and_then(|held| held.as_bool())
+    {
+        Some(true) => format!(" {}", "[held]".bright_blue()),
+        _ => String::new(),
+    }
+}
+
+pub fn available() -> Result<(), AatiError> {
+    let aati_config: Value = parse_aati_config(156)?;
     let repos = aati_config["sources"]["repos"].as_array().unwrap();
 
-    let aati_lock: Value = get_aati_lock().parse().unwrap();
+    let aati_lock: Value = get_aati_lock()?.parse().unwrap();
     let installed_packages = aati_lock["package"].as_array().unwrap();
 
     println!("{}", "+ Available Packages:".bright_green());
@@ -109,7 +125,7 @@ pub fn available() {
         for repo in repos {
             let repo_name = repo["name"].as_str().unwrap();
 
-            let repo_toml: Value = get_repo_config(repo_name).parse().unwrap();
+            let repo_toml: Value = get_repo_config(repo_name)?.parse().unwrap();
             let available_packages = repo_toml["index"]["packages"].as_array().unwrap();
 
             println!("{}   {}/", "+".bright_green(), repo_name);
@@ -155,7 +171,7 @@ pub fn available() {
         for repo in repos {
             let repo_name = repo["name"].as_str().unwrap();
 
-            let repo_toml: Value = get_repo_config(repo_name).parse().unwrap();
+            let repo_toml: Value = get_repo_config(repo_name)?.parse().unwrap();
             let available_packages = repo_toml["index"]["packages"].as_array().unwrap();
 
             println!("{}   {}/", "+".yellow(), repo_name);
@@ -183,4 +199,6 @@ pub fn available() {
     } else {
         println!("    None!");
     }
+
+    Ok(())
 }
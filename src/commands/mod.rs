@@ -20,35 +20,52 @@ use colored::Colorize;
 use toml::Value;
 
 use crate::{
+    error::AatiError,
+    types::LockFile,
     utils::{get_aati_lock, get_package_versions, prompt_yn},
     version::get_versions,
 };
 
+pub mod autoremove;
+pub mod cache;
 mod changelog;
+pub mod downgrade;
+pub mod files;
 pub mod generate;
 pub mod get;
+pub mod hold;
 pub mod install;
 pub mod list;
+pub mod mark;
+pub mod owns;
 pub mod package;
 pub mod query;
 mod remove;
 pub mod repo;
 pub mod serve;
+pub mod sign;
 pub mod sync;
 pub mod upgrade;
 
 // Either a Some() of a Vec of Strings or a None which will be treated as --all
-pub fn remove(packages_option: Option<Vec<String>>, lock: bool, force: bool, quiet: bool) {
-    let aati_lock: Value = get_aati_lock().parse().unwrap();
+pub fn remove(
+    packages_option: Option<Vec<String>>,
+    lock: bool,
+    cascade: bool,
+    force: bool,
+    quiet: bool,
+) -> Result<(), AatiError> {
+    let aati_lock: Value = get_aati_lock()?.parse().unwrap();
     let installed_packages = aati_lock["package"].as_array().unwrap();
 
     if let Some(packages) = packages_option {
         if lock {
             // $ aati remove --lock package1 package2 package3...
+            // Only the Lockfile is touched, so dependents aren't checked
             let mut did_removal = false;
 
             for package_name in packages {
-                if let Some(installed_package) = is_installed(&package_name) {
+                if let Some(installed_package) = is_installed(&package_name)? {
                     if !quiet {
                         println!(
                             "{}",
@@ -62,7 +79,7 @@ pub fn remove(packages_option: Option<Vec<String>>, lock: bool, force: bool, qui
                         );
                     }
 
-                    remove::remove_from_lockfile(&package_name);
+                    remove::remove_from_lockfile(&package_name)?;
                     did_removal = true;
                 } else if !quiet {
                     eprintln!(
@@ -84,8 +101,107 @@ pub fn remove(packages_option: Option<Vec<String>>, lock: bool, force: bool, qui
             }
         } else {
             // $ aati remove package1 package2 package3...
+            let lock_file: LockFile = toml::from_str(&get_aati_lock()?).unwrap();
+            let mut to_be_removed: Vec<String> = Vec::new();
+
             for package_name in packages {
-                if let Some(package) = is_installed(&package_name) {
+                if is_installed(&package_name)?.is_some() {
+                    if !to_be_removed.contains(&package_name) {
+                        to_be_removed.push(package_name);
+                    }
+                } else {
+                    eprintln!(
+                        "{}",
+                        format!("- Package '{}' is not installed!", package_name).bright_red()
+                    );
+                }
+            }
+
+            if cascade {
+                for package_name in to_be_removed.clone() {
+                    for dependent in remove::get_dependents(&package_name, &lock_file.package) {
+                        if !to_be_removed.contains(&dependent) {
+                            to_be_removed.push(dependent);
+                        }
+                    }
+                }
+            } else {
+                // Nothing is removed if other installed packages still need any of them
+                for package_name in &to_be_removed {
+                    let dependents: Vec<String> =
+                        remove::get_dependents(package_name, &lock_file.package)
+                            .into_iter()
+                            .filter(|dependent| !to_be_removed.contains(dependent))
+                            .collect();
+
+                    if !dependents.is_empty() {
+                        return Err(AatiError::RequiredBy {
+                            package_name: package_name.clone(),
+                            dependents,
+                        });
+                    }
+                }
+            }
+
+            let to_be_removed = remove::order_for_removal(&to_be_removed, &lock_file.package);
+
+            if cascade && to_be_removed.len() > 1 {
+                if !quiet {
+                    println!(
+                        "{}",
+                        "+ Packages to be removed, in this order:".bright_green()
+                    );
+
+                    for package_name in &to_be_removed {
+                        let package = is_installed(package_name)?.unwrap();
+
+                        println!(
+                            "{}   {}/{}-{}",
+                            "+".bright_green(),
+                            package["source"].as_str().unwrap(),
+                            package_name,
+                            package["version"].as_str().unwrap()
+                        );
+                    }
+                }
+
+                if force || prompt_yn("/ Are you sure you want to completely remove them?")? {
+                    for package_name in &to_be_removed {
+                        if !quiet {
+                            println!(
+                                "{}",
+                                format!("+ Removing '{}'...", package_name).bright_green()
+                            );
+                        }
+
+                        remove::command(package_name, true, quiet)?;
+                    }
+                } else if !quiet {
+                    println!("{}", "+ Transaction aborted".bright_green());
+                }
+            } else {
+                for package_name in to_be_removed {
+                    let package = is_installed(&package_name)?.unwrap();
+
+                    // Dependents come first, so any that are still installed
+                    // are ones the user chose to keep
+                    let lock_file: LockFile = toml::from_str(&get_aati_lock()?).unwrap();
+                    let kept_dependents = remove::get_dependents(&package_name, &lock_file.package);
+
+                    if !kept_dependents.is_empty() {
+                        eprintln!(
+                            "{}",
+                            format!(
+                                "- Package '{}' is kept since it's still required by: {}",
+                                package_name,
+                                kept_dependents.join(", ")
+                            )
+                            .bright_red()
+                        );
+
+                        continue;
+                    }
+
                     if force
                         || prompt_yn(
                             format!(
@@ -95,7 +211,7 @@ pub fn remove(packages_option: Option<Vec<String>>, lock: bool, force: bool, qui
                                 package["version"].as_str().unwrap()
                             )
                             .as_str(),
-                        )
+                        )?
                     {
                         if !quiet {
                             println!(
@@ -103,15 +219,10 @@ pub fn remove(packages_option: Option<Vec<String>>, lock: bool, force: bool, qui
                                 format!("+ Removing '{}'...", package_name).bright_green()
                             );
                         }
-                        remove::command(package["name"].as_str().unwrap(), force, quiet);
+                        remove::command(package["name"].as_str().unwrap(), force, quiet)?;
                     } else if !quiet {
                         println!("{}", "+ Transaction aborted".bright_green());
                     }
-                } else {
-                    eprintln!(
-                        "{}",
-                        format!("- Package '{}' is not installed!", package_name).bright_red()
-                    );
                 }
             }
         }
@@ -120,7 +231,7 @@ pub fn remove(packages_option: Option<Vec<String>>, lock: bool, force: bool, qui
         if force
             || prompt_yn(
                 "/ Are you sure you want to remove all of your packages from the Lockfile?",
-            )
+            )?
         {
             for installed_package in installed_packages {
                 let package_name = installed_package["name"].as_str().unwrap();
@@ -132,7 +243,7 @@ pub fn remove(packages_option: Option<Vec<String>>, lock: bool, force: bool, qui
                     );
                 }
 
-                remove::remove_from_lockfile(package_name);
+                remove::remove_from_lockfile(package_name)?;
             }
             if !quiet {
                 println!(
@@ -146,20 +257,23 @@ pub fn remove(packages_option: Option<Vec<String>>, lock: bool, force: bool, qui
     } else {
         // $ aati remove --all
         if !installed_packages.is_empty() {
-            if force || prompt_yn("/ Are you sure you want to remove all of your packages?") {
-                for installed_package in installed_packages {
+            if force || prompt_yn("/ Are you sure you want to remove all of your packages?")? {
+                let lock_file: LockFile = toml::from_str(&get_aati_lock()?).unwrap();
+                let package_names: Vec<String> = lock_file
+                    .package
+                    .iter()
+                    .map(|pkg| pkg.name.clone())
+                    .collect();
+
+                for package_name in remove::order_for_removal(&package_names, &lock_file.package) {
                     if !quiet {
                         println!(
                             "{}",
-                            format!(
-                                "+ Removing '{}'...",
-                                installed_package["name"].as_str().unwrap()
-                            )
-                            .bright_green()
+                            format!("+ Removing '{}'...", package_name).bright_green()
                         );
                     }
 
-                    remove::command(installed_package["name"].as_str().unwrap(), force, quiet);
+                    remove::command(&package_name, force, quiet)?;
                 }
             } else if !quiet {
                 println!("{}", "+ Transaction aborted".bright_green());
@@ -168,29 +282,23 @@ pub fn remove(packages_option: Option<Vec<String>>, lock: bool, force: bool, qui
             eprintln!("{}", "+ No packages to remove".bright_green());
         }
     }
+
+    Ok(())
 }
 
-pub fn changelog(package_name_option: Option<&str>, latest_only: bool) {
+pub fn changelog(package_name_option: Option<&str>, latest_only: bool) -> Result<(), AatiError> {
     if let Some(package_name) = package_name_option {
-        match get_package_versions(package_name) {
-            Some(versions) => {
-                changelog::display(&versions, latest_only);
-            }
-
-            None => {
-                eprintln!(
-                    "{}",
-                    "- Package not found in the added repositories!".bright_red()
-                );
-            }
+        match get_package_versions(package_name)? {
+            Some(versions) => changelog::display(&versions, latest_only),
+            None => Err(AatiError::PackageNotFound(package_name.into())),
         }
     } else {
-        changelog::display(&get_versions(), latest_only);
+        changelog::display(&get_versions(), latest_only)
     }
 }
 
-fn is_installed(package_name: &str) -> Option<Value> {
-    let aati_lock: Value = get_aati_lock().parse().unwrap();
+fn is_installed(package_name: &str) -> Result<Option<Value>, AatiError> {
+    let aati_lock: Value = get_aati_lock()?.parse().unwrap();
     let installed_packages = aati_lock["package"].as_array().unwrap();
 
     let mut package_option = None;
@@ -201,5 +309,44 @@ fn is_installed(package_name: &str) -> Option<Value> {
         }
     }
 
-    package_option
+    Ok(package_option)
+}
+
+#[test]
+fn test_remove_required_package() {
+    use std::fs::{remove_dir_all, write};
+
+    use crate::{
+        types::Package,
+        utils::{get_aati_lock_path_buf, use_test_home},
+    };
+
+    let (_guard, home) = use_test_home("remove-required");
+
+    let lock_file = LockFile {
+        package: vec![
+            Package::test("lib"),
+            Package {
+                dependencies: vec!["lib".into()],
+                ..Package::test("app")
+            },
+        ],
+    };
+    let lock_file_str = toml::to_string(&lock_file).unwrap();
+    write(get_aati_lock_path_buf(), &lock_file_str).unwrap();
+
+    match remove(Some(vec!["lib".into()]), false, false, true, true) {
+        Err(AatiError::RequiredBy {
+            package_name,
+            dependents,
+        }) => {
+            assert_eq!(package_name, "lib");
+            assert_eq!(dependents, vec!["app".to_string()]);
+        }
+        result => panic!("expected the removal to be refused, got {:?}", result),
+    }
+
+    assert_eq!(get_aati_lock().unwrap(), lock_file_str.trim());
+
+    let _ = remove_dir_all(&home);
 }
@@ -0,0 +1,112 @@
+/* بسم الله الرحمن الرحيم
+
+   Aati - Cross-platform Package Manager written in Rust.
+   Copyright (C) 2023  Husayn Haras <haras@disroot.org>
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of version 3 of the GNU General Public License
+   as published by the Free Software Foundation.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use colored::Colorize;
+use std::{
+    env::current_dir,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::AatiError,
+    types::{LockFile, Package},
+    utils::get_aati_lock,
+};
+
+pub fn command(path: &str) -> Result<(), AatiError> {
+    let lock_file: LockFile = toml::from_str(&get_aati_lock()?).unwrap();
+
+    let mut path_buf = PathBuf::from(path);
+
+    if path_buf.is_relative() {
+        path_buf = current_dir().unwrap().join(path_buf);
+    }
+
+    let owners = find_owners(&path_buf, &lock_file.package);
+
+    if owners.is_empty() {
+        return Err(AatiError::NotOwned(path_buf));
+    }
+
+    for owner in owners {
+        println!(
+            "{}",
+            format!(
+                "+ '{}' is owned by {}/{}-{}",
+                path_buf.display(),
+                owner.source,
+                owner.name,
+                owner.version
+            )
+            .bright_green()
+        );
+    }
+
+    Ok(())
+}
+
+// Installed packages whose file manifest contains the given path
+pub fn find_owners<'a>(path: &Path, installed_packages: &'a [Package]) -> Vec<&'a Package> {
+    let path = path.canonicalize().unwrap_or(path.to_path_buf());
+
+    installed_packages
+        .iter()
+        .filter(|pkg| {
+            pkg.files.iter().any(|file| {
+                let file_path = Path::new(&file.path);
+                file_path.canonicalize().unwrap_or(file_path.to_path_buf()) == path
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_find_owners() {
+    use crate::types::InstalledFile;
+
+    fn package(name: &str, files: &[&str]) -> Package {
+        Package {
+            files: files
+                .iter()
+                .map(|path| InstalledFile {
+                    path: path.to_string(),
+                    size: 0,
+                    sha256: String::new(),
+                })
+                .collect(),
+            ..Package::test(name)
+        }
+    }
+
+    let installed_packages = vec![
+        package(
+            "foo",
+            &["/nonexistent/aati/bin/foo", "/nonexistent/aati/lib/foo.so"],
+        ),
+        package("bar", &["/nonexistent/aati/bin/bar"]),
+    ];
+
+    let owners = find_owners(
+        Path::new("/nonexistent/aati/lib/foo.so"),
+        &installed_packages,
+    );
+    assert_eq!(owners.len(), 1);
+    assert_eq!(owners[0].name, "foo");
+
+    assert!(find_owners(Path::new("/nonexistent/aati/bin/baz"), &installed_packages).is_empty());
+}
@@ -22,11 +22,12 @@ use std::{
     fs::{remove_file, File},
     io::copy,
     path::PathBuf,
-    process::exit,
 };
 use tar::Builder;
 
-pub fn command(mut directory_name: String, quiet: bool) {
+use crate::error::AatiError;
+
+pub fn command(mut directory_name: String, quiet: bool) -> Result<(), AatiError> {
     if directory_name.ends_with('/') {
         directory_name.pop();
     }
@@ -55,17 +56,11 @@ pub fn command(mut directory_name: String, quiet: bool) {
     let file = match File::create(&tar_destination) {
         Ok(file) => file,
         Err(error) => {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FAILED TO CREATE NEW FILE '{}'! ERROR[7]: {}",
-                    tar_destination.display(),
-                    error
-                )
-                .bright_red()
-            );
-
-            exit(1);
+            return Err(AatiError::CreateFile {
+                code: 7,
+                path: tar_destination,
+                error,
+            });
         }
     };
 
@@ -78,60 +73,41 @@ pub fn command(mut directory_name: String, quiet: bool) {
         Ok(_) => match builder.finish() {
             Ok(_) => {}
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO CREATE '{}' TARBALL! ERROR[102]: {}",
-                        tar_destination.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-
-                exit(1);
+                return Err(AatiError::Archive {
+                    code: 102,
+                    path: tar_destination,
+                    error,
+                });
             }
         },
         Err(error) => {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FAILED TO APPEND '{}' DIRECTORY TO THE TARBALL! ERROR[101]: {}",
-                    source.display(),
-                    error
-                )
-                .bright_red()
-            );
-
-            exit(1);
+            return Err(AatiError::Archive {
+                code: 101,
+                path: tar_destination,
+                error,
+            });
         }
     }
 
     let output_file = match File::create(&lz4_destination) {
         Ok(file) => file,
         Err(error) => {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FAILED TO CREATE FILE '{}'! ERROR[75]: {}",
-                    &lz4_destination.display(),
-                    error
-                )
-                .bright_red()
-            );
-
-            exit(1);
+            return Err(AatiError::CreateFile {
+                code: 75,
+                path: lz4_destination.to_path_buf(),
+                error,
+            });
         }
     };
 
     let mut encoder = match EncoderBuilder::new().level(16).build(output_file) {
         Ok(encoder) => encoder,
         Err(error) => {
-            eprintln!(
-                "{}",
-                format!("- UNABLE INITIALISE THE LZ4 ENCODER! ERROR[76]: {}", error).bright_red()
-            );
-
-            exit(1);
+            return Err(AatiError::Compress {
+                code: 76,
+                path: source,
+                error,
+            });
         }
     };
 
@@ -142,67 +118,44 @@ pub fn command(mut directory_name: String, quiet: bool) {
     let mut tarball = match File::open(&tar_destination) {
         Ok(file) => file,
         Err(error) => {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FAILED TO OPEN FILE '{}' FOR READING! ERROR[96]: {}",
-                    tar_destination.display(),
-                    error
-                )
-                .bright_red()
-            );
-
-            exit(1);
+            return Err(AatiError::OpenFile {
+                code: 96,
+                path: tar_destination,
+                error,
+            });
         }
     };
 
     match copy(&mut tarball, &mut encoder) {
         Ok(_) => {}
         Err(error) => {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FAILED TO WRITE DATA INTO THE LZ4 ENCODER! ERROR[77]: {}",
-                    error
-                )
-                .bright_red()
-            );
-
-            exit(1);
+            return Err(AatiError::Compress {
+                code: 77,
+                path: source,
+                error,
+            });
         }
     }
 
     match encoder.finish().1 {
         Ok(_) => {}
         Err(error) => {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FAILED TO COMPRESS FINE '{}' USING LZ4! ERROR[78]: {}",
-                    source.display(),
-                    error
-                )
-                .bright_red()
-            );
-
-            exit(1);
+            return Err(AatiError::Compress {
+                code: 78,
+                path: source,
+                error,
+            });
         }
     }
 
     match remove_file(&tar_destination) {
         Ok(_) => {}
         Err(error) => {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FAILED TO DELETE FILE {}! ERROR[54]: {}",
-                    tar_destination.display(),
-                    error
-                )
-                .as_str()
-                .bright_red()
-            );
-            exit(1);
+            return Err(AatiError::DeleteFile {
+                code: 54,
+                path: tar_destination,
+                error,
+            });
         }
     }
 
@@ -212,4 +165,6 @@ pub fn command(mut directory_name: String, quiet: bool) {
             format!("+ Done packaging! See: {}", lz4_destination.display()).bright_green()
         );
     }
+
+    Ok(())
 }
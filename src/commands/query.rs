@@ -16,19 +16,23 @@
    along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::{process::exit, str::FromStr};
+use std::{cmp::Ordering, str::FromStr};
 
 use colored::Colorize;
 use toml::Value;
 
-use crate::utils::{get_aati_config, get_aati_lock, get_repo_config, is_supported, prompt};
+use crate::error::AatiError;
+use crate::utils::{
+    compare_versions, get_aati_lock, get_latest_version, get_repo_config, is_supported,
+    parse_aati_config, prompt,
+};
 
-pub fn command(text: &str, repo_name: Option<&str>) {
+pub fn command(text: &str, repo_name: Option<&str>) -> Result<(), AatiError> {
     // Initialising main variables
-    let aati_config: Value = get_aati_config().unwrap().parse().unwrap();
+    let aati_config: Value = parse_aati_config(157)?;
     let repos = aati_config["sources"]["repos"].as_array().unwrap();
 
-    let aati_lock: Value = get_aati_lock().parse().unwrap();
+    let aati_lock: Value = get_aati_lock()?.parse().unwrap();
     let installed_packages = aati_lock["package"].as_array().unwrap();
 
     // Some placeholders too
@@ -40,7 +44,7 @@ pub fn command(text: &str, repo_name: Option<&str>) {
         let mut results: Vec<Vec<Value>> = Vec::new();
 
         if let Some(repo_name) = repo_name {
-            let repo_toml: Value = get_repo_config(repo_name).parse().unwrap();
+            let repo_toml: Value = get_repo_config(repo_name)?.parse().unwrap();
             let available_packages = repo_toml["index"]["packages"].as_array().unwrap();
 
             for available_package in available_packages {
@@ -70,7 +74,7 @@ pub fn command(text: &str, repo_name: Option<&str>) {
             for repo in repos {
                 let repo_name = repo["name"].as_str().unwrap();
 
-                let repo_toml: Value = get_repo_config(repo_name).parse().unwrap();
+                let repo_toml: Value = get_repo_config(repo_name)?.parse().unwrap();
                 let available_packages = repo_toml["index"]["packages"].as_array().unwrap();
 
                 for available_package in available_packages {
@@ -109,10 +113,13 @@ pub fn command(text: &str, repo_name: Option<&str>) {
                         installed_package_version = installed_package["version"].as_str().unwrap();
 
                         is_installed = true;
-                        if installed_package["version"].as_str().unwrap()
-                            == package["versions"].as_array().unwrap().first().unwrap()["tag"]
+                        if compare_versions(
+                            installed_package["version"].as_str().unwrap(),
+                            get_latest_version(package["versions"].as_array().unwrap()).unwrap()
+                                ["tag"]
                                 .as_str()
-                                .unwrap()
+                                .unwrap(),
+                        ) != Ordering::Less
                         {
                             is_up_to_date = true;
                         }
@@ -161,7 +168,7 @@ pub fn command(text: &str, repo_name: Option<&str>) {
                     );
                 }
 
-                let input = prompt("* Enter the number of the package you choose:");
+                let input = prompt("* Enter the number of the package you choose:")?;
 
                 match input.parse::<usize>() {
                     Ok(response) => {
@@ -183,11 +190,13 @@ pub fn command(text: &str, repo_name: Option<&str>) {
                                     && installed_package["source"].as_str().unwrap() == repo_name
                                 {
                                     is_installed = true;
-                                    if installed_package["version"].as_str().unwrap()
-                                        == package["versions"].as_array().unwrap().first().unwrap()
-                                            ["tag"]
+                                    if compare_versions(
+                                        installed_package["version"].as_str().unwrap(),
+                                        get_latest_version(package["versions"].as_array().unwrap())
+                                            .unwrap()["tag"]
                                             .as_str()
-                                            .unwrap()
+                                            .unwrap(),
+                                    ) != Ordering::Less
                                     {
                                         is_up_to_date = true;
                                         installed_package_version =
@@ -207,28 +216,24 @@ pub fn command(text: &str, repo_name: Option<&str>) {
                                 installed_package_version,
                             );
                         } else {
-                            eprintln!("{}", "- INVALID CHOICE!".bright_red());
-                            exit(1);
+                            return Err(AatiError::InvalidChoice);
                         }
                     }
 
                     Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!("- FAILED TO PARSE INPUT! ERROR[9]: {}", error).bright_red()
-                        );
-                        exit(1);
+                        return Err(AatiError::ParseInput { code: 9, error });
                     }
                 }
             }
+
+            Ok(())
         } else {
-            eprintln!("{}", "- Package not found!".bright_red());
-            exit(1);
+            Err(AatiError::PackageNotFound(text.into()))
         }
     } else {
         let (repo_name, text_to_be_extracted) = text.split_once('/').unwrap();
 
-        command(text_to_be_extracted, Some(repo_name));
+        command(text_to_be_extracted, Some(repo_name))
     }
 }
 
@@ -242,7 +247,9 @@ pub fn display_package(
 ) {
     let name = package["name"].as_str().unwrap();
     let versions = package["versions"].as_array().unwrap();
-    let version = versions.first().unwrap()["tag"].as_str().unwrap();
+    let version = get_latest_version(versions).unwrap()["tag"]
+        .as_str()
+        .unwrap();
 
     let mut tags: Vec<&str> = vec![];
     for version in versions {
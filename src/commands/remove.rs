@@ -18,33 +18,29 @@
 
 use colored::Colorize;
 use std::{
-    fs::{read_to_string, OpenOptions},
-    io::Write,
-    process::exit,
+    fs::{read_to_string, remove_file},
+    path::Path,
 };
 
 use crate::{
-    types::LockFile,
-    utils::{execute_lines, get_aati_lock_path_buf, prompt_yn},
+    error::AatiError,
+    types::{LockFile, Package},
+    utils::{
+        execute_lines, get_aati_lock_path_buf, get_script_policy, prompt_yn, write_atomically,
+    },
 };
 
-pub fn command(package_name: &str, force: bool, quiet: bool) {
+pub fn command(package_name: &str, force: bool, quiet: bool) -> Result<(), AatiError> {
     let aati_lock_path_buf = get_aati_lock_path_buf();
 
     let lock_file_str = match read_to_string(&aati_lock_path_buf) {
         Ok(contents) => contents,
         Err(error) => {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FAILED TO READ LOCKFILE AT '{}'! ERROR[45]: {}",
-                    &aati_lock_path_buf.display(),
-                    error
-                )
-                .bright_red()
-            );
-
-            exit(1);
+            return Err(AatiError::ReadFile {
+                code: 45,
+                path: aati_lock_path_buf,
+                error,
+            });
         }
     };
     let lock_file: LockFile = toml::from_str(&lock_file_str).unwrap();
@@ -59,28 +55,22 @@ pub fn command(package_name: &str, force: bool, quiet: bool) {
         .find(|pkg| pkg.name == package_name)
     {
         Some(found_package) => found_package,
-        None => {
-            eprintln!(
-                "{}",
-                format!("- Package '{}' not found in the Lockfile!", package_name).bright_red()
-            );
-
-            exit(1);
-        }
+        None => return Err(AatiError::NotInstalled(package_name.into())),
     };
 
     if force
         || prompt_yn(&format!(
             "+ Commands to be ran:\n  {}\n/ Do these commands seem safe to execute?",
             found_package.pkgfile.removal_lines.join("\n  ")
-        ))
+        ))?
     {
-        execute_lines(
+        let (_, transaction) = execute_lines(
             &found_package.pkgfile.removal_lines,
             &found_package.pkgfile.data,
             None,
+            &get_script_policy(&found_package.source)?,
             quiet,
-        );
+        )?;
 
         if !quiet {
             println!(
@@ -89,7 +79,17 @@ pub fn command(package_name: &str, force: bool, quiet: bool) {
             );
         }
 
-        remove_from_lockfile(package_name);
+        // What the removal lines deleted comes back if the package can't be
+        // taken out of the Lockfile
+        if let Err(error) = remove_from_lockfile(package_name) {
+            transaction.rollback(quiet);
+
+            return Err(error);
+        }
+
+        transaction.commit();
+
+        remove_leftover_files(found_package, &lock_file.package, quiet);
 
         if !quiet {
             println!("{}", "+ Removal finished successfully!".bright_green());
@@ -97,66 +97,165 @@ pub fn command(package_name: &str, force: bool, quiet: bool) {
     } else if !quiet {
         println!("{}", "+ Transaction aborted".bright_green());
     }
+
+    Ok(())
 }
 
-pub fn remove_from_lockfile(package_name: &str) {
+// Delete the files the package installed that its [removal] section left
+// behind, unless another installed package owns them as well
+fn remove_leftover_files(package: &Package, installed_packages: &[Package], quiet: bool) {
+    for file in &package.files {
+        let path = Path::new(&file.path);
+
+        if !path.exists() {
+            continue;
+        }
+
+        let is_shared = installed_packages
+            .iter()
+            .filter(|pkg| pkg.name != package.name)
+            .any(|pkg| pkg.files.iter().any(|other| other.path == file.path));
+
+        if is_shared {
+            continue;
+        }
+
+        match remove_file(path) {
+            Ok(_) => {
+                if !quiet {
+                    println!(
+                        "{}",
+                        format!("+ Deleted leftover file '{}'", file.path).bright_green()
+                    );
+                }
+            }
+            Err(error) => {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "- FAILED TO DELETE FILE '{}'! ERROR[110]: {}",
+                        file.path, error
+                    )
+                    .bright_red()
+                );
+            }
+        }
+    }
+}
+
+pub fn remove_from_lockfile(package_name: &str) -> Result<(), AatiError> {
     let aati_lock_path_buf = get_aati_lock_path_buf();
     let lock_file_str = match read_to_string(&aati_lock_path_buf) {
         Ok(contents) => contents,
         Err(error) => {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FAILED TO READ LOCKFILE AT '{}'! ERROR[57]: {}",
-                    &aati_lock_path_buf.display(),
-                    error
-                )
-                .bright_red()
-            );
-
-            exit(1);
+            return Err(AatiError::ReadFile {
+                code: 57,
+                path: aati_lock_path_buf,
+                error,
+            });
         }
     };
     let mut lock_file: LockFile = toml::from_str(&lock_file_str).unwrap();
 
     lock_file.package.retain(|pkg| pkg.name != package_name);
 
-    let mut file = match OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(&aati_lock_path_buf)
-    {
-        Ok(file) => file,
-        Err(error) => {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FAILED TO OPEN LOCKFILE AT '{}' FOR WRITING! ERROR[46]: {}",
-                    &aati_lock_path_buf.display(),
-                    error
-                )
-                .bright_red()
-            );
+    let toml_str = toml::to_string_pretty(&lock_file).unwrap();
+    match write_atomically(&aati_lock_path_buf, &toml_str) {
+        Ok(_) => Ok(()),
+        Err(error) => Err(AatiError::WriteFile {
+            code: 47,
+            path: aati_lock_path_buf,
+            error,
+        }),
+    }
+}
 
-            exit(1);
+// Every installed package that depends on the given one, directly or through
+// other installed packages
+pub fn get_dependents(package_name: &str, installed_packages: &[Package]) -> Vec<String> {
+    let mut dependents: Vec<String> = Vec::new();
+    let mut to_be_checked: Vec<&str> = vec![package_name];
+
+    while let Some(checked_package) = to_be_checked.pop() {
+        for installed_package in installed_packages {
+            if installed_package
+                .dependencies
+                .iter()
+                .any(|dependency| dependency == checked_package)
+                && installed_package.name != package_name
+                && !dependents.contains(&installed_package.name)
+            {
+                dependents.push(installed_package.name.clone());
+                to_be_checked.push(&installed_package.name);
+            }
         }
-    };
+    }
 
-    let toml_str = toml::to_string_pretty(&lock_file).unwrap();
-    match file.write_all(toml_str.as_bytes()) {
-        Ok(_) => {}
-        Err(error) => {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FAILED TO WRITE INTO LOCKFILE AT'{}'! ERROR[47]: {}",
-                    &aati_lock_path_buf.display(),
-                    error
-                )
-                .bright_red()
-            );
+    dependents
+}
 
-            exit(1);
-        }
+// Sort packages so that each one is removed before the packages it depends on
+pub fn order_for_removal(package_names: &[String], installed_packages: &[Package]) -> Vec<String> {
+    let mut remaining: Vec<String> = package_names.to_vec();
+    let mut ordered: Vec<String> = Vec::new();
+
+    while !remaining.is_empty() {
+        let next_index = remaining
+            .iter()
+            .position(|package_name| {
+                !installed_packages.iter().any(|pkg| {
+                    remaining.contains(&pkg.name)
+                        && pkg.name != *package_name
+                        && pkg.dependencies.contains(package_name)
+                })
+            })
+            // Only reachable with circular dependencies, just go in the given order
+            .unwrap_or(0);
+
+        ordered.push(remaining.remove(next_index));
     }
+
+    ordered
+}
+
+#[test]
+fn test_get_dependents() {
+    let package = |name: &str, dependencies: &[&str]| Package {
+        dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+        ..Package::test(name)
+    };
+
+    let installed_packages = vec![
+        package("libbase", &[]),
+        package("libfoo", &["libbase"]),
+        package("app", &["libfoo"]),
+        package("tool", &[]),
+    ];
+
+    assert_eq!(
+        get_dependents("libbase", &installed_packages),
+        vec!["libfoo".to_string(), "app".into()]
+    );
+    assert_eq!(
+        get_dependents("app", &installed_packages),
+        Vec::<String>::new()
+    );
+
+    assert_eq!(
+        order_for_removal(
+            &[
+                "libbase".into(),
+                "tool".into(),
+                "libfoo".into(),
+                "app".into()
+            ],
+            &installed_packages
+        ),
+        vec![
+            "tool".to_string(),
+            "app".into(),
+            "libfoo".into(),
+            "libbase".into()
+        ]
+    );
 }
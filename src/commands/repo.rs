@@ -18,25 +18,31 @@
 
 use colored::Colorize;
 use std::{
-    fs::{create_dir_all, read_to_string, remove_file, File, OpenOptions},
+    fs::{create_dir_all, read_to_string, remove_file, File},
     io::Write,
     path::PathBuf,
-    process::exit,
 };
 use toml::Value;
 
+use super::remove::get_dependents;
 use crate::{
     commands,
     config::HOMEPAGE_URL,
-    types::{ConfigFile, Repo},
+    error::AatiError,
+    types::{ConfigFile, LockFile, Repo},
     utils::{
-        check_aati_dirs, get_aati_config, get_aati_config_path_buf, get_aati_lock, get_repo_config,
-        get_repo_config_path_buf, prompt_yn,
+        check_aati_dirs, confirm, get_aati_config, get_aati_config_path_buf, get_aati_lock,
+        get_key_fingerprint, get_repo_config, get_repo_config_path_buf, is_offline,
+        parse_aati_config, parse_public_key, prompt_yn, write_atomically,
     },
 };
 
-pub fn add(repository_url: String, quiet: bool) {
-    let aati_config: Value = get_aati_config().unwrap().parse().unwrap();
+pub fn add(repository_url: String, key: Option<&str>, quiet: bool) -> Result<(), AatiError> {
+    if is_offline()? {
+        return Err(AatiError::Offline("add repositories"));
+    }
+
+    let aati_config: Value = parse_aati_config(158)?;
     let added_repos = aati_config["sources"]["repos"].as_array().unwrap();
 
     let mut already_added_repo: Option<&Value> = None;
@@ -55,187 +61,178 @@ pub fn add(repository_url: String, quiet: bool) {
             );
         }
 
-        let requested_url = format!("{}/repo.toml", repository_url);
+        let repo_toml = commands::sync::request_manifest(&repository_url, 6, quiet)?;
 
-        if !quiet {
-            println!(
-                "{}",
-                format!("+ Requesting ({})", requested_url).bright_green()
-            );
-        }
-        match ureq::get(requested_url.as_str()).call() {
-            Ok(mut repo_toml) => {
-                let repo_toml = repo_toml.body_mut().read_to_string().unwrap();
+        let repo_value: Value = repo_toml.parse().unwrap();
 
-                let repo_value: Value = repo_toml.parse().unwrap();
+        let repo_name = repo_value["repo"]["name"].as_str().unwrap();
 
-                let repo_name = repo_value["repo"]["name"].as_str().unwrap();
+        for added_repo in added_repos {
+            if added_repo["name"].as_str().unwrap() == repo_name {
+                already_added_repo = Some(added_repo);
+            }
+        }
 
-                for added_repo in added_repos {
-                    if added_repo["name"].as_str().unwrap() == repo_name {
-                        already_added_repo = Some(added_repo);
-                    }
+        if already_added_repo.is_none() {
+            // A key given with --key is trusted as is, one that the repository
+            // advertises itself is only trusted once the user confirms it
+            let key = match key {
+                Some(key) => {
+                    commands::sync::verify_manifest(
+                        &repository_url,
+                        &repo_toml,
+                        &parse_public_key(key)?,
+                        quiet,
+                    )?;
+
+                    Some(key.trim().to_lowercase())
                 }
 
-                if already_added_repo.is_none() {
-                    check_aati_dirs();
-
-                    let repo_config_path_buf = get_repo_config_path_buf(repo_name);
-
-                    let mut repo_config = match File::create(&repo_config_path_buf) {
-                        Ok(file) => file,
-                        Err(error) => {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                    "- FAILED TO CREATE FILE '{}'! ERROR[68]: {}",
-                                    &repo_config_path_buf.display(),
-                                    error
-                                )
-                                .bright_red()
-                            );
-
-                            exit(1);
+                None => match commands::sync::get_advertised_key(&repo_value) {
+                    Some(advertised_key) => {
+                        let public_key = parse_public_key(advertised_key)?;
+
+                        commands::sync::verify_manifest(
+                            &repository_url,
+                            &repo_toml,
+                            &public_key,
+                            quiet,
+                        )?;
+
+                        // Nothing is trusted without an answer, e.g. when piped
+                        let is_trusted = match confirm(&format!(
+                            "+ The repository is signed with the key: {}\n/ Do you want to trust it?",
+                            get_key_fingerprint(&public_key)
+                        )) {
+                            Ok(is_trusted) => is_trusted,
+                            Err(error) => return Err(AatiError::Input { code: 143, error }),
+                        };
+
+                        if !is_trusted {
+                            if !quiet {
+                                println!("{}", "+ Transaction aborted".bright_green());
+                                println!(
+                                    "{}",
+                                    format!(
+                                        "+ Trust the key without being asked using: $ aati repo add --key {} {}",
+                                        advertised_key.trim(),
+                                        repository_url
+                                    )
+                                    .bright_blue()
+                                );
+                            }
+
+                            return Ok(());
                         }
-                    };
-
-                    if !quiet {
-                        println!(
-                            "{}",
-                            format!(
-                                "+ Writing Repo Config to {}",
-                                &repo_config_path_buf.display()
-                            )
-                            .bright_green()
-                        );
-                    }
 
-                    match writeln!(repo_config, "{}", repo_toml) {
-                        Ok(_) => {}
-                        Err(error) => {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                    "- FAILED TO WRITE INTO REPO CONFIG AT '{}'! ERROR[69]: {}",
-                                    repo_config_path_buf.display(),
-                                    error
-                                )
-                                .bright_red()
-                            );
-
-                            exit(1);
-                        }
+                        Some(advertised_key.trim().to_lowercase())
                     }
 
-                    // Putting it in rc.toml
+                    None => None,
+                },
+            };
 
-                    if !quiet {
-                        println!("{}", "+ Adding URL to the Config File...".bright_green());
-                    }
+            check_aati_dirs()?;
 
-                    let config_file_str = get_aati_config().unwrap();
+            let repo_config_path_buf = get_repo_config_path_buf(repo_name);
 
-                    let mut config_file: ConfigFile = toml::from_str(&config_file_str).unwrap();
+            if !quiet {
+                println!(
+                    "{}",
+                    format!(
+                        "+ Writing Repo Config to {}",
+                        &repo_config_path_buf.display()
+                    )
+                    .bright_green()
+                );
+            }
 
-                    let repo = Repo {
-                        name: repo_name.into(),
-                        url: repository_url,
-                    };
+            match write_atomically(&repo_config_path_buf, &format!("{}\n", repo_toml)) {
+                Ok(_) => {}
+                Err(error) => {
+                    return Err(AatiError::WriteFile {
+                        code: 69,
+                        path: repo_config_path_buf,
+                        error,
+                    });
+                }
+            }
 
-                    config_file.sources.repos.push(repo);
+            // Putting it in rc.toml
 
-                    let aati_config_path_buf = get_aati_config_path_buf();
+            if !quiet {
+                println!("{}", "+ Adding URL to the Config File...".bright_green());
+            }
 
-                    let mut file = match OpenOptions::new()
-                        .write(true)
-                        .truncate(true)
-                        .open(&aati_config_path_buf)
-                    {
-                        Ok(file) => file,
-                        Err(error) => {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                "- FAILED TO OPEN CONFIG FILE AT '{}' FOR WRITING! ERROR[70]: {}",
-                                &aati_config_path_buf.display(),
-                                error
-                            )
-                                .bright_red()
-                            );
+            let config_file_str = get_aati_config()?;
 
-                            exit(1);
-                        }
-                    };
-
-                    let toml_str = toml::to_string(&config_file).unwrap();
-                    match file.write_all(toml_str.as_bytes()) {
-                        Ok(_) => {}
-                        Err(error) => {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                    "- FAILED TO WRITE INTO CONFIG FILE AT '{}'! ERROR[71]: {}",
-                                    aati_config_path_buf.display(),
-                                    error
-                                )
-                                .bright_red()
-                            );
-
-                            exit(1);
-                        }
-                    }
-
-                    if !quiet {
-                        println!(
-                            "{}",
-                            format!("+ Repository '{}' added successfully!", repo_name)
-                                .bright_green()
-                        );
-                    }
-                } else {
-                    eprintln!(
-                        "{}",
-                        format!(
-                            "- Repository '{} ({})' is already added!",
-                            already_added_repo.unwrap()["name"].as_str().unwrap(),
-                            already_added_repo.unwrap()["url"].as_str().unwrap()
-                        )
-                        .bright_red()
-                    );
+            let mut config_file: ConfigFile = match toml::from_str(&config_file_str) {
+                Ok(config_file) => config_file,
+                Err(error) => {
+                    return Err(AatiError::ParseToml {
+                        code: 140,
+                        path: get_aati_config_path_buf(),
+                        error: Box::new(error),
+                    });
+                }
+            };
+
+            let repo = Repo {
+                name: repo_name.into(),
+                url: repository_url,
+                key,
+                key_rotated: None,
+                system_commands: None,
+            };
+
+            config_file.sources.repos.push(repo);
+
+            let aati_config_path_buf = get_aati_config_path_buf();
+
+            let toml_str = toml::to_string(&config_file).unwrap();
+            match write_atomically(&aati_config_path_buf, &toml_str) {
+                Ok(_) => {}
+                Err(error) => {
+                    return Err(AatiError::WriteFile {
+                        code: 71,
+                        path: aati_config_path_buf,
+                        error,
+                    });
                 }
             }
 
-            Err(error) => {
-                eprintln!(
+            if !quiet {
+                println!(
                     "{}",
-                    format!(
-                        "- FAILED TO REQUEST ({})! ERROR[6]: {}",
-                        requested_url, error
-                    )
-                    .bright_red()
+                    format!("+ Repository '{}' added successfully!", repo_name).bright_green()
                 );
-                exit(1);
             }
+        } else if let Some(already_added_repo) = already_added_repo {
+            return Err(AatiError::RepoAlreadyAdded {
+                name: already_added_repo["name"].as_str().unwrap().into(),
+                url: already_added_repo["url"].as_str().unwrap().into(),
+            });
         }
-    } else {
-        eprintln!(
-            "{}",
-            format!(
-                "- Repository '{} ({})' is already added!",
-                already_added_repo.unwrap()["name"].as_str().unwrap(),
-                already_added_repo.unwrap()["url"].as_str().unwrap()
-            )
-            .bright_red()
-        );
+    } else if let Some(already_added_repo) = already_added_repo {
+        return Err(AatiError::RepoAlreadyAdded {
+            name: already_added_repo["name"].as_str().unwrap().into(),
+            url: already_added_repo["url"].as_str().unwrap().into(),
+        });
     }
+
+    Ok(())
 }
 
-pub fn remove(repo_name_option: Option<String>, force: bool, quiet: bool) {
-    let aati_lock: Value = get_aati_lock().parse().unwrap();
-    let installed_packages = aati_lock["package"].as_array().unwrap();
+pub fn remove(
+    repo_name_option: Option<String>,
+    cascade: bool,
+    force: bool,
+    quiet: bool,
+) -> Result<(), AatiError> {
+    let lock_file: LockFile = toml::from_str(&get_aati_lock()?).unwrap();
 
     let aati_config_path_buf = get_aati_config_path_buf();
-    let aati_config: Value = get_aati_config().unwrap().parse().unwrap();
+    let aati_config: Value = parse_aati_config(159)?;
     let added_repos = aati_config["sources"]["repos"].as_array().unwrap();
 
     if let Some(repo_name) = repo_name_option {
@@ -251,19 +248,47 @@ pub fn remove(repo_name_option: Option<String>, force: bool, quiet: bool) {
         }
 
         if is_added {
-            if force
-                || prompt_yn(
-                    format!(
+            let package_names: Vec<String> = lock_file
+                .package
+                .iter()
+                .filter(|pkg| pkg.source == repo_name)
+                .map(|pkg| pkg.name.clone())
+                .collect();
+
+            // Packages from other repositories that need the ones installed
+            // from this one would be left broken
+            let mut dependents: Vec<String> = Vec::new();
+
+            for package_name in &package_names {
+                for dependent in get_dependents(package_name, &lock_file.package) {
+                    if !package_names.contains(&dependent) && !dependents.contains(&dependent) {
+                        dependents.push(dependent);
+                    }
+                }
+            }
+
+            if !dependents.is_empty() && !cascade {
+                return Err(AatiError::RepoRequiredBy {
+                    repo_name,
+                    dependents,
+                });
+            }
+
+            let prompt_text = if dependents.is_empty() {
+                format!(
                     "Are you sure you want to remove '{}' from your added package repositories?",
                     repo_name
                 )
-                    .as_str(),
+            } else {
+                format!(
+                    "Are you sure you want to remove '{}' from your added package repositories, along with {} from other repositories?",
+                    repo_name,
+                    dependents.join(", ")
                 )
-            {
-                if installed_packages
-                    .iter()
-                    .any(|pkg| pkg["source"].as_str().unwrap() == repo_name)
-                {
+            };
+
+            if force || prompt_yn(&prompt_text)? {
+                if !package_names.is_empty() {
                     if !quiet {
                         println!(
                             "{}",
@@ -275,16 +300,8 @@ pub fn remove(repo_name_option: Option<String>, force: bool, quiet: bool) {
                         );
                     }
 
-                    for installed_package in installed_packages {
-                        if installed_package["source"].as_str().unwrap() == repo_name {
-                            commands::remove(
-                                Some(vec![installed_package["name"].as_str().unwrap().into()]),
-                                false,
-                                force,
-                                quiet,
-                            );
-                        }
-                    }
+                    // The user agreed to all of it already
+                    commands::remove(Some(package_names), false, true, true, quiet)?;
                 }
 
                 if !quiet {
@@ -298,62 +315,38 @@ pub fn remove(repo_name_option: Option<String>, force: bool, quiet: bool) {
                 let config_file_str = match read_to_string(&aati_config_path_buf) {
                     Ok(contents) => contents,
                     Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "- FAILED TO READ CONFIG FILE AT '{}'! ERROR[72]: {}",
-                                &aati_config_path_buf.display(),
-                                error
-                            )
-                            .bright_red()
-                        );
-
-                        exit(1);
+                        return Err(AatiError::ReadFile {
+                            code: 72,
+                            path: aati_config_path_buf,
+                            error,
+                        });
+                    }
+                };
+                let mut config_file: ConfigFile = match toml::from_str(&config_file_str) {
+                    Ok(config_file) => config_file,
+                    Err(error) => {
+                        return Err(AatiError::ParseToml {
+                            code: 141,
+                            path: aati_config_path_buf,
+                            error: Box::new(error),
+                        });
                     }
                 };
-                let mut config_file: ConfigFile = toml::from_str(&config_file_str).unwrap();
 
                 config_file.sources.repos.retain(|r| {
                     r.name != repo["name"].as_str().unwrap()
                         && r.url != repo["url"].as_str().unwrap()
                 });
 
-                let mut file = match OpenOptions::new()
-                    .write(true)
-                    .truncate(true)
-                    .open(&aati_config_path_buf)
-                {
-                    Ok(file) => file,
-                    Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "- FAILED TO OPEN CONFIG FILE AT '{}' FOR WRITING! ERROR[73]: {}",
-                                &aati_config_path_buf.display(),
-                                error
-                            )
-                            .bright_red()
-                        );
-
-                        exit(1);
-                    }
-                };
-
                 let toml_str = toml::to_string_pretty(&config_file).unwrap();
-                match file.write_all(toml_str.as_bytes()) {
+                match write_atomically(&aati_config_path_buf, &toml_str) {
                     Ok(_) => {}
                     Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "- FAILED TO WRITE INTO CONFIG FILE AT '{}'! ERROR[74]: {}",
-                                aati_config_path_buf.display(),
-                                error
-                            )
-                            .bright_red()
-                        );
-
-                        exit(1);
+                        return Err(AatiError::WriteFile {
+                            code: 74,
+                            path: aati_config_path_buf,
+                            error,
+                        });
                     }
                 }
 
@@ -369,17 +362,11 @@ pub fn remove(repo_name_option: Option<String>, force: bool, quiet: bool) {
                 match remove_file(&repo_path_buf) {
                     Ok(_) => {}
                     Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "- FAILED TO DELETE FILE '{}'! ERROR[79]: {}",
-                                repo_path_buf.display(),
-                                error
-                            )
-                            .bright_red()
-                        );
-
-                        exit(1);
+                        return Err(AatiError::DeleteFile {
+                            code: 79,
+                            path: repo_path_buf,
+                            error,
+                        });
                     }
                 }
 
@@ -397,51 +384,63 @@ pub fn remove(repo_name_option: Option<String>, force: bool, quiet: bool) {
                 println!("{}", "+ Transaction aborted".bright_green());
             }
         } else {
-            eprintln!(
-                "{}",
-                format!(
-                    "- Repository '{}' is not added to the Config file!",
-                    repo_name
-                )
-                .bright_red()
-            );
+            return Err(AatiError::RepoNotAdded(repo_name));
         }
     } else {
+        // Every package goes either way, so there's nothing to be left broken
         for repo in added_repos {
-            remove(Some(repo["name"].as_str().unwrap().into()), force, quiet)
+            remove(
+                Some(repo["name"].as_str().unwrap().into()),
+                true,
+                force,
+                quiet,
+            )?;
         }
     }
+
+    Ok(())
 }
 
-pub fn info(repo_name: String) {
-    let aati_config = get_aati_config().unwrap();
-    let aati_toml: Value = aati_config.parse().unwrap();
+pub fn info(repo_name: String) -> Result<(), AatiError> {
+    let aati_toml: Value = parse_aati_config(163)?;
 
     let repos = aati_toml["sources"]["repos"].as_array().unwrap();
 
-    let repo_config = get_repo_config(&repo_name);
+    let repo_config = get_repo_config(&repo_name)?;
     let repo_toml: Value = repo_config.parse().unwrap();
 
-    let url = repos
+    let repo = repos
         .iter()
         .find(|r| r["name"].as_str().unwrap() == repo_name)
-        .unwrap()["url"]
-        .as_str()
         .unwrap();
 
+    let url = repo["url"].as_str().unwrap();
+
+    let key = match repo.get("key").and_then(|key| key.as_str()) {
+        Some(key) => get_key_fingerprint(&parse_public_key(key)?),
+        None => "None (unsigned)".into(),
+    };
+
+    let key_rotated = repo
+        .get("key_rotated")
+        .and_then(|key_rotated| key_rotated.as_str())
+        .unwrap_or("Never");
+
     let maintainer = repo_toml["repo"]["maintainer"].as_str().unwrap();
     let description = repo_toml["repo"]["description"].as_str().unwrap();
     let packages_number = repo_toml["index"]["packages"].as_array().unwrap().len();
 
     println!(
-        "{}\n    Name: {}\n    URL: {}\n    Maintainer: {}\n    Number of Packages: {}\n    Description:\n      {}",
+        "{}\n    Name: {}\n    URL: {}\n    Maintainer: {}\n    Number of Packages: {}\n    Key: {}\n    Key Last Rotated: {}\n    Description:\n      {}",
         "+ Repository Information:".bright_green(),
-        repo_name, url, maintainer, packages_number, description
+        repo_name, url, maintainer, packages_number, key, key_rotated, description
     );
+
+    Ok(())
 }
 
-pub fn list() {
-    let aati_config: Value = get_aati_config().unwrap().parse().unwrap();
+pub fn list() -> Result<(), AatiError> {
+    let aati_config: Value = parse_aati_config(160)?;
     let repos = aati_config["sources"]["repos"].as_array().unwrap();
 
     if !repos.is_empty() {
@@ -457,6 +456,8 @@ pub fn list() {
     } else {
         eprintln!("{}", "+ You have no repos set!".yellow());
     }
+
+    Ok(())
 }
 
 pub fn init(
@@ -465,7 +466,7 @@ pub fn init(
     repo_description: String,
     parent_directory: PathBuf,
     quiet: bool,
-) {
+) -> Result<(), AatiError> {
     if parent_directory.exists() {
         let mut repo_dir = parent_directory.clone();
         repo_dir.push("aati_repo");
@@ -485,33 +486,22 @@ pub fn init(
         match create_dir_all(&repo_dir) {
             Ok(_) => {}
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO CREATE DIRECTORY '{}'! ERROR[49]: {}",
-                        &repo_dir.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-                exit(1);
+                return Err(AatiError::CreateDirectory {
+                    code: 49,
+                    path: repo_dir,
+                    error,
+                });
             }
         }
 
         let mut repo_toml = match File::create(&repo_toml_path_buf) {
             Ok(file) => file,
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO CREATE FILE '{}'! ERROR[50]: {}",
-                        &repo_toml_path_buf.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-
-                exit(1);
+                return Err(AatiError::CreateFile {
+                    code: 50,
+                    path: repo_toml_path_buf,
+                    error,
+                });
             }
         };
 
@@ -525,17 +515,11 @@ pub fn init(
                 }
             }
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO CREATE DIRECTORY '{}'! ERROR[52]: {}",
-                        &any_dir.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-
-                exit(1);
+                return Err(AatiError::CreateDirectory {
+                    code: 52,
+                    path: any_dir,
+                    error,
+                });
             }
         }
 
@@ -550,17 +534,11 @@ pub fn init(
                 }
             }
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO CREATE DIRECTORY '{}'! ERROR[51]: {}",
-                        &x86_64_linux_dir.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-
-                exit(1);
+                return Err(AatiError::CreateDirectory {
+                    code: 51,
+                    path: x86_64_linux_dir.to_path_buf(),
+                    error,
+                });
             }
         }
 
@@ -574,17 +552,11 @@ pub fn init(
                 }
             }
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO CREATE DIRECTORY '{}'! ERROR[53]: {}",
-                        &aarch64_dir.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-
-                exit(1);
+                return Err(AatiError::CreateDirectory {
+                    code: 53,
+                    path: aarch64_dir.to_path_buf(),
+                    error,
+                });
             }
         }
 
@@ -619,17 +591,11 @@ pub fn init(
                 }
             }
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO WRITE INTO FILE '{}'! ERROR[67]: {}",
-                        repo_toml_path_buf.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-
-                exit(1);
+                return Err(AatiError::WriteFile {
+                    code: 67,
+                    path: repo_toml_path_buf,
+                    error,
+                });
             }
         }
 
@@ -640,13 +606,8 @@ pub fn init(
             );
         }
     } else {
-        eprintln!(
-            "{}",
-            format!(
-                "+ Directory '{}' doesn't exist!",
-                parent_directory.display()
-            )
-            .bright_red()
-        );
+        return Err(AatiError::DirectoryNotFound(parent_directory));
     }
+
+    Ok(())
 }
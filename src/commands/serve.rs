@@ -18,18 +18,34 @@
 
 use ascii::AsciiString;
 use colored::Colorize;
-use std::{fs::read_to_string, path::PathBuf, process::exit};
+use std::{
+    fs::{read_to_string, File},
+    io::{Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
 use toml::Value;
 
-use tiny_http::{Header, Response, Server};
+use tiny_http::{Header, Request, Response, Server, StatusCode};
 
-use crate::{commands::generate::generate_apr_html, config::POSSIBLE_TARGETS};
+use crate::{commands::generate::generate_apr_html, config::POSSIBLE_TARGETS, error::AatiError};
 
-pub fn command(host: &str, port: &str, repo_url: &str, manifest_path: PathBuf) {
+pub fn command(
+    host: &str,
+    port: &str,
+    repo_url: &str,
+    manifest_path: PathBuf,
+) -> Result<(), AatiError> {
     let address = format!("{host}:{port}");
 
+    // Tarballs are looked up next to the manifest, like they're laid out
+    // in a repository
+    let repo_directory = match manifest_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
     match Server::http(address) {
-        Ok(server) => match read_to_string(manifest_path) {
+        Ok(server) => match read_to_string(&manifest_path) {
             Ok(repo_toml) => match repo_toml.parse::<Value>() {
                 Ok(repo_config) => {
                     let packages = repo_config["index"]["packages"].as_array().unwrap();
@@ -56,6 +72,18 @@ pub fn command(host: &str, port: &str, repo_url: &str, manifest_path: PathBuf) {
 
                         url.remove(0);
 
+                        if let Some(file_path) =
+                            get_served_file(&repo_directory, &manifest_path, &url)
+                        {
+                            // A client that went away mid-transfer is no
+                            // reason to stop serving the others
+                            if let Err(error) = respond_with_file(request, &file_path) {
+                                eprintln!("{}", format!("- {}", error).bright_red());
+                            }
+
+                            continue;
+                        }
+
                         if url.is_empty() || url == "index.html" {
                             html = generate_apr_html(&repo_config, "index", None, repo_url);
                         } else if url == "about.html" {
@@ -107,32 +135,205 @@ pub fn command(host: &str, port: &str, repo_url: &str, manifest_path: PathBuf) {
                             value: AsciiString::from_ascii("text/html; charset=utf8").unwrap(),
                         });
 
-                        match request.respond(response) {
-                            Ok(_) => {}
-
-                            Err(error) => {
-                                eprintln!("{}", format!("ERROR[18]: {}", error).bright_red());
-                                exit(1);
-                            }
+                        if let Err(error) = request.respond(response) {
+                            eprintln!(
+                                "{}",
+                                format!(
+                                    "- {}",
+                                    AatiError::Serve {
+                                        code: 18,
+                                        error: error.to_string(),
+                                    }
+                                )
+                                .bright_red()
+                            );
                         }
                     }
-                }
 
-                Err(error) => {
-                    eprintln!("{}", format!("ERROR[17]: {}", error).bright_red());
-                    exit(1);
+                    Ok(())
                 }
+
+                Err(error) => Err(AatiError::ParseToml {
+                    code: 17,
+                    path: manifest_path,
+                    error: Box::new(error),
+                }),
             },
 
-            Err(error) => {
-                eprintln!("{}", format!("ERROR[16]: {}", error).bright_red());
-                exit(1);
-            }
+            Err(error) => Err(AatiError::ReadFile {
+                code: 16,
+                path: manifest_path,
+                error,
+            }),
         },
 
+        Err(error) => Err(AatiError::Serve {
+            code: 15,
+            error: error.to_string(),
+        }),
+    }
+}
+
+// The file behind a request for the manifest or a package tarball, if the
+// repository has it
+fn get_served_file(repo_directory: &Path, manifest_path: &Path, url: &str) -> Option<PathBuf> {
+    if url == "repo.toml" {
+        return Some(manifest_path.to_path_buf());
+    }
+
+    // The manifest's detached signature lives right next to it
+    if url == "repo.toml.sig" {
+        let mut signature_path = manifest_path.as_os_str().to_owned();
+        signature_path.push(".sig");
+
+        let signature_path = PathBuf::from(signature_path);
+
+        return if signature_path.is_file() {
+            Some(signature_path)
+        } else {
+            None
+        };
+    }
+
+    let relative_path = Path::new(url);
+
+    if !url.ends_with(".tar.lz4")
+        || !relative_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    let file_path = repo_directory.join(relative_path);
+
+    if file_path.is_file() {
+        Some(file_path)
+    } else {
+        None
+    }
+}
+
+// Send a file, or the part of it asked for with a Range header so that
+// interrupted downloads can be resumed
+fn respond_with_file(request: Request, file_path: &Path) -> Result<(), AatiError> {
+    let mut file = match File::open(file_path) {
+        Ok(file) => file,
+        Err(error) => {
+            return Err(AatiError::OpenFile {
+                code: 123,
+                path: file_path.to_path_buf(),
+                error,
+            });
+        }
+    };
+
+    let length = match file.metadata() {
+        Ok(metadata) => metadata.len(),
         Err(error) => {
-            eprintln!("{}", format!("ERROR[15]: {}", error).bright_red());
-            exit(1);
+            return Err(AatiError::ReadFile {
+                code: 124,
+                path: file_path.to_path_buf(),
+                error,
+            });
         }
+    };
+
+    let range = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Range"))
+        .and_then(|header| parse_range(header.value.as_str(), length));
+
+    let accept_ranges = Header::from_bytes("Accept-Ranges", "bytes").unwrap();
+
+    let result = match range {
+        None => request.respond(Response::from_file(file).with_header(accept_ranges)),
+
+        Some((first, _)) if first >= length => {
+            request.respond(Response::empty(StatusCode(416)).with_header(
+                Header::from_bytes("Content-Range", format!("bytes */{}", length)).unwrap(),
+            ))
+        }
+
+        Some((first, last)) => {
+            if let Err(error) = file.seek(SeekFrom::Start(first)) {
+                return Err(AatiError::ReadFile {
+                    code: 125,
+                    path: file_path.to_path_buf(),
+                    error,
+                });
+            }
+
+            let content_range = Header::from_bytes(
+                "Content-Range",
+                format!("bytes {}-{}/{}", first, last, length),
+            )
+            .unwrap();
+
+            request.respond(Response::new(
+                StatusCode(206),
+                vec![accept_ranges, content_range],
+                file.take(last - first + 1),
+                Some((last - first + 1) as usize),
+                None,
+            ))
+        }
+    };
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(error) => Err(AatiError::Serve {
+            code: 18,
+            error: error.to_string(),
+        }),
+    }
+}
+
+// The first and last byte of a `Range: bytes=...` header. Ranges that can't be
+// parsed, or several of them at once, are ignored and the whole file is sent.
+fn parse_range(range: &str, length: u64) -> Option<(u64, u64)> {
+    let range = range.trim().strip_prefix("bytes=")?;
+
+    if range.contains(',') {
+        return None;
     }
+
+    let (first, last) = range.split_once('-')?;
+    let last_byte = length.saturating_sub(1);
+
+    match (first.trim(), last.trim()) {
+        ("", "") => None,
+
+        // The last N bytes
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+
+            Some((length.saturating_sub(suffix), last_byte))
+        }
+
+        (first, "") => Some((first.parse().ok()?, last_byte)),
+
+        (first, last) => {
+            let first: u64 = first.parse().ok()?;
+            let last: u64 = last.parse().ok()?;
+
+            if last < first {
+                return None;
+            }
+
+            Some((first, last.min(last_byte)))
+        }
+    }
+}
+
+#[test]
+fn test_parse_range() {
+    assert_eq!(parse_range("bytes=100-", 1000), Some((100, 999)));
+    assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+    assert_eq!(parse_range("bytes=900-2000", 1000), Some((900, 999)));
+    assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+    assert_eq!(parse_range("bytes=1000-", 1000), Some((1000, 999)));
+    assert_eq!(parse_range("bytes=0-1,5-9", 1000), None);
+    assert_eq!(parse_range("items=0-1", 1000), None);
 }
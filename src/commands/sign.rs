@@ -0,0 +1,214 @@
+/* بسم الله الرحمن الرحيم
+
+   Aati - Cross-platform Package Manager written in Rust.
+   Copyright (C) 2023  Husayn Haras <haras@disroot.org>
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of version 3 of the GNU General Public License
+   as published by the Free Software Foundation.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use colored::Colorize;
+use ring::{
+    digest,
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use std::{
+    fs::{read, read_to_string, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    commands::install::parse_filename,
+    error::AatiError,
+    utils::{get_signed_message, hash_file, write_atomically},
+};
+
+// Generate an Ed25519 keypair for signing repositories and packages. The
+// private key is only readable by its owner, the public key is printed and
+// written next to it for adding repositories with: $ aati repo add --key
+pub fn keygen(key_path: PathBuf, quiet: bool) -> Result<(), AatiError> {
+    if key_path.exists() {
+        return Err(AatiError::KeyExists(key_path));
+    }
+
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+    let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+    let public_key = hex::encode(key_pair.public_key());
+
+    if !quiet {
+        println!(
+            "{}",
+            format!("+ Writing the signing key to {}", key_path.display()).bright_green()
+        );
+    }
+
+    match create_private_file(&key_path)
+        .and_then(|mut file| writeln!(file, "{}", hex::encode(pkcs8.as_ref())))
+    {
+        Ok(_) => {}
+        Err(error) => {
+            return Err(AatiError::WriteFile {
+                code: 130,
+                path: key_path,
+                error,
+            });
+        }
+    }
+
+    let public_key_path = append_extension(&key_path, "pub");
+
+    match write_atomically(&public_key_path, &format!("{}\n", public_key)) {
+        Ok(_) => {}
+        Err(error) => {
+            return Err(AatiError::WriteFile {
+                code: 131,
+                path: public_key_path,
+                error,
+            });
+        }
+    }
+
+    if quiet {
+        println!("{}", public_key);
+    } else {
+        println!("{}", format!("+ Public key: {}", public_key).bright_green());
+        println!(
+            "{}",
+            format!("+ Also written to {}", public_key_path.display()).bright_green()
+        );
+    }
+
+    Ok(())
+}
+
+// Sign a package with a key from keygen(), writing the hex encoded signature
+// to <file>.sig for adding to its version in repo.toml. Signing a repo.toml
+// the same way produces the repo.toml.sig that sync checks.
+pub fn command(file_path: PathBuf, key_path: PathBuf, quiet: bool) -> Result<(), AatiError> {
+    let key_pair = read_key_pair(&key_path)?;
+
+    let file_name = file_path
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .unwrap_or_default();
+
+    // Packages are signed by name, version and digest, see get_signed_message()
+    let (message, sha512) = if file_name.ends_with(".tar.lz4") {
+        let package = parse_filename(file_name)?;
+
+        let sha512 = match hash_file(&file_path, &digest::SHA512) {
+            Ok(hash) => hash,
+            Err(error) => {
+                return Err(AatiError::ReadFile {
+                    code: 133,
+                    path: file_path,
+                    error,
+                });
+            }
+        };
+
+        let message = get_signed_message(&package.name, &package.version, &sha512);
+
+        (message.into_bytes(), Some(sha512))
+    } else {
+        match read(&file_path) {
+            Ok(contents) => (contents, None),
+            Err(error) => {
+                return Err(AatiError::ReadFile {
+                    code: 133,
+                    path: file_path,
+                    error,
+                });
+            }
+        }
+    };
+
+    let signature = hex::encode(key_pair.sign(&message));
+    let signature_path = append_extension(&file_path, "sig");
+
+    match write_atomically(&signature_path, &format!("{}\n", signature)) {
+        Ok(_) => {}
+        Err(error) => {
+            return Err(AatiError::WriteFile {
+                code: 134,
+                path: signature_path,
+                error,
+            });
+        }
+    }
+
+    if quiet {
+        println!("{}", signature);
+    } else {
+        println!(
+            "{}",
+            format!("+ Signed {}", file_path.display()).bright_green()
+        );
+        println!("{}", format!("+ Signature: {}", signature).bright_green());
+
+        if let Some(sha512) = sha512 {
+            println!(
+                "{}",
+                format!("+ Checksum: sha512:{}", sha512).bright_green()
+            );
+        }
+        println!(
+            "{}",
+            format!("+ Also written to {}", signature_path.display()).bright_green()
+        );
+    }
+
+    Ok(())
+}
+
+fn read_key_pair(key_path: &Path) -> Result<Ed25519KeyPair, AatiError> {
+    let contents = match read_to_string(key_path) {
+        Ok(contents) => contents,
+        Err(error) => {
+            return Err(AatiError::ReadFile {
+                code: 132,
+                path: key_path.to_path_buf(),
+                error,
+            });
+        }
+    };
+
+    hex::decode(contents.trim())
+        .ok()
+        .and_then(|pkcs8| Ed25519KeyPair::from_pkcs8(&pkcs8).ok())
+        .ok_or_else(|| AatiError::InvalidPrivateKey(key_path.to_path_buf()))
+}
+
+// e.g. repo.toml -> repo.toml.sig
+fn append_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+
+    PathBuf::from(path)
+}
+
+fn create_private_file(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.mode(0o600);
+    }
+
+    options.open(path)
+}
@@ -16,17 +16,24 @@
    along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::{fs::File, io::Write, process::exit};
-
+use crate::error::AatiError;
+use crate::types::ConfigFile;
 use crate::utils::{
-    check_aati_dirs, get_aati_config, get_aati_config_path_buf, get_repo_config_path_buf,
+    check_aati_dirs, format_timestamp, get_aati_config, get_aati_config_path_buf,
+    get_key_fingerprint, get_repo_config_path_buf, is_offline, parse_aati_config, parse_public_key,
+    verify_signature, write_atomically,
 };
 
 use colored::Colorize;
+use std::time::{SystemTime, UNIX_EPOCH};
 use toml::Value;
 
-pub fn command(repo_names_option: Option<Vec<String>>, quiet: bool) {
-    let aati_config: Value = get_aati_config().unwrap().parse().unwrap();
+pub fn command(repo_names_option: Option<Vec<String>>, quiet: bool) -> Result<(), AatiError> {
+    if is_offline()? {
+        return Err(AatiError::Offline("sync repositories"));
+    }
+
+    let aati_config: Value = parse_aati_config(161)?;
 
     match aati_config
         .get("sources")
@@ -53,106 +60,319 @@ pub fn command(repo_names_option: Option<Vec<String>>, quiet: bool) {
 
             for repo in selected_repos {
                 let url = repo["url"].as_str().unwrap();
-                let requested_url = format!("{}/repo.toml", url);
 
-                if !quiet {
-                    println!(
-                        "{}",
-                        format!("+ Requesting ({})", requested_url).bright_green()
-                    );
-                }
+                let repo_toml = request_manifest(url, 5, quiet)?;
 
-                match ureq::get(requested_url.as_str()).call() {
-                    Ok(mut repo_toml) => {
-                        let repo_toml = repo_toml.body_mut().read_to_string().unwrap();
+                let repo_value: Value = repo_toml.parse().unwrap();
 
-                        let repo_value: Value = repo_toml.parse().unwrap();
+                let repo_name = repo["name"].as_str().unwrap();
 
-                        let repo_name = repo_value["repo"]["name"].as_str().unwrap();
+                // The manifest is stored under the name the repository was added
+                // with, so a repository can't replace another one's by using its name
+                let manifest_name = repo_value
+                    .get("repo")
+                    .and_then(|repo| repo.get("name"))
+                    .and_then(|name| name.as_str())
+                    .unwrap_or_default();
 
-                        check_aati_dirs();
+                if manifest_name != repo_name {
+                    return Err(AatiError::RepoNameMismatch {
+                        url: url.into(),
+                        name: repo_name.into(),
+                        manifest_name: manifest_name.into(),
+                    });
+                }
 
-                        let repo_config_path_buf = get_repo_config_path_buf(repo_name);
+                // A repository with a pinned key can only move to another one
+                // by signing the manifest that announces it with the old one
+                if let Some(pinned_key) = repo.get("key").and_then(|key| key.as_str()) {
+                    let pinned_key = parse_public_key(pinned_key)?;
 
-                        let mut repo_config = match File::create(&repo_config_path_buf) {
-                            Ok(file) => file,
-                            Err(error) => {
-                                eprintln!(
-                                    "{}",
-                                    format!(
-                                        "- FAILED TO CREATE FILE '{}'! ERROR[88]: {}",
-                                        repo_config_path_buf.display(),
-                                        error
-                                    )
-                                    .bright_red()
-                                );
+                    let new_key = match get_advertised_key(&repo_value) {
+                        Some(key) => Some((key, parse_public_key(key)?)),
+                        None => None,
+                    }
+                    .filter(|(_, new_key)| *new_key != pinned_key);
 
-                                exit(1);
+                    match new_key {
+                        Some((key, new_key)) => {
+                            let fingerprint = get_key_fingerprint(&new_key);
+
+                            match verify_manifest(url, &repo_toml, &pinned_key, quiet) {
+                                Ok(_) => {}
+                                Err(AatiError::BadSignature(_)) => {
+                                    return Err(AatiError::KeyChanged {
+                                        url: url.into(),
+                                        fingerprint,
+                                    });
+                                }
+                                Err(error) => return Err(error),
                             }
-                        };
-
-                        if !quiet {
-                            println!(
-                                "{}",
-                                format!(
-                                    "+   Writing Repo Config to {}",
-                                    repo_config_path_buf.display()
-                                )
-                                .bright_green()
-                            );
-                        }
 
-                        match writeln!(repo_config, "{}", repo_toml) {
-                            Ok(_) => {}
-                            Err(error) => {
-                                eprintln!(
+                            rotate_key(repo_name, key)?;
+
+                            if !quiet {
+                                println!(
                                     "{}",
                                     format!(
-                                        "- FAILED TO WRITE INTO REPO CONFIG AT '{}'! ERROR[48]: {}",
-                                        &repo_config_path_buf.display(),
-                                        error
+                                        "+   The repository's key was rotated to {}",
+                                        fingerprint
                                     )
-                                    .bright_red()
+                                    .bright_green()
                                 );
-
-                                exit(1);
                             }
                         }
 
-                        if !quiet {
-                            println!(
-                                "{}",
-                                format!("+   Synced with ({}) successfully!", url).bright_green()
-                            );
-                        }
+                        None => verify_manifest(url, &repo_toml, &pinned_key, quiet)?,
                     }
+                }
+
+                check_aati_dirs()?;
 
+                let repo_config_path_buf = get_repo_config_path_buf(repo_name);
+
+                if !quiet {
+                    println!(
+                        "{}",
+                        format!(
+                            "+   Writing Repo Config to {}",
+                            repo_config_path_buf.display()
+                        )
+                        .bright_green()
+                    );
+                }
+
+                match write_atomically(&repo_config_path_buf, &format!("{}\n", repo_toml)) {
+                    Ok(_) => {}
                     Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "- FAILED TO REQUEST ({})! ERROR[5]: {}",
-                                requested_url, error
-                            )
-                            .bright_red()
-                        );
-                        exit(1);
+                        return Err(AatiError::WriteFile {
+                            code: 48,
+                            path: repo_config_path_buf,
+                            error,
+                        });
                     }
                 }
+
+                if !quiet {
+                    println!(
+                        "{}",
+                        format!("+   Synced with ({}) successfully!", url).bright_green()
+                    );
+                }
             }
+
+            Ok(())
+        }
+
+        None => Err(AatiError::InvalidConfig {
+            code: 8,
+            path: get_aati_config_path_buf(),
+        }),
+    }
+}
+
+// Request the repo.toml of a repository
+pub fn request_manifest(url: &str, code: u16, quiet: bool) -> Result<String, AatiError> {
+    let requested_url = format!("{}/repo.toml", url);
+
+    if !quiet {
+        println!(
+            "{}",
+            format!("+ Requesting ({})", requested_url).bright_green()
+        );
+    }
+
+    match ureq::get(requested_url.as_str()).call() {
+        Ok(mut response) => Ok(response.body_mut().read_to_string().unwrap()),
+        Err(error) => Err(AatiError::Request {
+            code,
+            url: requested_url,
+            error: error.to_string(),
+        }),
+    }
+}
+
+// Request the detached signature of a repository's repo.toml
+// (repo.toml.sig) and check that it was signed with public_key
+pub fn verify_manifest(
+    url: &str,
+    repo_toml: &str,
+    public_key: &[u8],
+    quiet: bool,
+) -> Result<(), AatiError> {
+    let signature_url = format!("{}/repo.toml.sig", url);
+
+    let signature = match ureq::get(signature_url.as_str()).call() {
+        Ok(mut response) => response.body_mut().read_to_string().unwrap_or_default(),
+        Err(ureq::Error::StatusCode(404)) => {
+            return Err(AatiError::UnsignedManifest(url.into()));
+        }
+        Err(error) => {
+            return Err(AatiError::Request {
+                code: 128,
+                url: signature_url,
+                error: error.to_string(),
+            });
         }
+    };
 
-        None => {
-            let aati_config_path_buf = get_aati_config_path_buf();
-            eprintln!(
-                "{}",
-                format!(
-                    "- ERROR[8]: FAILED TO PARSE INFO FROM {}! TRY: aati repo <repo url>",
-                    aati_config_path_buf.display()
-                )
-                .bright_red()
-            );
-            exit(1);
+    if !verify_signature(public_key, repo_toml.as_bytes(), &signature) {
+        return Err(AatiError::BadSignature(format!("{}/repo.toml", url)));
+    }
+
+    if !quiet {
+        println!(
+            "{}",
+            "+   The manifest's signature is valid!".bright_green()
+        );
+    }
+
+    Ok(())
+}
+
+// The public key a repository advertises in the [repo] table of its repo.toml
+pub fn get_advertised_key(repo_value: &Value) -> Option<&str> {
+    repo_value["repo"].get("key").and_then(|key| key.as_str())
+}
+
+// Pin the new key of a repository whose manifest was signed by the old one
+fn rotate_key(repo_name: &str, key: &str) -> Result<(), AatiError> {
+    let mut config_file: ConfigFile = match toml::from_str(&get_aati_config()?) {
+        Ok(config_file) => config_file,
+        Err(error) => {
+            return Err(AatiError::ParseToml {
+                code: 142,
+                path: get_aati_config_path_buf(),
+                error: Box::new(error),
+            });
         }
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    if let Some(repo) = config_file
+        .sources
+        .repos
+        .iter_mut()
+        .find(|repo| repo.name == repo_name)
+    {
+        repo.key = Some(key.trim().to_lowercase());
+        repo.key_rotated = Some(format_timestamp(timestamp));
     }
+
+    let aati_config_path_buf = get_aati_config_path_buf();
+
+    match write_atomically(
+        &aati_config_path_buf,
+        &toml::to_string_pretty(&config_file).unwrap(),
+    ) {
+        Ok(_) => Ok(()),
+        Err(error) => Err(AatiError::WriteFile {
+            code: 135,
+            path: aati_config_path_buf,
+            error,
+        }),
+    }
+}
+
+#[test]
+fn test_key_rotation() {
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+    use std::{
+        fs::{remove_dir_all, write},
+        sync::{Arc, Mutex},
+        thread,
+    };
+    use tiny_http::{Response, Server};
+
+    use crate::utils::use_test_home;
+
+    let (_guard, home) = use_test_home("sync-rotation");
+
+    let random = SystemRandom::new();
+    let key_pair = |random| {
+        Ed25519KeyPair::from_pkcs8(Ed25519KeyPair::generate_pkcs8(random).unwrap().as_ref())
+            .unwrap()
+    };
+    let (old, new, stranger) = (key_pair(&random), key_pair(&random), key_pair(&random));
+
+    // The repo.toml and repo.toml.sig being served
+    let served = Arc::new(Mutex::new((String::new(), String::new())));
+    let publish = |advertised: &Ed25519KeyPair, signer: &Ed25519KeyPair| {
+        let repo_toml = format!(
+            "[repo]\nname = \"testing\"\nkey = \"{}\"\n\n[index]\npackages = []",
+            hex::encode(advertised.public_key())
+        );
+        let signature = hex::encode(signer.sign(repo_toml.as_bytes()));
+
+        *served.lock().unwrap() = (repo_toml, signature);
+    };
+
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+
+    let served_files = Arc::clone(&served);
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let (repo_toml, signature) = served_files.lock().unwrap().clone();
+
+            let body = match request.url() {
+                "/repo.toml" => repo_toml,
+                _ => signature,
+            };
+
+            let _ = request.respond(Response::from_string(body));
+        }
+    });
+
+    write(
+        get_aati_config_path_buf(),
+        format!(
+            "[sources]\nrepos = [{{ name = \"testing\", url = \"{}\", key = \"{}\" }}]",
+            url,
+            hex::encode(old.public_key())
+        ),
+    )
+    .unwrap();
+
+    let pinned_key = || {
+        let config_file: ConfigFile = toml::from_str(&get_aati_config().unwrap()).unwrap();
+
+        config_file.sources.repos[0].key.clone().unwrap()
+    };
+
+    // The new key is announced in a manifest signed by the pinned one
+    publish(&new, &old);
+    command(None, true).unwrap();
+
+    assert_eq!(pinned_key(), hex::encode(new.public_key()));
+    assert!(get_repo_config_path_buf("testing").exists());
+
+    // Anyone else announcing a key of theirs isn't trusted
+    publish(&stranger, &stranger);
+
+    assert!(matches!(
+        command(None, true),
+        Err(AatiError::KeyChanged { .. })
+    ));
+    assert_eq!(pinned_key(), hex::encode(new.public_key()));
+
+    // Nor is a manifest that calls the repository something else
+    let repo_toml = "[repo]\nname = \"other\"\n\n[index]\npackages = []".to_string();
+    let signature = hex::encode(new.sign(repo_toml.as_bytes()));
+    *served.lock().unwrap() = (repo_toml, signature);
+
+    assert!(matches!(
+        command(None, true),
+        Err(AatiError::RepoNameMismatch { .. })
+    ));
+    assert!(!get_repo_config_path_buf("other").exists());
+
+    let _ = remove_dir_all(&home);
 }
@@ -16,38 +16,46 @@
    along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::process::exit;
-
 use colored::Colorize;
+use humansize::{format_size, BINARY};
+use std::cmp::Ordering;
 use toml::Value;
 
+use crate::error::AatiError;
+use crate::types::InstallReason;
 use crate::utils::{
-    extract_package, get_aati_config, get_aati_lock, get_repo_config, is_supported, prompt_yn,
+    apply_requirement, compare_versions, extract_package, get_aati_lock, get_allowed_version,
+    get_repo_config, is_offline, is_supported, parse_aati_config, prompt_yn, split_requirement,
 };
 
-use super::{get, remove};
+use super::{cache, get, hold, remove};
 
-pub fn command(choice: Option<&str>, force: bool, quiet: bool) {
-    let aati_config: Value = get_aati_config().unwrap().parse().unwrap();
+pub fn command(
+    choice: Option<&str>,
+    allow_downgrade: bool,
+    force: bool,
+    quiet: bool,
+) -> Result<(), AatiError> {
+    let aati_config: Value = parse_aati_config(162)?;
     let repo_list = aati_config["sources"]["repos"].as_array().unwrap();
     let mut added_repos: Vec<Value> = Vec::new();
 
     for repo_info in repo_list {
         added_repos.push(
-            get_repo_config(repo_info["name"].as_str().unwrap())
+            get_repo_config(repo_info["name"].as_str().unwrap())?
                 .parse::<Value>()
                 .unwrap(),
         );
     }
 
-    let aati_lock: Value = get_aati_lock().parse().unwrap();
+    let aati_lock: Value = get_aati_lock()?.parse().unwrap();
 
     let repos = aati_config["sources"]["repos"].as_array().unwrap();
     let mut repos_toml: Vec<Value> = Vec::new();
 
     for repo in repos {
         repos_toml.push(
-            get_repo_config(repo["name"].as_str().unwrap())
+            get_repo_config(repo["name"].as_str().unwrap())?
                 .parse::<Value>()
                 .unwrap(),
         )
@@ -56,45 +64,76 @@ pub fn command(choice: Option<&str>, force: bool, quiet: bool) {
     let installed_packages = aati_lock["package"].as_array().unwrap();
 
     if let Some(package_name) = choice {
-        match extract_package(package_name, &added_repos) {
-            Some(extracted_package) => {
-                let mut is_installed = false;
-                let mut is_up_to_date = true;
-
-                for installed_package in installed_packages {
-                    if installed_package["name"].as_str().unwrap() == extracted_package[1]
-                        && installed_package["source"].as_str().unwrap() == extracted_package[0]
-                    {
-                        is_installed = true;
-                        if installed_package["version"].as_str().unwrap() != extracted_package[2] {
-                            is_up_to_date = false;
-                        }
-                    }
+        let (package, requirement) = split_requirement(package_name);
+
+        match extract_package(package, &added_repos)? {
+            Some(mut extracted_package) => {
+                let installed_package = match installed_packages.iter().find(|pkg| {
+                    pkg["name"].as_str().unwrap() == extracted_package[1]
+                        && pkg["source"].as_str().unwrap() == extracted_package[0]
+                }) {
+                    Some(installed_package) => installed_package,
+                    None => return Err(AatiError::NotInstalled(extracted_package[1].clone())),
+                };
+
+                let installed_version = installed_package["version"].as_str().unwrap();
+                let reason = installed_reason(installed_package);
+                let is_held = is_held(installed_package);
+
+                if is_held && !force {
+                    return Err(AatiError::Held(extracted_package[1].clone()));
+                }
+
+                // Stay within the requirement the package was installed with,
+                // unless a new one is given
+                let requirement = requirement.or_else(|| {
+                    installed_package
+                        .get("requirement")
+                        .and_then(|r| r.as_str())
+                });
+
+                if let Some(requirement) = requirement {
+                    apply_requirement(&mut extracted_package, requirement, &added_repos)?;
                 }
 
-                if is_installed {
-                    if !is_up_to_date {
-                        remove(Some(vec![package_name.into()]), false, true, quiet);
-                        get::command(package_name, true, quiet);
-                    } else {
+                match compare_versions(&extracted_package[2], installed_version) {
+                    Ordering::Equal => {
                         if !quiet {
                             eprintln!("{}", "+ That Package is already up to date!".bright_blue());
                         }
-                        exit(1);
                     }
-                } else {
-                    eprintln!("{}", "- Package not installed!".bright_red());
-                    exit(1);
+
+                    Ordering::Less if !allow_downgrade => {
+                        return Err(AatiError::Downgrade {
+                            package_name: extracted_package[1].clone(),
+                            installed_version: installed_version.into(),
+                            available_version: extracted_package[2].clone(),
+                        });
+                    }
+
+                    _ => {
+                        cache::check_available(&extracted_package)?;
+                        get::download_packages(&[extracted_package.clone()], &added_repos, quiet)?;
+                        get::fetch_verified(&extracted_package, &added_repos, quiet)?;
+
+                        remove::command(&extracted_package[1], true, quiet)?;
+                        get::command(&extracted_package, requirement, reason, &[], true, quiet)?;
+
+                        if is_held {
+                            hold::set_held(&extracted_package[1], true)?;
+                        }
+                    }
                 }
             }
 
-            None => {
-                eprintln!("{}", "- PACKAGE NOT FOUND!".bright_red());
-                exit(1);
-            }
+            None => return Err(AatiError::PackageNotFound(package_name.into())),
         }
     } else {
-        let mut to_be_upgraded: Vec<&str> = Vec::new();
+        let mut to_be_upgraded: Vec<(Vec<String>, Option<String>, InstallReason)> = Vec::new();
+        let mut not_downgraded: Vec<(&Value, &str)> = Vec::new();
+        let mut held: Vec<(&Value, &str)> = Vec::new();
+        let mut not_cached: Vec<(&Value, &str)> = Vec::new();
+        let is_offline = is_offline()?;
 
         if !quiet {
             println!("{}", "+ Packages to be upgraded:".bright_green());
@@ -111,18 +150,55 @@ pub fn command(choice: Option<&str>, force: bool, quiet: bool) {
                         .unwrap();
 
                     for available_package in available_packages {
-                        if installed_package["name"] == available_package["name"]
-                            && is_supported(available_package["target"].as_str().unwrap())
-                            && installed_package["version"].as_str().unwrap()
-                                != available_package["versions"]
-                                    .as_array()
-                                    .unwrap()
-                                    .first()
-                                    .unwrap()["tag"]
-                                    .as_str()
-                                    .unwrap()
+                        if installed_package["name"] != available_package["name"]
+                            || !is_supported(available_package["target"].as_str().unwrap())
                         {
-                            to_be_upgraded.push(available_package["name"].as_str().unwrap());
+                            continue;
+                        }
+
+                        let latest_version = match get_allowed_version(
+                            installed_package,
+                            available_package["versions"].as_array().unwrap(),
+                        ) {
+                            Some(latest_version) => latest_version["tag"].as_str().unwrap(),
+                            None => continue,
+                        };
+
+                        let ordering = compare_versions(
+                            latest_version,
+                            installed_package["version"].as_str().unwrap(),
+                        );
+
+                        if ordering != Ordering::Equal && is_held(installed_package) {
+                            held.push((installed_package, latest_version));
+                        } else if ordering == Ordering::Less && !allow_downgrade {
+                            not_downgraded.push((installed_package, latest_version));
+                        } else if ordering != Ordering::Equal {
+                            let package_name = available_package["name"].as_str().unwrap();
+
+                            let extracted_package: Vec<String> = vec![
+                                installed_package["source"].as_str().unwrap().into(),
+                                package_name.into(),
+                                latest_version.into(),
+                                available_package["target"].as_str().unwrap().into(),
+                            ];
+
+                            if is_offline && !cache::is_available(&extracted_package)? {
+                                not_cached.push((installed_package, latest_version));
+                                continue;
+                            }
+
+                            // The requirement is kept in the lockfile
+                            let requirement = installed_package
+                                .get("requirement")
+                                .and_then(|r| r.as_str())
+                                .map(|r| r.into());
+
+                            to_be_upgraded.push((
+                                extracted_package,
+                                requirement,
+                                installed_reason(installed_package),
+                            ));
 
                             if !quiet {
                                 println!(
@@ -131,13 +207,7 @@ pub fn command(choice: Option<&str>, force: bool, quiet: bool) {
                                     installed_package["source"].as_str().unwrap(),
                                     installed_package["name"].as_str().unwrap(),
                                     installed_package["version"].as_str().unwrap(),
-                                    available_package["versions"]
-                                        .as_array()
-                                        .unwrap()
-                                        .first()
-                                        .unwrap()["tag"]
-                                        .as_str()
-                                        .unwrap(),
+                                    latest_version,
                                 );
                             }
                         }
@@ -145,11 +215,105 @@ pub fn command(choice: Option<&str>, force: bool, quiet: bool) {
                 }
             }
 
+            if to_be_upgraded.is_empty() && !quiet {
+                println!("{}", "+   None!".bright_green());
+            }
+
+            if !held.is_empty() && !quiet {
+                println!(
+                    "{}",
+                    "+ Held packages (use: $ aati unhold <package>):".yellow()
+                );
+
+                for (installed_package, latest_version) in &held {
+                    println!(
+                        "{}   {}/{}-{} -> {} {}",
+                        "+".yellow(),
+                        installed_package["source"].as_str().unwrap(),
+                        installed_package["name"].as_str().unwrap(),
+                        installed_package["version"].as_str().unwrap(),
+                        latest_version,
+                        "[held]".yellow()
+                    );
+                }
+            }
+
+            // The repository only offers older versions of these, which
+            // usually means it was rolled back or its versions are mistagged
+            if !not_downgraded.is_empty() && !quiet {
+                println!(
+                    "{}",
+                    "+ Packages not downgraded (use --allow-downgrade to do so):".yellow()
+                );
+
+                for (installed_package, latest_version) in &not_downgraded {
+                    println!(
+                        "{}   {}/{}-{} -> {}",
+                        "+".yellow(),
+                        installed_package["source"].as_str().unwrap(),
+                        installed_package["name"].as_str().unwrap(),
+                        installed_package["version"].as_str().unwrap(),
+                        latest_version,
+                    );
+                }
+            }
+
+            if !not_cached.is_empty() && !quiet {
+                println!(
+                    "{}",
+                    "+ Packages not upgraded because they aren't in the cache (offline):".yellow()
+                );
+
+                for (installed_package, latest_version) in &not_cached {
+                    println!(
+                        "{}   {}/{}-{} -> {}",
+                        "+".yellow(),
+                        installed_package["source"].as_str().unwrap(),
+                        installed_package["name"].as_str().unwrap(),
+                        installed_package["version"].as_str().unwrap(),
+                        latest_version,
+                    );
+                }
+            }
+
             if !to_be_upgraded.is_empty() {
-                if force || prompt_yn("/ Are you sure you want to continue this Transaction?") {
-                    for package in to_be_upgraded {
-                        remove(Some(vec![package.into()]), false, true, quiet);
-                        get::command(package, true, quiet);
+                let extracted_packages: Vec<Vec<String>> = to_be_upgraded
+                    .iter()
+                    .map(|(extracted_package, _, _)| extracted_package.clone())
+                    .collect();
+
+                let downloads = get::plan_downloads(&extracted_packages, &added_repos)?;
+
+                if !quiet {
+                    println!(
+                        "{}",
+                        format!(
+                            "+ Total download size: {}",
+                            format_size(get::get_total_size(&downloads), BINARY)
+                        )
+                        .bright_green()
+                    );
+                }
+
+                if force || prompt_yn("/ Are you sure you want to continue this Transaction?")? {
+                    // Nothing is removed before every new version is downloaded
+                    // and verified
+                    get::download_all(&downloads, quiet)?;
+
+                    for (extracted_package, _, _) in &to_be_upgraded {
+                        get::fetch_verified(extracted_package, &added_repos, quiet)?;
+                    }
+
+                    for (extracted_package, requirement, reason) in to_be_upgraded {
+                        remove::command(&extracted_package[1], true, quiet)?;
+                        get::command(
+                            &extracted_package,
+                            requirement.as_deref(),
+                            reason,
+                            &[],
+                            true,
+                            quiet,
+                        )?;
                     }
 
                     if !quiet {
@@ -158,8 +322,11 @@ pub fn command(choice: Option<&str>, force: bool, quiet: bool) {
                 } else if !quiet {
                     println!("{}", "+ Transaction aborted".bright_green());
                 }
-            } else if !quiet {
-                println!("{}", "+   None!".bright_green());
+            } else if not_downgraded.is_empty()
+                && held.is_empty()
+                && not_cached.is_empty()
+                && !quiet
+            {
                 println!("{}", "+ It's all up-to-date!".bright_green());
             }
         } else if !quiet {
@@ -170,4 +337,104 @@ pub fn command(choice: Option<&str>, force: bool, quiet: bool) {
             );
         }
     }
+
+    Ok(())
+}
+
+fn is_held(installed_package: &Value) -> bool {
+    installed_package
+        .get("held")
+        .and_then(|held| held.as_bool())
+        .unwrap_or(false)
+}
+
+// Keep the reason a package was installed for when it's reinstalled
+fn installed_reason(installed_package: &Value) -> InstallReason {
+    installed_package
+        .get("reason")
+        .and_then(|reason| reason.clone().try_into().ok())
+        .unwrap_or_default()
+}
+
+#[test]
+fn test_held_package_needs_force() {
+    use lz4::EncoderBuilder;
+    use ring::digest;
+    use std::fs::{create_dir_all, remove_dir_all, write, File};
+    use tar::{Builder, Header};
+
+    use crate::{
+        types::{LockFile, Package},
+        utils::{
+            get_aati_config_path_buf, get_aati_lock_path_buf, get_repo_config_path_buf, hash_file,
+            use_test_home,
+        },
+    };
+
+    let (_guard, home) = use_test_home("upgrade-held");
+
+    write(
+        get_aati_config_path_buf(),
+        "offline = true\n\n[sources]\nrepos = [{ name = \"testing\", url = \"http://localhost:1\" }]",
+    )
+    .unwrap();
+
+    // The new version is only in the cache
+    let extracted_package: Vec<String> = ["testing", "tool", "0.2.0", "any"]
+        .map(String::from)
+        .to_vec();
+    let cached_path = cache::get_cached_path(&extracted_package);
+    create_dir_all(cached_path.parent().unwrap()).unwrap();
+
+    let pkgfile = "[data]\nname tool\nversion 0.2.0\n\n[installation]\n\n[removal]\n";
+    let mut header = Header::new_gnu();
+    header.set_size(pkgfile.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+
+    let encoder = EncoderBuilder::new()
+        .build(File::create(&cached_path).unwrap())
+        .unwrap();
+    let mut builder = Builder::new(encoder);
+    builder
+        .append_data(&mut header, "./tool-0.2.0/PKGFILE", pkgfile.as_bytes())
+        .unwrap();
+    let (_, result) = builder.into_inner().unwrap().finish();
+    result.unwrap();
+
+    write(get_repo_config_path_buf("testing"), format!("[repo]
+name = \"testing\"
+maintainer = \"Husayn Haras\"
+description = \"APR made for testing upgrade::command()\"
+
+[index]
+packages = [
+    {{ name = \"tool\", target = \"any\", versions = [
+        {{ tag = \"0.1.0\", checksum = \"checksum-placeholder\" }},
+        {{ tag = \"0.2.0\", checksum = \"sha256:{}\" }},
+    ], author = \"Husayn Haras\", description = \"Held at its older version\", url = \"https://example.com\" }},
+]", hash_file(&cached_path, &digest::SHA256).unwrap())).unwrap();
+
+    let lock_file = LockFile {
+        package: vec![Package {
+            held: true,
+            ..Package::test("tool")
+        }],
+    };
+    let lock_file_str = toml::to_string(&lock_file).unwrap();
+    write(get_aati_lock_path_buf(), &lock_file_str).unwrap();
+
+    assert!(matches!(
+        command(Some("tool"), false, false, true),
+        Err(AatiError::Held(_))
+    ));
+    assert_eq!(get_aati_lock().unwrap(), lock_file_str.trim());
+
+    command(Some("tool"), false, true, true).unwrap();
+
+    let lock_file: LockFile = toml::from_str(&get_aati_lock().unwrap()).unwrap();
+    assert_eq!(lock_file.package[0].version, "0.2.0");
+    assert!(lock_file.package[0].held);
+
+    let _ = remove_dir_all(&home);
 }
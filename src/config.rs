@@ -26,8 +26,27 @@ pub const AATI_DIRNAME: &str = ".aati";
 pub const REPOS_DIRNAME: &str = "repos";
 pub const BIN_DIRNAME: &str = "bin";
 pub const LIB_DIRNAME: &str = "lib";
+pub const CACHE_DIRNAME: &str = "cache";
 pub const CONFIG_FILENAME: &str = "rc.toml";
 pub const LOCK_FILENAME: &str = "lock.toml";
+pub const PROCESS_LOCK_FILENAME: &str = ".lock";
+pub const SIGNING_KEY_FILENAME: &str = "signing.key";
+pub const DEFAULT_DOWNLOAD_WORKERS: usize = 4;
+pub const DEFAULT_SYSTEM_TIMEOUT_SECS: u64 = 300;
+
+// The only environment variables `system` lines get from the user's
+// environment, everything else is cleared before they're ran
+#[cfg(not(target_os = "windows"))]
+pub const SYSTEM_ENVIRONMENT: [&str; 4] = ["PATH", "HOME", "LANG", "TERM"];
+#[cfg(target_os = "windows")]
+pub const SYSTEM_ENVIRONMENT: [&str; 6] = [
+    "PATH",
+    "SYSTEMROOT",
+    "TEMP",
+    "TMP",
+    "USERPROFILE",
+    "COMSPEC",
+];
 
 // Package targets
 pub const POSSIBLE_TARGETS: [&str; 92] = [
@@ -0,0 +1,603 @@
+/* بسم الله الرحمن الرحيم
+
+   Aati - Cross-platform Package Manager written in Rust.
+   Copyright (C) 2023  Husayn Haras <haras@disroot.org>
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of version 3 of the GNU General Public License
+   as published by the Free Software Foundation.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{fmt, io, num::ParseIntError, path::PathBuf};
+
+use crate::transaction::ExecutionError;
+
+// Everything that can make an aati command fail. Variants that wrap a failed
+// filesystem or network operation carry the ERROR[nn] number of the place it
+// happened at, so that reports keep pointing at the same spot in the code.
+#[derive(Debug)]
+pub enum AatiError {
+    CreateDirectory {
+        code: u16,
+        path: PathBuf,
+        error: io::Error,
+    },
+    CreateFile {
+        code: u16,
+        path: PathBuf,
+        error: io::Error,
+    },
+    OpenFile {
+        code: u16,
+        path: PathBuf,
+        error: io::Error,
+    },
+    ReadFile {
+        code: u16,
+        path: PathBuf,
+        error: io::Error,
+    },
+    WriteFile {
+        code: u16,
+        path: PathBuf,
+        error: io::Error,
+    },
+    DeleteFile {
+        code: u16,
+        path: PathBuf,
+        error: io::Error,
+    },
+    DeleteDirectory {
+        code: u16,
+        path: PathBuf,
+        error: io::Error,
+    },
+    ReadDirectory {
+        code: u16,
+        path: PathBuf,
+        error: io::Error,
+    },
+    LockFile {
+        code: u16,
+        path: PathBuf,
+        error: io::Error,
+    },
+    Extract {
+        code: u16,
+        path: PathBuf,
+        error: io::Error,
+    },
+    Decode {
+        code: u16,
+        path: PathBuf,
+        error: io::Error,
+    },
+    Compress {
+        code: u16,
+        path: PathBuf,
+        error: io::Error,
+    },
+    Archive {
+        code: u16,
+        path: PathBuf,
+        error: io::Error,
+    },
+    ParseToml {
+        code: u16,
+        path: PathBuf,
+        error: Box<toml::de::Error>,
+    },
+    Request {
+        code: u16,
+        url: String,
+        error: String,
+    },
+    Serve {
+        code: u16,
+        error: String,
+    },
+    Input {
+        code: u16,
+        error: io::Error,
+    },
+    ParseInput {
+        code: u16,
+        error: ParseIntError,
+    },
+    InvalidGlob {
+        code: u16,
+        pattern: String,
+        error: glob::PatternError,
+    },
+    InvalidConfig {
+        code: u16,
+        path: PathBuf,
+    },
+    Execution {
+        error: ExecutionError,
+        // Whether `system` lines ran before it failed, which can't be undone
+        ran_system_commands: bool,
+    },
+    PackageNotFound(String),
+    PackageNotInRepo(String),
+    NotInstalled(String),
+    NotOwned(PathBuf),
+    RepoNotFound(PathBuf),
+    RepoNotAdded(String),
+    RepoAlreadyAdded {
+        name: String,
+        url: String,
+    },
+    RepoNameMismatch {
+        url: String,
+        name: String,
+        manifest_name: String,
+    },
+    DirectoryNotFound(PathBuf),
+    NoRepositories,
+    InvalidChoice,
+    InvalidFilename(String),
+    MissingPkgfileData(&'static str),
+    PkgfileNotFound(PathBuf),
+    InvalidRequirement {
+        requirement: String,
+        error: semver::Error,
+    },
+    NoMatchingVersion {
+        package_name: String,
+        requirement: String,
+    },
+    ChecksumMismatch(String),
+    InvalidChecksum {
+        package_name: String,
+        checksum: String,
+    },
+    Held(String),
+    NoOlderVersion(String),
+    Downgrade {
+        package_name: String,
+        installed_version: String,
+        available_version: String,
+    },
+    DependenciesNotInstalled(String),
+    DependencyNotFound {
+        dependency: String,
+        package_name: String,
+    },
+    CircularDependency(Vec<String>),
+    RequiredBy {
+        package_name: String,
+        dependents: Vec<String>,
+    },
+    RepoRequiredBy {
+        repo_name: String,
+        dependents: Vec<String>,
+    },
+    FileConflicts(String),
+    UnsafeArchive {
+        path: PathBuf,
+        reason: String,
+    },
+    InvalidKey(String),
+    KeyChanged {
+        url: String,
+        fingerprint: String,
+    },
+    UnsignedManifest(String),
+    BadSignature(String),
+    UnsignedPackage {
+        package_name: String,
+        version: String,
+    },
+    InvalidPrivateKey(PathBuf),
+    KeyExists(PathBuf),
+    InvalidPolicy(String),
+    Offline(&'static str),
+    NotCached {
+        package_name: String,
+        version: String,
+    },
+    Busy,
+}
+
+impl fmt::Display for AatiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AatiError::CreateDirectory { code, path, error } => write!(
+                f,
+                "FAILED TO CREATE DIRECTORY '{}'! ERROR[{}]: {}",
+                path.display(),
+                code,
+                error
+            ),
+            AatiError::CreateFile { code, path, error } => write!(
+                f,
+                "FAILED TO CREATE FILE '{}'! ERROR[{}]: {}",
+                path.display(),
+                code,
+                error
+            ),
+            AatiError::OpenFile { code, path, error } => write!(
+                f,
+                "FAILED TO OPEN FILE '{}'! ERROR[{}]: {}",
+                path.display(),
+                code,
+                error
+            ),
+            AatiError::ReadFile { code, path, error } => write!(
+                f,
+                "FAILED TO READ FILE '{}'! ERROR[{}]: {}",
+                path.display(),
+                code,
+                error
+            ),
+            AatiError::WriteFile { code, path, error } => write!(
+                f,
+                "FAILED TO WRITE INTO FILE '{}'! ERROR[{}]: {}",
+                path.display(),
+                code,
+                error
+            ),
+            AatiError::DeleteFile { code, path, error } => write!(
+                f,
+                "FAILED TO DELETE FILE '{}'! ERROR[{}]: {}",
+                path.display(),
+                code,
+                error
+            ),
+            AatiError::DeleteDirectory { code, path, error } => write!(
+                f,
+                "FAILED TO DELETE DIRECTORY '{}'! ERROR[{}]: {}",
+                path.display(),
+                code,
+                error
+            ),
+            AatiError::ReadDirectory { code, path, error } => write!(
+                f,
+                "FAILED TO READ DIRECTORY '{}'! ERROR[{}]: {}",
+                path.display(),
+                code,
+                error
+            ),
+            AatiError::LockFile { code, path, error } => write!(
+                f,
+                "FAILED TO LOCK FILE '{}'! ERROR[{}]: {}",
+                path.display(),
+                code,
+                error
+            ),
+            AatiError::Extract { code, path, error } => write!(
+                f,
+                "FAILED TO EXTRACT TARBALL '{}'! ERROR[{}]: {}",
+                path.display(),
+                code,
+                error
+            ),
+            AatiError::Decode { code, path, error } => write!(
+                f,
+                "FAILED TO DECODE THE LZ4 COMPRESSED PACKAGE AT '{}'! ERROR[{}]: {}",
+                path.display(),
+                code,
+                error
+            ),
+            AatiError::Compress { code, path, error } => write!(
+                f,
+                "FAILED TO COMPRESS '{}' USING LZ4! ERROR[{}]: {}",
+                path.display(),
+                code,
+                error
+            ),
+            AatiError::Archive { code, path, error } => write!(
+                f,
+                "FAILED TO CREATE TARBALL '{}'! ERROR[{}]: {}",
+                path.display(),
+                code,
+                error
+            ),
+            AatiError::ParseToml { code, path, error } => write!(
+                f,
+                "FAILED TO PARSE '{}'! ERROR[{}]: {}",
+                path.display(),
+                code,
+                error
+            ),
+            AatiError::Request { code, url, error } => {
+                write!(f, "FAILED TO REQUEST ({})! ERROR[{}]: {}", url, code, error)
+            }
+            AatiError::Serve { code, error } => {
+                write!(f, "FAILED TO SERVE THE REPOSITORY! ERROR[{}]: {}", code, error)
+            }
+            AatiError::Input { code, error } => {
+                write!(f, "DIDN'T RECEIVE VALID INPUT! ERROR[{}]: {}", code, error)
+            }
+            AatiError::ParseInput { code, error } => {
+                write!(f, "FAILED TO PARSE INPUT! ERROR[{}]: {}", code, error)
+            }
+            AatiError::InvalidGlob {
+                code,
+                pattern,
+                error,
+            } => write!(
+                f,
+                "INVALID GLOB PATTERN '{}'! ERROR[{}]: {}",
+                pattern, code, error
+            ),
+            AatiError::InvalidConfig { code, path } => write!(
+                f,
+                "ERROR[{}]: FAILED TO PARSE INFO FROM {}! TRY: aati repo <repo url>",
+                code,
+                path.display()
+            ),
+            AatiError::Execution {
+                error,
+                ran_system_commands,
+            } => write!(
+                f,
+                "{}\n- Changes made by the PKGFILE were rolled back{}",
+                error,
+                if *ran_system_commands {
+                    ", except for the effects of its `system` lines which can't be undone"
+                } else {
+                    ""
+                }
+            ),
+            AatiError::PackageNotFound(package_name) => {
+                write!(f, "Package '{}' not found!", package_name)
+            }
+            AatiError::PackageNotInRepo(package_name) => write!(
+                f,
+                "Package '{}' is not found on the Repository! Try: $ aati sync",
+                package_name
+            ),
+            AatiError::NotInstalled(package_name) => {
+                write!(f, "Package '{}' is not installed!", package_name)
+            }
+            AatiError::NotOwned(path) => {
+                write!(f, "No installed package owns '{}'", path.display())
+            }
+            AatiError::RepoNotFound(path) => write!(
+                f,
+                "Could not find repository manifest at '{}'! Try: $ aati repo add <repo url>",
+                path.display()
+            ),
+            AatiError::RepoNotAdded(repo_name) => write!(
+                f,
+                "Repository '{}' is not added to the Config file!",
+                repo_name
+            ),
+            AatiError::RepoAlreadyAdded { name, url } => {
+                write!(f, "Repository '{} ({})' is already added!", name, url)
+            }
+            AatiError::RepoNameMismatch {
+                url,
+                name,
+                manifest_name,
+            } => write!(
+                f,
+                "The repository ({}) was added as '{}' but its repo.toml calls it '{}'! Refusing to sync it",
+                url, name, manifest_name
+            ),
+            AatiError::DirectoryNotFound(path) => {
+                write!(f, "Directory '{}' doesn't exist!", path.display())
+            }
+            AatiError::NoRepositories => write!(
+                f,
+                "YOU HAVE NO REPOSITORIES SET! TRY: aati repo add <repo url>"
+            ),
+            AatiError::InvalidChoice => write!(f, "INVALID CHOICE!"),
+            AatiError::InvalidFilename(reason) => write!(f, "{}", reason),
+            AatiError::MissingPkgfileData(field) => write!(
+                f,
+                "Package {} not provided by the PKGFILE nor as a command line argument!",
+                field
+            ),
+            AatiError::PkgfileNotFound(path) => {
+                write!(f, "No PKGFILE found at '{}'", path.display())
+            }
+            AatiError::InvalidRequirement { requirement, error } => {
+                write!(f, "INVALID VERSION REQUIREMENT '{}'! {}", requirement, error)
+            }
+            AatiError::NoMatchingVersion {
+                package_name,
+                requirement,
+            } => write!(
+                f,
+                "No version of '{}' matches '{}'! Try: $ aati sync",
+                package_name, requirement
+            ),
+            AatiError::ChecksumMismatch(package_name) => write!(
+                f,
+                "Checksums of '{}' don't match! Installation is aborted",
+                package_name
+            ),
+            AatiError::InvalidChecksum {
+                package_name,
+                checksum,
+            } => write!(
+                f,
+                "The checksum of '{}' is invalid: '{}'! It should be a hex digest, optionally prefixed with sha256:, sha384: or sha512:",
+                package_name, checksum
+            ),
+            AatiError::Held(package_name) => write!(
+                f,
+                "Package '{}' is held! Upgrade it anyway using: $ aati upgrade --force {}",
+                package_name, package_name
+            ),
+            AatiError::NoOlderVersion(package_name) => write!(
+                f,
+                "The repository has no version of '{}' older than the installed one!",
+                package_name
+            ),
+            AatiError::Downgrade {
+                package_name,
+                installed_version,
+                available_version,
+            } => write!(
+                f,
+                "Upgrading '{}' would downgrade it from {} to {}! Try: $ aati upgrade --allow-downgrade {}",
+                package_name, installed_version, available_version, package_name
+            ),
+            AatiError::DependenciesNotInstalled(package_name) => write!(
+                f,
+                "Dependencies of '{}' are not installed! Installation is aborted",
+                package_name
+            ),
+            AatiError::DependencyNotFound {
+                dependency,
+                package_name,
+            } => write!(
+                f,
+                "Dependency '{}' of package '{}' is not found in the added repositories! Try: $ aati sync",
+                dependency, package_name
+            ),
+            AatiError::CircularDependency(chain) => {
+                write!(f, "CIRCULAR DEPENDENCY DETECTED! {}", chain.join(" -> "))
+            }
+            AatiError::RequiredBy {
+                package_name,
+                dependents,
+            } => write!(
+                f,
+                "Package '{}' is required by: {}! Remove them along with it using: $ aati remove --cascade {}",
+                package_name,
+                dependents.join(", "),
+                package_name
+            ),
+            AatiError::RepoRequiredBy {
+                repo_name,
+                dependents,
+            } => write!(
+                f,
+                "Packages from other repositories depend on the ones installed from '{}': {}! Remove them along with it using: $ aati repo remove --cascade {}",
+                repo_name,
+                dependents.join(", "),
+                repo_name
+            ),
+            AatiError::FileConflicts(package_name) => write!(
+                f,
+                "Installing '{}' would overwrite files it doesn't own! Installation is aborted",
+                package_name
+            ),
+            AatiError::UnsafeArchive { path, reason } => write!(
+                f,
+                "Refusing to unpack '{}': {}! Installation is aborted",
+                path.display(),
+                reason
+            ),
+            AatiError::InvalidKey(key) => write!(
+                f,
+                "'{}' is not a valid Ed25519 public key! It should be 64 hexadecimal characters",
+                key
+            ),
+            AatiError::KeyChanged { url, fingerprint } => write!(
+                f,
+                "The repository ({}) changed its key to {} without signing the change with the pinned key! Refusing to trust it",
+                url, fingerprint
+            ),
+            AatiError::UnsignedManifest(url) => write!(
+                f,
+                "The repository ({}) is not signed! Refusing to trust its repo.toml without a repo.toml.sig",
+                url
+            ),
+            AatiError::BadSignature(url) => write!(
+                f,
+                "The signature of ({}) doesn't match the trusted key!",
+                url
+            ),
+            AatiError::UnsignedPackage {
+                package_name,
+                version,
+            } => write!(
+                f,
+                "Package '{}-{}' has no signature but its repository is signed! Installation is aborted",
+                package_name, version
+            ),
+            AatiError::InvalidPrivateKey(path) => write!(
+                f,
+                "'{}' is not a valid signing key! Generate one using: $ aati keygen",
+                path.display()
+            ),
+            AatiError::KeyExists(path) => write!(
+                f,
+                "A signing key already exists at '{}'! Use --output to write the new one elsewhere",
+                path.display()
+            ),
+            AatiError::InvalidPolicy(policy) => write!(
+                f,
+                "{} is not a valid system_commands policy in rc.toml! Use \"allow\", \"prompt\" or \"deny\"",
+                policy
+            ),
+            AatiError::Offline(action) => write!(
+                f,
+                "Can't {} while offline! Try again without --offline or `offline = true` in rc.toml",
+                action
+            ),
+            AatiError::NotCached {
+                package_name,
+                version,
+            } => write!(
+                f,
+                "Package '{}-{}' is not in the package cache and aati is offline!",
+                package_name, version
+            ),
+            AatiError::Busy => write!(
+                f,
+                "Another aati process is running! Try again once it's finished"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AatiError {}
+
+impl From<ExecutionError> for AatiError {
+    fn from(error: ExecutionError) -> Self {
+        AatiError::Execution {
+            error,
+            ran_system_commands: false,
+        }
+    }
+}
+
+#[test]
+fn test_error_display() {
+    let error = AatiError::ReadFile {
+        code: 45,
+        path: PathBuf::from("/home/user/.config/aati/lock.toml"),
+        error: io::Error::new(io::ErrorKind::NotFound, "No such file or directory"),
+    };
+
+    assert_eq!(
+        error.to_string(),
+        "FAILED TO READ FILE '/home/user/.config/aati/lock.toml'! ERROR[45]: No such file or directory"
+    );
+
+    let error: AatiError = ExecutionError::Delete {
+        path: PathBuf::from("/tmp/aati-file"),
+        error: io::Error::new(io::ErrorKind::PermissionDenied, "Permission denied"),
+    }
+    .into();
+
+    assert!(error
+        .to_string()
+        .ends_with("- Changes made by the PKGFILE were rolled back"));
+
+    let error = AatiError::Execution {
+        error: ExecutionError::InvalidCommand {
+            line: "frobnicate".into(),
+        },
+        ran_system_commands: true,
+    };
+
+    assert!(error
+        .to_string()
+        .ends_with("except for the effects of its `system` lines which can't be undone"));
+}
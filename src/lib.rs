@@ -0,0 +1,31 @@
+/* بسم الله الرحمن الرحيم
+
+   Aati - Cross-platform Package Manager written in Rust.
+   Copyright (C) 2023  Husayn Haras <haras@disroot.org>
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of version 3 of the GNU General Public License
+   as published by the Free Software Foundation.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Aati as a library: the commands are exposed as functions that return
+// Result<_, AatiError> and the `aati` binary is a thin CLI over them
+
+pub mod commands;
+pub mod config;
+pub mod error;
+pub mod progress;
+pub mod transaction;
+pub mod types;
+pub mod utils;
+pub mod version;
+
+pub use error::AatiError;
@@ -17,22 +17,25 @@
 */
 
 use colored::Colorize;
-use config::{ISSUE_TRACKER_URL, USER_GUIDE_URL};
-use std::io::stdout;
+use std::{io::stdout, process::exit};
 
 use clap::{Arg, ArgAction, Command, ValueHint};
 use clap_complete::Shell;
-use commands::{
-    changelog, generate, get, install, list, package, query, repo, serve, sync, upgrade,
-};
-use utils::get_target;
-use version::get_version;
 
-mod commands;
-mod config;
-mod types;
-mod utils;
-mod version;
+use aati::{
+    commands::{
+        self, autoremove, cache, changelog, downgrade, files, generate, get, hold, install, list,
+        mark, owns, package, query, repo, serve, sign, sync, upgrade,
+    },
+    config::{ISSUE_TRACKER_URL, USER_GUIDE_URL},
+    error::AatiError,
+    types::InstallReason,
+    utils::{
+        check_aati_dirs, get_signing_key_path_buf, get_target, lock_aati_home, set_no_scripts,
+        set_offline,
+    },
+    version::get_version,
+};
 
 fn main() {
     let after_help = format!(
@@ -58,21 +61,43 @@ as published by the Free Software Foundation.",
         .after_help(after_help)
         .subcommand_required(true)
         .arg_required_else_help(true)
+        .arg(
+            Arg::new("offline")
+                .long("offline")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Only use synced repositories and cached packages, never the network"),
+        )
         .subcommands([
             Command::new("get")
                 .short_flag('G')
                 .about("Download and install packages from an available repository")
                 .args([
                     Arg::new("packages")
-                        .help("Package(s) to get")
+                        .help("Package(s) to get, optionally with a version requirement like name@^1.2")
                         .action(ArgAction::Set)
                         .required(true)
                         .num_args(1..),
+                    Arg::new("overwrite")
+                        .long("overwrite")
+                        .value_name("GLOB")
+                        .help("Allow overwriting files matching the glob (repeatable)")
+                        .action(ArgAction::Append)
+                        .num_args(1)
+                        .value_hint(ValueHint::FilePath),
+                    Arg::new("no-scripts")
+                        .long("no-scripts")
+                        .action(ArgAction::SetTrue)
+                        .help("Skip the system commands of PKGFILEs"),
                     Arg::new("force")
                         .long("force")
                         .short('f')
                         .action(ArgAction::SetTrue)
                         .help("Agree to all prompts"),
+                    Arg::new("no-wait")
+                        .long("no-wait")
+                        .action(ArgAction::SetTrue)
+                        .help("Fail instead of waiting if another aati process is running"),
                     Arg::new("quiet")
                         .long("quiet")
                         .short('q')
@@ -113,11 +138,49 @@ as published by the Free Software Foundation.",
                         .action(ArgAction::Set)
                         .conflicts_with("package")
                         .num_args(1),
+                    Arg::new("sha256")
+                        .long("sha256")
+                        .value_name("HEX")
+                        .help("SHA256 checksum the package must match [default: from <package>.sha256 if it exists]")
+                        .action(ArgAction::Set)
+                        .conflicts_with("pkgfile")
+                        .num_args(1),
+                    Arg::new("sig")
+                        .long("sig")
+                        .value_name("FILE")
+                        .help("Detached signature of the package, as made by: $ aati sign")
+                        .action(ArgAction::Set)
+                        .conflicts_with("pkgfile")
+                        .requires("key")
+                        .num_args(1)
+                        .value_hint(ValueHint::FilePath),
+                    Arg::new("key")
+                        .long("key")
+                        .value_name("PUBKEY")
+                        .help("Hex encoded Ed25519 public key the package must be signed with")
+                        .action(ArgAction::Set)
+                        .requires("sig")
+                        .num_args(1),
+                    Arg::new("overwrite")
+                        .long("overwrite")
+                        .value_name("GLOB")
+                        .help("Allow overwriting files matching the glob (repeatable)")
+                        .action(ArgAction::Append)
+                        .num_args(1)
+                        .value_hint(ValueHint::FilePath),
+                    Arg::new("no-scripts")
+                        .long("no-scripts")
+                        .action(ArgAction::SetTrue)
+                        .help("Skip the system commands of PKGFILEs"),
                     Arg::new("force")
                         .long("force")
                         .short('f')
                         .action(ArgAction::SetTrue)
                         .help("Agree to all prompts"),
+                    Arg::new("no-wait")
+                        .long("no-wait")
+                        .action(ArgAction::SetTrue)
+                        .help("Fail instead of waiting if another aati process is running"),
                     Arg::new("quiet")
                         .long("quiet")
                         .short('q')
@@ -133,11 +196,54 @@ as published by the Free Software Foundation.",
                         .help("Package(s) to upgrade")
                         .action(ArgAction::Set)
                         .num_args(1..),
+                    Arg::new("allow-downgrade")
+                        .long("allow-downgrade")
+                        .action(ArgAction::SetTrue)
+                        .help("Install older versions if those are the latest ones the repository has"),
+                    Arg::new("no-scripts")
+                        .long("no-scripts")
+                        .action(ArgAction::SetTrue)
+                        .help("Skip the system commands of PKGFILEs"),
                     Arg::new("force")
                         .long("force")
                         .short('f')
                         .action(ArgAction::SetTrue)
                         .help("Agree to all prompts"),
+                    Arg::new("no-wait")
+                        .long("no-wait")
+                        .action(ArgAction::SetTrue)
+                        .help("Fail instead of waiting if another aati process is running"),
+                    Arg::new("quiet")
+                        .long("quiet")
+                        .short('q')
+                        .action(ArgAction::SetTrue)
+                        .help("Show the least output possible"),
+                ]),
+            Command::new("downgrade")
+                .about("Replace an installed package with an older version")
+                .args([
+                    Arg::new("package")
+                        .help("Package to downgrade")
+                        .action(ArgAction::Set)
+                        .required(true),
+                    Arg::new("version")
+                        .long("version")
+                        .action(ArgAction::Set)
+                        .value_name("TAG")
+                        .help("Version to install instead (default: the one before the installed version)"),
+                    Arg::new("no-scripts")
+                        .long("no-scripts")
+                        .action(ArgAction::SetTrue)
+                        .help("Skip the system commands of PKGFILEs"),
+                    Arg::new("force")
+                        .long("force")
+                        .short('f')
+                        .action(ArgAction::SetTrue)
+                        .help("Agree to all prompts"),
+                    Arg::new("no-wait")
+                        .long("no-wait")
+                        .action(ArgAction::SetTrue)
+                        .help("Fail instead of waiting if another aati process is running"),
                     Arg::new("quiet")
                         .long("quiet")
                         .short('q')
@@ -164,12 +270,113 @@ as published by the Free Software Foundation.",
                         .long("lock")
                         .short('l')
                         .action(ArgAction::SetTrue)
-                        .help("Remove from lockfile"),
+                        .help("Only remove from lockfile, without checking for dependents"),
+                    Arg::new("cascade")
+                        .long("cascade")
+                        .short('c')
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("lock")
+                        .help("Also remove the packages that depend on the given ones"),
+                    Arg::new("no-scripts")
+                        .long("no-scripts")
+                        .action(ArgAction::SetTrue)
+                        .help("Skip the system commands of PKGFILEs"),
                     Arg::new("force")
                         .long("force")
                         .short('f')
                         .action(ArgAction::SetTrue)
                         .help("Agree to all prompts"),
+                    Arg::new("no-wait")
+                        .long("no-wait")
+                        .action(ArgAction::SetTrue)
+                        .help("Fail instead of waiting if another aati process is running"),
+                    Arg::new("quiet")
+                        .long("quiet")
+                        .short('q')
+                        .action(ArgAction::SetTrue)
+                        .help("Show the least output possible"),
+                ]),
+            Command::new("autoremove")
+                .short_flag('A')
+                .about("Remove automatically installed packages that are no longer needed")
+                .args([
+                    Arg::new("force")
+                        .long("force")
+                        .short('f')
+                        .action(ArgAction::SetTrue)
+                        .help("Agree to all prompts"),
+                    Arg::new("no-wait")
+                        .long("no-wait")
+                        .action(ArgAction::SetTrue)
+                        .help("Fail instead of waiting if another aati process is running"),
+                    Arg::new("quiet")
+                        .long("quiet")
+                        .short('q')
+                        .action(ArgAction::SetTrue)
+                        .help("Show the least output possible"),
+                ]),
+            Command::new("mark")
+                .short_flag('M')
+                .about("Change the reason installed packages are kept for")
+                .args([
+                    Arg::new("packages")
+                        .help("Package(s) to mark")
+                        .action(ArgAction::Set)
+                        .required(true)
+                        .num_args(1..),
+                    Arg::new("explicit")
+                        .long("explicit")
+                        .short('e')
+                        .action(ArgAction::SetTrue)
+                        .required_unless_present("auto")
+                        .conflicts_with("auto")
+                        .help("Mark as explicitly installed"),
+                    Arg::new("auto")
+                        .long("auto")
+                        .short('a')
+                        .action(ArgAction::SetTrue)
+                        .required_unless_present("explicit")
+                        .help("Mark as installed as a dependency"),
+                    Arg::new("no-wait")
+                        .long("no-wait")
+                        .action(ArgAction::SetTrue)
+                        .help("Fail instead of waiting if another aati process is running"),
+                    Arg::new("quiet")
+                        .long("quiet")
+                        .short('q')
+                        .action(ArgAction::SetTrue)
+                        .help("Show the least output possible"),
+                ]),
+            Command::new("hold")
+                .about("Keep installed packages at their current versions when upgrading")
+                .args([
+                    Arg::new("packages")
+                        .help("Package(s) to hold")
+                        .action(ArgAction::Set)
+                        .required(true)
+                        .num_args(1..),
+                    Arg::new("no-wait")
+                        .long("no-wait")
+                        .action(ArgAction::SetTrue)
+                        .help("Fail instead of waiting if another aati process is running"),
+                    Arg::new("quiet")
+                        .long("quiet")
+                        .short('q')
+                        .action(ArgAction::SetTrue)
+                        .help("Show the least output possible"),
+                ]),
+            Command::new("unhold")
+                .about("Let held packages be upgraded again")
+                .args([
+                    Arg::new("packages")
+                        .help("Package(s) to unhold")
+                        .action(ArgAction::Set)
+                        .required(true)
+                        .num_args(1..),
+                    Arg::new("no-wait")
+                        .long("no-wait")
+                        .action(ArgAction::SetTrue)
+                        .help("Fail instead of waiting if another aati process is running"),
                     Arg::new("quiet")
                         .long("quiet")
                         .short('q')
@@ -186,6 +393,25 @@ as published by the Free Software Foundation.",
                         .help("List available packages")
                         .action(ArgAction::SetTrue),
                 ),
+            Command::new("files")
+                .about("List the files an installed package owns")
+                .arg(
+                    Arg::new("package")
+                        .help("Package name")
+                        .action(ArgAction::Set)
+                        .required(true)
+                        .num_args(1),
+                ),
+            Command::new("owns")
+                .about("Find which installed package owns a file")
+                .arg(
+                    Arg::new("path")
+                        .help("Path of the file")
+                        .action(ArgAction::Set)
+                        .required(true)
+                        .num_args(1)
+                        .value_hint(ValueHint::FilePath),
+                ),
             Command::new("sync")
                 .short_flag('S')
                 .about("Sync repository manifests")
@@ -195,12 +421,41 @@ as published by the Free Software Foundation.",
                         .required(false)
                         .num_args(1..)
                         .help("Selected repositories to sync"),
+                    Arg::new("no-wait")
+                        .long("no-wait")
+                        .action(ArgAction::SetTrue)
+                        .help("Fail instead of waiting if another aati process is running"),
                     Arg::new("quiet")
                         .long("quiet")
                         .short('q')
                         .action(ArgAction::SetTrue)
                         .help("Show the least output possible"),
                 ]),
+            Command::new("cache")
+                .about("Manage downloaded packages kept in the cache")
+                .subcommand_required(true)
+                .subcommands([
+                    Command::new("list").about("List cached packages"),
+                    Command::new("clean")
+                        .about("Delete cached packages")
+                        .args([
+                            Arg::new("keep")
+                                .long("keep")
+                                .action(ArgAction::Set)
+                                .value_name("N")
+                                .value_parser(clap::value_parser!(usize))
+                                .help("Keep the newest N versions of each package"),
+                            Arg::new("no-wait")
+                                .long("no-wait")
+                                .action(ArgAction::SetTrue)
+                                .help("Fail instead of waiting if another aati process is running"),
+                            Arg::new("quiet")
+                                .long("quiet")
+                                .short('q')
+                                .action(ArgAction::SetTrue)
+                                .help("Show the least output possible"),
+                        ]),
+                ]),
             Command::new("repo")
                 .short_flag('P')
                 .about("Manage repositories")
@@ -216,6 +471,15 @@ as published by the Free Software Foundation.",
                                 .required(true)
                                 .num_args(1..)
                                 .value_hint(ValueHint::Url),
+                            Arg::new("key")
+                                .long("key")
+                                .value_name("PUBKEY")
+                                .action(ArgAction::Set)
+                                .help("Hex encoded Ed25519 public key the repository's manifest must be signed with"),
+                            Arg::new("no-wait")
+                                .long("no-wait")
+                                .action(ArgAction::SetTrue)
+                                .help("Fail instead of waiting if another aati process is running"),
                             Arg::new("quiet")
                                 .long("quiet")
                                 .short('q')
@@ -242,6 +506,14 @@ as published by the Free Software Foundation.",
                                 .action(ArgAction::SetTrue)
                                 .conflicts_with("names")
                                 .help("Remove all repositories"),
+                            Arg::new("cascade")
+                                .long("cascade")
+                                .action(ArgAction::SetTrue)
+                                .help("Also remove packages from other repositories that depend on this one's"),
+                            Arg::new("no-wait")
+                                .long("no-wait")
+                                .action(ArgAction::SetTrue)
+                                .help("Fail instead of waiting if another aati process is running"),
                             Arg::new("quiet")
                                 .long("quiet")
                                 .short('q')
@@ -406,6 +678,44 @@ as published by the Free Software Foundation.",
                         .help("Path to the repo.toml file")
                         .value_hint(ValueHint::FilePath),
                 ]),
+            Command::new("keygen")
+                .about("Generate a keypair for signing a repository and its packages")
+                .args([
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Where to write the private key [default: ~/.aati/signing.key]")
+                        .value_hint(ValueHint::FilePath),
+                    Arg::new("quiet")
+                        .long("quiet")
+                        .short('q')
+                        .action(ArgAction::SetTrue)
+                        .help("Only print the public key"),
+                ]),
+            Command::new("sign")
+                .about("Sign a .tar.lz4 package or a repo.toml, writing the signature to <file>.sig")
+                .args([
+                    Arg::new("file")
+                        .help("Path of the file to sign")
+                        .action(ArgAction::Set)
+                        .required(true)
+                        .num_args(1)
+                        .value_hint(ValueHint::FilePath),
+                    Arg::new("key")
+                        .long("key")
+                        .short('k')
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .help("Path of the private key [default: ~/.aati/signing.key]")
+                        .value_hint(ValueHint::FilePath),
+                    Arg::new("quiet")
+                        .long("quiet")
+                        .short('q')
+                        .action(ArgAction::SetTrue)
+                        .help("Only print the signature"),
+                ]),
             Command::new("completions")
                 .short_flag('O')
                 .about("Generate tab-completion scripts for your shell")
@@ -418,117 +728,303 @@ as published by the Free Software Foundation.",
                 ),
         ]);
 
-    match cli.clone().get_matches().subcommand() {
+    if let Err(error) = run(&mut cli) {
+        eprintln!("{}", format!("- {}", error).bright_red());
+        exit(1);
+    }
+}
+
+// Dispatch the parsed command line to the library
+fn run(cli: &mut Command) -> Result<(), AatiError> {
+    let matches = cli.clone().get_matches();
+
+    if matches.get_flag("offline") {
+        set_offline();
+    }
+
+    match matches.subcommand() {
         Some(("get", get_matches)) => {
+            let _lock = lock_aati_home(get_matches.get_flag("no-wait"))?;
+
+            if get_matches.get_flag("no-scripts") {
+                set_no_scripts();
+            }
+
             let force = get_matches.get_flag("force");
             let quiet = get_matches.get_flag("quiet");
 
+            let overwrite: Vec<String> = get_matches
+                .get_many::<String>("overwrite")
+                .unwrap_or_default()
+                .map(|s| s.into())
+                .collect();
+
             let packages = get_matches.get_many::<String>("packages").unwrap();
             let packages_vec: Vec<String> = packages.map(|s| s.into()).collect::<Vec<_>>();
-            for package in packages_vec {
-                get::command(&package, force, quiet);
+
+            // Find everything once, download it at once, then install one
+            // package at a time
+            let resolved_packages = get::resolve(&packages_vec)?;
+            let extracted_packages: Vec<Vec<String>> = resolved_packages
+                .iter()
+                .map(|resolved_package| resolved_package.extracted_package.clone())
+                .collect();
+
+            if !get::prefetch(&extracted_packages, force, quiet)? {
+                return Ok(());
             }
+
+            for resolved_package in &resolved_packages {
+                get::command(
+                    &resolved_package.extracted_package,
+                    resolved_package.requirement.as_deref(),
+                    InstallReason::Explicit,
+                    &overwrite,
+                    force,
+                    quiet,
+                )?;
+            }
+
+            cache::trim(quiet);
         }
         Some(("install", install_matches)) => {
+            let _lock = lock_aati_home(install_matches.get_flag("no-wait"))?;
+
+            if install_matches.get_flag("no-scripts") {
+                set_no_scripts();
+            }
+
             let force = install_matches.get_flag("force");
             let quiet = install_matches.get_flag("quiet");
 
+            let overwrite: Vec<String> = install_matches
+                .get_many::<String>("overwrite")
+                .unwrap_or_default()
+                .map(|s| s.into())
+                .collect();
+
             if let Some(package) = install_matches.get_one::<String>("package") {
-                install::command(package, force, quiet);
+                let sha256 = install_matches.get_one::<String>("sha256");
+                let signature = install_matches
+                    .get_one::<String>("sig")
+                    .zip(install_matches.get_one::<String>("key"))
+                    .map(|(sig, key)| (sig.as_str(), key.as_str()));
+
+                install::command(
+                    package,
+                    sha256.map(|s| s.as_str()),
+                    signature,
+                    &overwrite,
+                    force,
+                    quiet,
+                )?;
             } else {
                 let pkgfile = install_matches.get_one::<String>("pkgfile").unwrap();
                 let name_option = install_matches.get_one::<String>("name");
                 let version_option = install_matches.get_one::<String>("version");
 
-                match install::use_pkgfile(pkgfile, name_option, version_option, force, quiet) {
-                    Ok(_) => {}
-                    Err(error) => {
-                        eprintln!("{}", format!("- {}", error).bright_red());
-                    }
-                }
+                install::use_pkgfile(
+                    pkgfile,
+                    name_option,
+                    version_option,
+                    &overwrite,
+                    force,
+                    quiet,
+                )?;
             }
+
+            // For the dependencies that were got from repositories
+            cache::trim(quiet);
         }
         Some(("upgrade", upgrade_matches)) => {
+            let _lock = lock_aati_home(upgrade_matches.get_flag("no-wait"))?;
+
+            if upgrade_matches.get_flag("no-scripts") {
+                set_no_scripts();
+            }
+
+            let allow_downgrade = upgrade_matches.get_flag("allow-downgrade");
             let force = upgrade_matches.get_flag("force");
             let quiet = upgrade_matches.get_flag("quiet");
 
             if let Some(packages) = upgrade_matches.get_many::<String>("packages") {
                 let packages_vec: Vec<&str> = packages.map(|s| s.as_str()).collect::<Vec<_>>();
                 for package in packages_vec {
-                    upgrade::command(Some(package), force, quiet);
+                    upgrade::command(Some(package), allow_downgrade, force, quiet)?;
                 }
             } else {
-                upgrade::command(None, force, quiet);
+                upgrade::command(None, allow_downgrade, force, quiet)?;
+            }
+
+            cache::trim(quiet);
+        }
+        Some(("downgrade", downgrade_matches)) => {
+            let _lock = lock_aati_home(downgrade_matches.get_flag("no-wait"))?;
+
+            if downgrade_matches.get_flag("no-scripts") {
+                set_no_scripts();
             }
+
+            let force = downgrade_matches.get_flag("force");
+            let quiet = downgrade_matches.get_flag("quiet");
+
+            let package = downgrade_matches.get_one::<String>("package").unwrap();
+            let version = downgrade_matches.get_one::<String>("version");
+
+            downgrade::command(package, version.map(|v| v.as_str()), force, quiet)?;
+
+            cache::trim(quiet);
         }
         Some(("remove", remove_matches)) => {
+            let _lock = lock_aati_home(remove_matches.get_flag("no-wait"))?;
+
+            if remove_matches.get_flag("no-scripts") {
+                set_no_scripts();
+            }
+
             let lock_flag = remove_matches.get_flag("lock");
+            let cascade_flag = remove_matches.get_flag("cascade");
             let force_flag = remove_matches.get_flag("force");
             let quiet_flag = remove_matches.get_flag("quiet");
 
             if remove_matches.get_flag("all") {
-                commands::remove(None, lock_flag, force_flag, quiet_flag);
+                commands::remove(None, lock_flag, cascade_flag, force_flag, quiet_flag)?;
             } else {
                 let packages = remove_matches.get_many::<String>("packages").unwrap();
                 let packages_vec: Vec<String> = packages.map(|s| s.to_owned()).collect::<Vec<_>>();
 
-                commands::remove(Some(packages_vec), lock_flag, force_flag, quiet_flag);
+                commands::remove(
+                    Some(packages_vec),
+                    lock_flag,
+                    cascade_flag,
+                    force_flag,
+                    quiet_flag,
+                )?;
             }
         }
+        Some(("autoremove", autoremove_matches)) => {
+            let _lock = lock_aati_home(autoremove_matches.get_flag("no-wait"))?;
+
+            let force = autoremove_matches.get_flag("force");
+            let quiet = autoremove_matches.get_flag("quiet");
+
+            autoremove::command(force, quiet)?;
+        }
+        Some(("mark", mark_matches)) => {
+            let _lock = lock_aati_home(mark_matches.get_flag("no-wait"))?;
+
+            let quiet = mark_matches.get_flag("quiet");
+
+            let packages = mark_matches.get_many::<String>("packages").unwrap();
+            let packages_vec: Vec<String> = packages.map(|s| s.to_owned()).collect::<Vec<_>>();
+
+            let reason = if mark_matches.get_flag("auto") {
+                InstallReason::Automatic
+            } else {
+                InstallReason::Explicit
+            };
+
+            mark::command(packages_vec, reason, quiet)?;
+        }
+        Some((subcommand @ ("hold" | "unhold"), hold_matches)) => {
+            let _lock = lock_aati_home(hold_matches.get_flag("no-wait"))?;
+
+            let quiet = hold_matches.get_flag("quiet");
+
+            let packages = hold_matches.get_many::<String>("packages").unwrap();
+            let packages_vec: Vec<String> = packages.map(|s| s.to_owned()).collect::<Vec<_>>();
+
+            hold::command(packages_vec, subcommand == "hold", quiet)?;
+        }
         Some(("list", list_matches)) => {
             if list_matches.get_flag("available") {
-                list::available()
+                list::available()?
             } else {
-                list::installed();
+                list::installed()?;
             }
         }
+        Some(("files", files_matches)) => {
+            let package = files_matches.get_one::<String>("package").unwrap();
+
+            files::command(package)?;
+        }
+        Some(("owns", owns_matches)) => {
+            let path = owns_matches.get_one::<String>("path").unwrap();
+
+            owns::command(path)?;
+        }
         Some(("sync", sync_matches)) => {
+            let _lock = lock_aati_home(sync_matches.get_flag("no-wait"))?;
+
             let quiet = sync_matches.get_flag("quiet");
 
             if let Some(repos) = sync_matches.get_many::<String>("repos") {
                 let repos_vec = repos.map(|r| r.to_owned()).collect::<Vec<_>>();
-                sync::command(Some(repos_vec), quiet);
+                sync::command(Some(repos_vec), quiet)?;
             } else {
-                sync::command(None, quiet);
+                sync::command(None, quiet)?;
             }
         }
+        Some(("cache", cache_matches)) => match cache_matches.subcommand() {
+            Some(("list", _)) => {
+                cache::list()?;
+            }
+
+            Some(("clean", clean_matches)) => {
+                let _lock = lock_aati_home(clean_matches.get_flag("no-wait"))?;
+
+                let quiet = clean_matches.get_flag("quiet");
+                let keep = clean_matches.get_one::<usize>("keep").copied();
+
+                cache::clean(keep, quiet)?;
+            }
+
+            _ => unreachable!(),
+        },
         Some(("repo", repo_matches)) => match repo_matches.subcommand() {
             Some(("add", add_matches)) => {
+                let _lock = lock_aati_home(add_matches.get_flag("no-wait"))?;
+
                 let quiet = add_matches.get_flag("quiet");
+                let key = add_matches.get_one::<String>("key").map(|key| key.as_str());
 
                 let repository_urls = add_matches.get_many::<String>("urls").unwrap();
                 let repository_urls_vec: Vec<String> =
                     repository_urls.map(|s| s.into()).collect::<Vec<_>>();
 
                 for repository_url in repository_urls_vec {
-                    repo::add(repository_url, quiet);
+                    repo::add(repository_url, key, quiet)?;
                 }
             }
 
             Some(("remove", remove_matches)) => {
+                let _lock = lock_aati_home(remove_matches.get_flag("no-wait"))?;
+
                 let force = remove_matches.get_flag("force");
                 let quiet = remove_matches.get_flag("quiet");
 
+                let cascade = remove_matches.get_flag("cascade");
+
                 if remove_matches.get_flag("all") {
-                    repo::remove(None, force, quiet)
+                    repo::remove(None, cascade, force, quiet)?
                 } else {
                     let repository_names = remove_matches.get_many::<String>("names").unwrap();
                     let repository_names_vec: Vec<String> =
                         repository_names.map(|s| s.into()).collect::<Vec<_>>();
 
                     for repository_name in repository_names_vec {
-                        repo::remove(Some(repository_name), force, quiet);
+                        repo::remove(Some(repository_name), cascade, force, quiet)?;
                     }
                 }
             }
 
             Some(("info", info_matches)) => {
                 let repository_url = info_matches.get_one::<String>("name").unwrap();
-                repo::info(repository_url.into());
+                repo::info(repository_url.into())?;
             }
 
             Some(("list", _)) => {
-                repo::list();
+                repo::list()?;
             }
 
             Some(("init", init_matches)) => {
@@ -545,29 +1041,29 @@ as published by the Free Software Foundation.",
                     repo_description.into(),
                     repo_directory.into(),
                     quiet,
-                );
+                )?;
             }
 
             _ => unreachable!(),
         },
         Some(("query", query_matches)) => {
             let package_name = query_matches.get_one::<String>("package").unwrap();
-            query::command(package_name, None);
+            query::command(package_name, None)?;
         }
         Some(("changelog", changelog_matches)) => {
             let latest_only = changelog_matches.get_flag("latest");
 
             if let Some(package_name) = changelog_matches.get_one::<String>("package") {
-                changelog(Some(package_name), latest_only);
+                changelog(Some(package_name), latest_only)?;
             } else {
-                changelog(None, latest_only);
+                changelog(None, latest_only)?;
             }
         }
         Some(("package", package_matches)) => {
             let quiet = package_matches.get_flag("quiet");
 
             let directory_name = package_matches.get_one::<String>("directory").unwrap();
-            package::command(directory_name.into(), quiet);
+            package::command(directory_name.into(), quiet)?;
         }
         Some(("generate", generate_matches)) => {
             let quiet = generate_matches.get_flag("quiet");
@@ -581,7 +1077,32 @@ as published by the Free Software Foundation.",
                 manifest_path.into(),
                 output_directory.into(),
                 quiet,
-            );
+            )?;
+        }
+        Some(("keygen", keygen_matches)) => {
+            let quiet = keygen_matches.get_flag("quiet");
+
+            let key_path = match keygen_matches.get_one::<String>("output") {
+                Some(output) => output.into(),
+                None => {
+                    check_aati_dirs()?;
+
+                    get_signing_key_path_buf()
+                }
+            };
+
+            sign::keygen(key_path, quiet)?;
+        }
+        Some(("sign", sign_matches)) => {
+            let quiet = sign_matches.get_flag("quiet");
+
+            let file_path = sign_matches.get_one::<String>("file").unwrap();
+            let key_path = match sign_matches.get_one::<String>("key") {
+                Some(key) => key.into(),
+                None => get_signing_key_path_buf(),
+            };
+
+            sign::command(file_path.into(), key_path, quiet)?;
         }
         Some(("serve", serve_matches)) => {
             let host = serve_matches.get_one::<String>("host").unwrap();
@@ -589,18 +1110,18 @@ as published by the Free Software Foundation.",
             let repo_url = serve_matches.get_one::<String>("repo").unwrap();
             let manifest_path = serve_matches.get_one::<String>("manifest").unwrap();
 
-            serve::command(host, port, repo_url, manifest_path.into());
+            serve::command(host, port, repo_url, manifest_path.into())?;
         }
         Some(("completions", completions_matches)) => {
             let shell = completions_matches.get_one::<String>("shell").unwrap();
 
             match shell.as_str() {
-                "bash" => clap_complete::generate(Shell::Bash, &mut cli, "aati", &mut stdout()),
-                "zsh" => clap_complete::generate(Shell::Zsh, &mut cli, "aati", &mut stdout()),
-                "fish" => clap_complete::generate(Shell::Fish, &mut cli, "aati", &mut stdout()),
-                "elvish" => clap_complete::generate(Shell::Elvish, &mut cli, "aati", &mut stdout()),
+                "bash" => clap_complete::generate(Shell::Bash, cli, "aati", &mut stdout()),
+                "zsh" => clap_complete::generate(Shell::Zsh, cli, "aati", &mut stdout()),
+                "fish" => clap_complete::generate(Shell::Fish, cli, "aati", &mut stdout()),
+                "elvish" => clap_complete::generate(Shell::Elvish, cli, "aati", &mut stdout()),
                 "powershell" => {
-                    clap_complete::generate(Shell::PowerShell, &mut cli, "aati", &mut stdout())
+                    clap_complete::generate(Shell::PowerShell, cli, "aati", &mut stdout())
                 }
                 _ => unreachable!(),
             }
@@ -608,6 +1129,8 @@ as published by the Free Software Foundation.",
 
         _ => unreachable!(),
     }
+
+    Ok(())
 }
 
 // تم بحمد الله
@@ -0,0 +1,228 @@
+/* بسم الله الرحمن الرحيم
+
+   Aati - Cross-platform Package Manager written in Rust.
+   Copyright (C) 2023  Husayn Haras <haras@disroot.org>
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of version 3 of the GNU General Public License
+   as published by the Free Software Foundation.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use humansize::{format_size, BINARY};
+use std::{
+    io::{stdout, IsTerminal, Read, Result, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const BAR_WIDTH: usize = 30;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+// A progress bar for downloads, drawn on a single line of stdout. It can be
+// shared by the workers downloading packages at the same time.
+pub struct Progress {
+    total: Option<u64>,
+    done: AtomicU64,
+    // Bytes that were already there from an earlier session, which don't
+    // count towards the download rate
+    resumed: AtomicU64,
+    started: Instant,
+    last_drawn: Mutex<Option<Instant>>,
+    is_enabled: bool,
+}
+
+impl Progress {
+    // Nothing is drawn with --quiet or when stdout isn't a terminal
+    pub fn new(total: Option<u64>, quiet: bool) -> Self {
+        Progress {
+            total: total.filter(|total| *total > 0),
+            done: AtomicU64::new(0),
+            resumed: AtomicU64::new(0),
+            started: Instant::now(),
+            last_drawn: Mutex::new(None),
+            is_enabled: !quiet && stdout().is_terminal(),
+        }
+    }
+
+    // Start further along, for a download that's resumed
+    pub fn resume(&self, bytes: u64) {
+        self.resumed.fetch_add(bytes, Ordering::Relaxed);
+        self.advance(bytes);
+    }
+
+    // Take back what a download counted, for one that's started over
+    pub fn rewind(&self, bytes: u64, resumed: u64) {
+        self.done.fetch_sub(bytes, Ordering::Relaxed);
+        self.resumed.fetch_sub(resumed, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, bytes: u64) {
+        let done = self.done.fetch_add(bytes, Ordering::Relaxed) + bytes;
+
+        if !self.is_enabled {
+            return;
+        }
+
+        let mut last_drawn = self.last_drawn.lock().unwrap();
+
+        if last_drawn.is_some_and(|last_drawn| last_drawn.elapsed() < REDRAW_INTERVAL) {
+            return;
+        }
+
+        *last_drawn = Some(Instant::now());
+
+        let mut stdout = stdout().lock();
+        let _ = write!(
+            stdout,
+            "\r\x1b[2K{}",
+            format_progress(
+                done,
+                self.resumed.load(Ordering::Relaxed),
+                self.total,
+                self.started.elapsed()
+            )
+        );
+        let _ = stdout.flush();
+    }
+
+    // Print a line without mixing it up with the bar, which is drawn again
+    // on the next advance
+    pub fn println(&self, line: &str) {
+        let last_drawn = self.last_drawn.lock().unwrap();
+
+        if self.is_enabled && last_drawn.is_some() {
+            print!("\r\x1b[2K");
+        }
+
+        println!("{}", line);
+    }
+
+    // Draw the final state of the bar and move past it
+    pub fn finish(&self) {
+        let last_drawn = self.last_drawn.lock().unwrap();
+
+        if self.is_enabled && last_drawn.is_some() {
+            println!(
+                "\r\x1b[2K{}",
+                format_progress(
+                    self.done.load(Ordering::Relaxed),
+                    self.resumed.load(Ordering::Relaxed),
+                    self.total,
+                    self.started.elapsed()
+                )
+            );
+        }
+    }
+}
+
+// Counts the bytes read through it towards a Progress
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a Progress,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub fn new(inner: R, progress: &'a Progress) -> Self {
+        ProgressReader { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.advance(read as u64);
+
+        Ok(read)
+    }
+}
+
+pub fn format_progress(done: u64, resumed: u64, total: Option<u64>, elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs_f64();
+    let throughput = if seconds > 0.0 {
+        (done.saturating_sub(resumed) as f64 / seconds) as u64
+    } else {
+        0
+    };
+
+    match total {
+        Some(total) => {
+            let ratio = (done as f64 / total as f64).min(1.0);
+            let filled = (ratio * BAR_WIDTH as f64) as usize;
+
+            let eta = match total.saturating_sub(done).checked_div(throughput) {
+                Some(seconds) => format!("{}s", seconds),
+                None => "?".into(),
+            };
+
+            format!(
+                "[{}{}] {:>3}% {}/{} {}/s ETA {}",
+                "#".repeat(filled),
+                " ".repeat(BAR_WIDTH - filled),
+                (ratio * 100.0) as u64,
+                format_size(done, BINARY),
+                format_size(total, BINARY),
+                format_size(throughput, BINARY),
+                eta
+            )
+        }
+
+        None => format!(
+            "{} {}/s",
+            format_size(done, BINARY),
+            format_size(throughput, BINARY)
+        ),
+    }
+}
+
+#[test]
+fn test_format_progress() {
+    assert_eq!(
+        format_progress(512, 0, Some(2048), Duration::from_secs(2)),
+        format!(
+            "[{}{}]  25% 512 B/2 KiB 256 B/s ETA 6s",
+            "#".repeat(7),
+            " ".repeat(23)
+        )
+    );
+
+    assert_eq!(
+        format_progress(1024, 0, None, Duration::from_secs(1)),
+        "1 KiB 1 KiB/s"
+    );
+
+    assert!(format_progress(0, 0, Some(100), Duration::ZERO).ends_with("ETA ?"));
+
+    // Only what was downloaded in this session counts towards the rate
+    assert_eq!(
+        format_progress(1536, 1024, Some(2048), Duration::from_secs(2)),
+        format!(
+            "[{}{}]  75% 1.50 KiB/2 KiB 256 B/s ETA 2s",
+            "#".repeat(22),
+            " ".repeat(8)
+        )
+    );
+}
+
+#[test]
+fn test_rewind() {
+    let progress = Progress::new(Some(100), true);
+
+    progress.resume(40);
+    progress.advance(60);
+    progress.rewind(100, 40);
+    progress.advance(100);
+
+    assert_eq!(progress.done.load(Ordering::Relaxed), 100);
+    assert_eq!(progress.resumed.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,339 @@
+/* بسم الله الرحمن الرحيم
+
+   Aati - Cross-platform Package Manager written in Rust.
+   Copyright (C) 2023  Husayn Haras <haras@disroot.org>
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of version 3 of the GNU General Public License
+   as published by the Free Software Foundation.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU General Public License for more details.
+
+   You should have received a copy of the GNU General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use colored::Colorize;
+use std::{
+    env::temp_dir,
+    fmt,
+    fs::{copy, create_dir_all, remove_dir_all, remove_file},
+    io,
+    path::PathBuf,
+    process::{self, ExitStatus},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Why a PKGFILE line failed to execute. By the time one of these is returned
+// every filesystem change made by the previous lines has been undone.
+#[derive(Debug)]
+pub enum ExecutionError {
+    Install {
+        destination: PathBuf,
+        error: io::Error,
+    },
+    Copy {
+        destination: PathBuf,
+        error: io::Error,
+    },
+    Delete {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Metadata {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Permissions {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Backup {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Hash {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Spawn {
+        line: String,
+        error: io::Error,
+    },
+    Status {
+        line: String,
+        status: ExitStatus,
+    },
+    InvalidCommand {
+        line: String,
+    },
+    Timeout {
+        line: String,
+        seconds: u64,
+    },
+    Input {
+        line: String,
+        error: io::Error,
+    },
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecutionError::Install { destination, error } => write!(
+                f,
+                "FAILED TO WRITE INTO FILE '{}'! ERROR[91]: {}",
+                destination.display(),
+                error
+            ),
+            ExecutionError::Copy { destination, error } => write!(
+                f,
+                "FAILED TO WRITE INTO FILE '{}'! ERROR[100]: {}",
+                destination.display(),
+                error
+            ),
+            ExecutionError::Delete { path, error } => write!(
+                f,
+                "FAILED TO DELETE FILE {}! ERROR[92]: {}",
+                path.display(),
+                error
+            ),
+            ExecutionError::Metadata { path, error } => write!(
+                f,
+                "FAILED TO GET METADATA OF FILE '{}'! ERROR[42]: {}",
+                path.display(),
+                error
+            ),
+            ExecutionError::Permissions { path, error } => write!(
+                f,
+                "FAILED TO SET PERMISSIONS OF FILE '{}'! ERROR[43]: {}",
+                path.display(),
+                error
+            ),
+            ExecutionError::Backup { path, error } => write!(
+                f,
+                "FAILED TO BACK UP FILE '{}'! ERROR[107]: {}",
+                path.display(),
+                error
+            ),
+            ExecutionError::Hash { path, error } => write!(
+                f,
+                "FAILED TO HASH FILE '{}'! ERROR[109]: {}",
+                path.display(),
+                error
+            ),
+            ExecutionError::Spawn { line, error } => {
+                write!(
+                    f,
+                    "FAILED RUNNING COMMAND: '{}'! GIVEN ERROR: {}",
+                    line, error
+                )
+            }
+            ExecutionError::Status { line, status } => {
+                write!(f, "COMMAND '{}' FAILED WITH {}!", line, status)
+            }
+            ExecutionError::InvalidCommand { line } => {
+                write!(f, "INVALID PKGFILE COMMAND '{}'!", line)
+            }
+            ExecutionError::Timeout { line, seconds } => {
+                write!(f, "COMMAND '{}' TIMED OUT AFTER {} SECONDS!", line, seconds)
+            }
+            ExecutionError::Input { line, error } => {
+                write!(
+                    f,
+                    "DIDN'T RECEIVE VALID INPUT FOR COMMAND '{}'! ERROR[4]: {}",
+                    line, error
+                )
+            }
+        }
+    }
+}
+
+enum Effect {
+    Created(PathBuf),
+    Overwritten { path: PathBuf, backup: PathBuf },
+    Deleted { path: PathBuf, backup: PathBuf },
+}
+
+// Journal of the filesystem changes made while executing PKGFILE lines, so
+// they can be undone if a later line fails. Effects of `system` lines can't
+// be tracked and are left as they are.
+pub struct Transaction {
+    effects: Vec<Effect>,
+    backup_directory: PathBuf,
+    ran_system_commands: bool,
+}
+
+impl Transaction {
+    pub fn begin() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or(0);
+
+        Transaction {
+            effects: Vec::new(),
+            backup_directory: temp_dir().join(format!(
+                "aati-transaction-{}-{}",
+                process::id(),
+                nanos
+            )),
+            ran_system_commands: false,
+        }
+    }
+
+    // Call before running a `system` line, whose effects can't be undone
+    pub fn record_system_command(&mut self) {
+        self.ran_system_commands = true;
+    }
+
+    pub fn ran_system_commands(&self) -> bool {
+        self.ran_system_commands
+    }
+
+    // Call before writing into `path`: an existing file gets backed up,
+    // a new one gets deleted on rollback
+    pub fn prepare_write(&mut self, path: &PathBuf) -> Result<(), ExecutionError> {
+        if path.is_file() {
+            let backup = self.backup(path)?;
+
+            self.effects.push(Effect::Overwritten {
+                path: path.clone(),
+                backup,
+            });
+        } else {
+            self.effects.push(Effect::Created(path.clone()));
+        }
+
+        Ok(())
+    }
+
+    pub fn delete(&mut self, path: &PathBuf) -> Result<(), ExecutionError> {
+        let backup = self.backup(path)?;
+
+        match remove_file(path) {
+            Ok(_) => {
+                self.effects.push(Effect::Deleted {
+                    path: path.clone(),
+                    backup,
+                });
+
+                Ok(())
+            }
+
+            Err(error) => Err(ExecutionError::Delete {
+                path: path.clone(),
+                error,
+            }),
+        }
+    }
+
+    fn backup(&self, path: &PathBuf) -> Result<PathBuf, ExecutionError> {
+        let backup = self
+            .backup_directory
+            .join(format!("{}", self.effects.len()));
+
+        match create_dir_all(&self.backup_directory).and_then(|_| copy(path, &backup)) {
+            Ok(_) => Ok(backup),
+            Err(error) => Err(ExecutionError::Backup {
+                path: path.clone(),
+                error,
+            }),
+        }
+    }
+
+    // Undo every recorded change, newest first
+    pub fn rollback(mut self, quiet: bool) {
+        if !quiet && !self.effects.is_empty() {
+            eprintln!("{}", "+ Rolling back changes...".yellow());
+        }
+
+        while let Some(effect) = self.effects.pop() {
+            let (path, result) = match effect {
+                Effect::Created(path) => {
+                    let result = if path.exists() {
+                        remove_file(&path)
+                    } else {
+                        Ok(())
+                    };
+
+                    (path, result)
+                }
+
+                Effect::Overwritten { path, backup } | Effect::Deleted { path, backup } => {
+                    let result = copy(&backup, &path).map(|_| ());
+                    (path, result)
+                }
+            };
+
+            match result {
+                Ok(_) => {
+                    if !quiet {
+                        eprintln!("{}", format!("+   Restored '{}'", path.display()).yellow());
+                    }
+                }
+
+                Err(error) => {
+                    eprintln!(
+                        "{}",
+                        format!(
+                            "- FAILED TO RESTORE FILE '{}'! ERROR[108]: {}",
+                            path.display(),
+                            error
+                        )
+                        .bright_red()
+                    );
+                }
+            }
+        }
+
+        self.discard_backups();
+    }
+
+    pub fn commit(self) {
+        self.discard_backups();
+    }
+
+    fn discard_backups(&self) {
+        if self.backup_directory.exists() {
+            let _ = remove_dir_all(&self.backup_directory);
+        }
+    }
+}
+
+#[test]
+fn test_transaction_rollback() {
+    use std::fs::{read_to_string, write};
+
+    let directory = temp_dir().join(format!("aati-test-rollback-{}", process::id()));
+    create_dir_all(&directory).unwrap();
+
+    let overwritten = directory.join("overwritten");
+    let created = directory.join("created");
+    let deleted = directory.join("deleted");
+
+    write(&overwritten, "original").unwrap();
+    write(&deleted, "to be deleted").unwrap();
+
+    let mut transaction = Transaction::begin();
+
+    transaction.prepare_write(&overwritten).unwrap();
+    write(&overwritten, "changed").unwrap();
+
+    transaction.prepare_write(&created).unwrap();
+    write(&created, "new").unwrap();
+
+    transaction.delete(&deleted).unwrap();
+    assert!(!deleted.exists());
+
+    transaction.rollback(true);
+
+    assert_eq!(read_to_string(&overwritten).unwrap(), "original");
+    assert!(!created.exists());
+    assert_eq!(read_to_string(&deleted).unwrap(), "to be deleted");
+
+    remove_dir_all(&directory).unwrap();
+}
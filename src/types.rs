@@ -24,7 +24,18 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ConfigFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offline: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_commands: Option<SystemCommands>,
+    // How long a `system` line may run before it's killed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_timeout_secs: Option<u64>,
     pub sources: SourcesSection,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheSection>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downloads: Option<DownloadsSection>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -36,25 +47,107 @@ pub struct SourcesSection {
 pub struct Repo {
     pub name: String,
     pub url: String,
+    // Hex encoded Ed25519 public key the repository's manifest is signed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    // When the repository last moved to a new key, e.g. "2025-01-31 12:00:00 UTC"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_rotated: Option<String>,
+    // Overrides the global system_commands for packages from this repository
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_commands: Option<SystemCommands>,
 }
 
-// lock.toml
+// What's done with the `system` lines of a PKGFILE
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SystemCommands {
+    #[default]
+    Allow,
+    // Ask before running each one, even with --force
+    Prompt,
+    // Skip them
+    Deny,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CacheSection {
+    // Downloaded tarballs are kept until the cache grows past this size
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size_mib: Option<u64>,
+}
 
 #[derive(Debug, Deserialize, Serialize)]
+pub struct DownloadsSection {
+    // How many packages are downloaded at the same time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workers: Option<usize>,
+}
+
+// lock.toml
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct LockFile {
     pub package: Vec<Package>,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
 pub struct Package {
     pub name: String,
     pub version: String,
     pub target: String,
     pub source: String,
+    #[serde(default)]
+    pub reason: InstallReason,
+    // The version requirement the package was asked for with, e.g. "^14"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requirement: Option<String>,
+    // Held packages are left alone by `aati upgrade`
+    #[serde(default)]
+    pub held: bool,
+    // What the .tar.lz4 of a local package was verified against when it was
+    // installed, e.g. "sha256:<hex>", or "sha512:<hex>" if only its signature was
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    #[serde(default)]
+    pub files: Vec<InstalledFile>,
     pub pkgfile: Pkgfile,
 }
 
+// An installed package from the "testing" repository, for tests to build on
+#[cfg(test)]
+impl Package {
+    pub fn test(name: &str) -> Self {
+        Package {
+            name: name.into(),
+            version: "0.1.0".into(),
+            target: "any".into(),
+            source: "testing".into(),
+            ..Default::default()
+        }
+    }
+}
+
+// A file written by an `install` or `copy` line of the package's PKGFILE
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct InstalledFile {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+// Whether a package was asked for by the user or only pulled in as a dependency
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallReason {
+    #[default]
+    Explicit,
+    Automatic,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
 pub struct Pkgfile {
     pub data: HashMap<String, String>,
     pub installation_lines: Vec<String>,
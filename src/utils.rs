@@ -18,23 +18,112 @@
 
 use colored::Colorize;
 use dirs::home_dir;
+use glob::Pattern;
+use lz4::Decoder;
+use ring::{digest, signature};
+use semver::{Version, VersionReq};
 use std::{
+    cmp::Ordering,
     collections::HashMap,
-    fs::{copy, create_dir_all, read_to_string, remove_file, File},
-    io::{stdin, stdout, Write},
-    path::PathBuf,
-    process::{exit, Command, Stdio},
+    env::{self, temp_dir},
+    fs::{
+        copy, create_dir_all, read_to_string, remove_dir_all, remove_file, rename, DirBuilder,
+        File, OpenOptions, TryLockError,
+    },
+    io::{self, stdin, stdout, BufRead, IsTerminal, Read, Write},
+    path::{Component, Path, PathBuf},
+    process::{self, Command, Stdio},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use tar::{Archive, EntryType};
 use toml::Value;
 
-use super::types::Package;
+use super::types::{LockFile, Package};
 use crate::{
     config::{
-        AATI_DIRNAME, BIN_DIRNAME, CONFIG_FILENAME, LIB_DIRNAME, LOCK_FILENAME, REPOS_DIRNAME,
+        AATI_DIRNAME, BIN_DIRNAME, CACHE_DIRNAME, CONFIG_FILENAME, DEFAULT_SYSTEM_TIMEOUT_SECS,
+        LIB_DIRNAME, LOCK_FILENAME, PROCESS_LOCK_FILENAME, REPOS_DIRNAME, SIGNING_KEY_FILENAME,
+        SYSTEM_ENVIRONMENT,
     },
-    types::Pkgfile,
+    error::AatiError,
+    transaction::{ExecutionError, Transaction},
+    types::{InstallReason, InstalledFile, Pkgfile, SystemCommands},
 };
 
+// Set by the global --offline flag
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_offline() {
+    OFFLINE.store(true, AtomicOrdering::Relaxed);
+}
+
+// Set by --no-scripts
+static NO_SCRIPTS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_no_scripts() {
+    NO_SCRIPTS.store(true, AtomicOrdering::Relaxed);
+}
+
+// How the `system` lines of a package's PKGFILE are run
+pub struct ScriptPolicy {
+    pub system_commands: SystemCommands,
+    pub timeout: Duration,
+}
+
+// The policy for packages from a repository, or "local" ones: --no-scripts
+// denies everything, otherwise the repository's system_commands in rc.toml
+// wins over the global one
+pub fn get_script_policy(source: &str) -> Result<ScriptPolicy, AatiError> {
+    let aati_config: Value = parse_aati_config(144)?;
+
+    let repo_policy = aati_config["sources"]["repos"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|repo| repo["name"].as_str().unwrap() == source)
+        .and_then(|repo| repo.get("system_commands"));
+
+    let system_commands = if NO_SCRIPTS.load(AtomicOrdering::Relaxed) {
+        SystemCommands::Deny
+    } else {
+        match repo_policy.or_else(|| aati_config.get("system_commands")) {
+            Some(policy) => match policy.clone().try_into() {
+                Ok(system_commands) => system_commands,
+                Err(_) => return Err(AatiError::InvalidPolicy(policy.to_string())),
+            },
+            None => SystemCommands::default(),
+        }
+    };
+
+    let timeout = aati_config
+        .get("system_timeout_secs")
+        .and_then(|timeout| timeout.as_integer())
+        .and_then(|timeout| u64::try_from(timeout).ok())
+        .unwrap_or(DEFAULT_SYSTEM_TIMEOUT_SECS);
+
+    Ok(ScriptPolicy {
+        system_commands,
+        timeout: Duration::from_secs(timeout),
+    })
+}
+
+// Whether aati must stay off the network, because of --offline or
+// `offline = true` in rc.toml
+pub fn is_offline() -> Result<bool, AatiError> {
+    if OFFLINE.load(AtomicOrdering::Relaxed) {
+        return Ok(true);
+    }
+
+    let aati_config: Value = parse_aati_config(145)?;
+
+    Ok(aati_config
+        .get("offline")
+        .and_then(|offline| offline.as_bool())
+        .unwrap_or(false))
+}
+
 // Get the host machine's target triple
 pub fn get_target() -> String {
     let version_metadata = rustc_version::version_meta().unwrap();
@@ -45,9 +134,102 @@ pub fn is_supported(target: &str) -> bool {
     target == get_target() || target == "any"
 }
 
+// Order two version tags, as semver when both of them parse as one and
+// component by component otherwise, so that tags like "1.2" or "2023.10"
+// still sort sensibly
+pub fn compare_versions(first: &str, second: &str) -> Ordering {
+    let parse = |tag: &str| Version::parse(tag.trim_start_matches('v'));
+
+    if let (Ok(first), Ok(second)) = (parse(first), parse(second)) {
+        return first.cmp(&second);
+    }
+
+    let components = |tag: &'_ str| -> Vec<String> {
+        tag.trim_start_matches('v')
+            .split(['.', '-', '+', '_'])
+            .map(|component| component.to_string())
+            .collect()
+    };
+
+    let first_components = components(first);
+    let second_components = components(second);
+
+    for (first, second) in first_components.iter().zip(&second_components) {
+        let ordering = match (first.parse::<u64>(), second.parse::<u64>()) {
+            (Ok(first), Ok(second)) => first.cmp(&second),
+            // A numeric component is newer than a textual one, like 1.0 > 1.0-beta
+            (Ok(_), Err(_)) => Ordering::Greater,
+            (Err(_), Ok(_)) => Ordering::Less,
+            (Err(_), Err(_)) => first.cmp(second),
+        };
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+
+    first_components.len().cmp(&second_components.len())
+}
+
+// Split a package specifier like "repo/tool@>=1.2,<2" into the package
+// and the version requirement after the '@'
+pub fn split_requirement(text: &str) -> (&str, Option<&str>) {
+    match text.split_once('@') {
+        Some((package, requirement)) => (package, Some(requirement)),
+        None => (text, None),
+    }
+}
+
+pub fn parse_requirement(requirement: &str) -> Result<VersionReq, AatiError> {
+    match VersionReq::parse(requirement) {
+        Ok(version_req) => Ok(version_req),
+        Err(error) => Err(AatiError::InvalidRequirement {
+            requirement: requirement.into(),
+            error,
+        }),
+    }
+}
+
+// The entry with the highest tag that satisfies the requirement. Tags that
+// aren't semver can't be checked against one, so they never match.
+pub fn get_matching_version<'a>(
+    versions: &'a [Value],
+    requirement: &VersionReq,
+) -> Option<&'a Value> {
+    versions
+        .iter()
+        .filter(|version| {
+            Version::parse(version["tag"].as_str().unwrap().trim_start_matches('v'))
+                .is_ok_and(|parsed_version| requirement.matches(&parsed_version))
+        })
+        .reduce(|latest, version| {
+            match compare_versions(
+                version["tag"].as_str().unwrap(),
+                latest["tag"].as_str().unwrap(),
+            ) {
+                Ordering::Greater => version,
+                _ => latest,
+            }
+        })
+}
+
+// The entry with the highest tag out of a package's `versions` array,
+// regardless of the order the repository lists them in
+pub fn get_latest_version(versions: &[Value]) -> Option<&Value> {
+    versions.iter().reduce(|latest, version| {
+        match compare_versions(
+            version["tag"].as_str().unwrap(),
+            latest["tag"].as_str().unwrap(),
+        ) {
+            Ordering::Greater => version,
+            _ => latest,
+        }
+    })
+}
+
 // Create `.aati`, `.aati/bin`, `.aati/lib`, `.aati/repos` under
 // the user's home directory if they don't exist
-pub fn check_aati_dirs() {
+pub fn check_aati_dirs() -> Result<(), AatiError> {
     let home_dir = home_dir().unwrap();
 
     let aati_dir = home_dir.join(AATI_DIRNAME);
@@ -60,16 +242,11 @@ pub fn check_aati_dirs() {
             Ok(_) => {}
 
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO CREATE DIRECTORY '{}'! ERROR[20]: {}",
-                        &aati_dir.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-                exit(1);
+                return Err(AatiError::CreateDirectory {
+                    code: 20,
+                    path: aati_dir,
+                    error,
+                });
             }
         }
     }
@@ -79,16 +256,11 @@ pub fn check_aati_dirs() {
             Ok(_) => {}
 
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO CREATE DIRECTORY '{}'! ERROR[21]: {}",
-                        &repos_dir.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-                exit(1);
+                return Err(AatiError::CreateDirectory {
+                    code: 21,
+                    path: repos_dir,
+                    error,
+                });
             }
         }
     }
@@ -98,16 +270,11 @@ pub fn check_aati_dirs() {
             Ok(_) => {}
 
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO CREATE DIRECTORY '{}'! ERROR[22]: {}",
-                        &bin_dir.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-                exit(1);
+                return Err(AatiError::CreateDirectory {
+                    code: 22,
+                    path: bin_dir,
+                    error,
+                });
             }
         }
     }
@@ -117,56 +284,113 @@ pub fn check_aati_dirs() {
             Ok(_) => {}
 
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO CREATE DIRECTORY '{}'! ERROR[23]: {}",
-                        &lib_dir.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-                exit(1);
+                return Err(AatiError::CreateDirectory {
+                    code: 23,
+                    path: lib_dir,
+                    error,
+                });
             }
         }
     }
+
+    Ok(())
 }
 
 pub fn get_bin_path_buf() -> PathBuf {
-    check_aati_dirs();
-
     let home_dir = home_dir().unwrap();
 
     home_dir.join(AATI_DIRNAME).join(BIN_DIRNAME)
 }
 
 pub fn get_lib_path_buf() -> PathBuf {
-    check_aati_dirs();
-
     let home_dir = home_dir().unwrap();
 
     home_dir.join(AATI_DIRNAME).join(LIB_DIRNAME)
 }
 
-pub fn get_aati_config_path_buf() -> PathBuf {
-    check_aati_dirs();
+pub fn get_cache_path_buf() -> PathBuf {
+    let home_dir = home_dir().unwrap();
+
+    home_dir.join(AATI_DIRNAME).join(CACHE_DIRNAME)
+}
 
+pub fn get_aati_config_path_buf() -> PathBuf {
     let home_dir = home_dir().unwrap();
 
     home_dir.join(AATI_DIRNAME).join(CONFIG_FILENAME)
 }
 
 pub fn get_aati_lock_path_buf() -> PathBuf {
-    check_aati_dirs();
-
     let home_dir = home_dir().unwrap();
 
     home_dir.join(AATI_DIRNAME).join(LOCK_FILENAME)
 }
 
-pub fn get_repo_config_path_buf(repo_name: &str) -> PathBuf {
-    check_aati_dirs();
+pub fn get_signing_key_path_buf() -> PathBuf {
+    let home_dir = home_dir().unwrap();
+
+    home_dir.join(AATI_DIRNAME).join(SIGNING_KEY_FILENAME)
+}
+
+// Take an exclusive advisory lock on the aati home so that only one process
+// changes it at a time. It's released when the returned file is dropped.
+pub fn lock_aati_home(no_wait: bool) -> Result<File, AatiError> {
+    check_aati_dirs()?;
+
+    let lock_path_buf = home_dir()
+        .unwrap()
+        .join(AATI_DIRNAME)
+        .join(PROCESS_LOCK_FILENAME);
+
+    let lock_file = match OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path_buf)
+    {
+        Ok(file) => file,
+        Err(error) => {
+            return Err(AatiError::OpenFile {
+                code: 112,
+                path: lock_path_buf,
+                error,
+            });
+        }
+    };
+
+    match lock_file.try_lock() {
+        Ok(_) => return Ok(lock_file),
+        Err(TryLockError::WouldBlock) => {}
+        Err(TryLockError::Error(error)) => {
+            return Err(AatiError::LockFile {
+                code: 113,
+                path: lock_path_buf,
+                error,
+            })
+        }
+    }
+
+    if no_wait {
+        return Err(AatiError::Busy);
+    }
 
+    eprintln!(
+        "{}",
+        "+ Waiting for another aati process to finish...".yellow()
+    );
+
+    if let Err(error) = lock_file.lock() {
+        return Err(AatiError::LockFile {
+            code: 113,
+            path: lock_path_buf,
+            error,
+        });
+    }
+
+    Ok(lock_file)
+}
+
+pub fn get_repo_config_path_buf(repo_name: &str) -> PathBuf {
     let home_dir = home_dir().unwrap();
 
     home_dir
@@ -175,24 +399,20 @@ pub fn get_repo_config_path_buf(repo_name: &str) -> PathBuf {
         .join(format!("{}.toml", repo_name))
 }
 
-pub fn get_aati_lock() -> String {
+pub fn get_aati_lock() -> Result<String, AatiError> {
+    check_aati_dirs()?;
+
     let aati_lock_path_buf = get_aati_lock_path_buf();
 
     if !aati_lock_path_buf.exists() {
         let mut aati_lock_file = match File::create(&aati_lock_path_buf) {
             Ok(file) => file,
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO CREATE FILE '{}'! ERROR[22]: {}",
-                        &aati_lock_path_buf.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-
-                exit(1);
+                return Err(AatiError::CreateFile {
+                    code: 22,
+                    path: aati_lock_path_buf,
+                    error,
+                });
             }
         };
 
@@ -200,17 +420,11 @@ pub fn get_aati_lock() -> String {
         match writeln!(aati_lock_file, "{}", default_config) {
             Ok(_) => {}
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO WRITE INTO FILE '{}'! ERROR[24]: {}",
-                        &aati_lock_path_buf.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-
-                exit(1);
+                return Err(AatiError::WriteFile {
+                    code: 24,
+                    path: aati_lock_path_buf,
+                    error,
+                });
             }
         }
 
@@ -243,76 +457,52 @@ pub fn get_aati_lock() -> String {
     let aati_lock = match read_to_string(&aati_lock_path_buf) {
         Ok(content) => content,
         Err(error) => {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FAILED TO READ FILE '{}'! ERROR[23]: {}",
-                    aati_lock_path_buf.display(),
-                    error
-                )
-                .bright_red()
-            );
-
-            exit(1);
+            return Err(AatiError::ReadFile {
+                code: 23,
+                path: aati_lock_path_buf,
+                error,
+            });
         }
     };
 
-    aati_lock.trim().into()
+    Ok(aati_lock.trim().into())
 }
 
-pub fn get_repo_config(repo_name: &str) -> String {
+pub fn get_repo_config(repo_name: &str) -> Result<String, AatiError> {
     let repo_config_path_buf = get_repo_config_path_buf(repo_name);
 
     if !repo_config_path_buf.exists() {
-        eprintln!(
-            "{}",
-            format!(
-                "- Could not find repository manifest at '{}'! Try: $ aati repo add <repo url>",
-                repo_config_path_buf.display()
-            )
-            .bright_red()
-        );
-        exit(1)
+        return Err(AatiError::RepoNotFound(repo_config_path_buf));
     }
 
     let repo_config = match read_to_string(&repo_config_path_buf) {
         Ok(content) => content,
         Err(error) => {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FAILED TO READ FILE '{}'! ERROR[25]: {}",
-                    repo_config_path_buf.display(),
-                    error
-                )
-                .bright_red()
-            );
-
-            exit(1);
+            return Err(AatiError::ReadFile {
+                code: 25,
+                path: repo_config_path_buf,
+                error,
+            });
         }
     };
 
-    repo_config.trim().into()
+    Ok(repo_config.trim().into())
 }
 
-pub fn get_aati_config() -> Option<String> {
+pub fn get_aati_config() -> Result<String, AatiError> {
+    check_aati_dirs()?;
+
     let aati_config_path_buf = get_aati_config_path_buf();
 
     if !aati_config_path_buf.exists() {
         let mut aati_config_file = match File::create(&aati_config_path_buf) {
             Ok(file) => file,
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO CREATE FILE '{}'! ERROR[26]: {}",
-                        &aati_config_path_buf.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-
-                exit(1);
+                return Err(AatiError::CreateFile {
+                    code: 26,
+                    path: aati_config_path_buf,
+                    error,
+                });
             }
         };
 
@@ -323,17 +513,11 @@ pub fn get_aati_config() -> Option<String> {
         match writeln!(aati_config_file, "{}", default_config) {
             Ok(_) => {}
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO WRITE INTO FILE '{}'! ERROR[27]: {}",
-                        &aati_config_path_buf.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-
-                exit(1);
+                return Err(AatiError::WriteFile {
+                    code: 27,
+                    path: aati_config_path_buf,
+                    error,
+                });
             }
         }
     }
@@ -343,24 +527,30 @@ pub fn get_aati_config() -> Option<String> {
     let aati_config = match read_to_string(&aati_config_path_buf) {
         Ok(content) => content,
         Err(error) => {
-            eprintln!(
-                "{}",
-                format!(
-                    "- FAILED TO READ FILE '{}'! ERROR[28]: {}",
-                    aati_config_path_buf.display(),
-                    error
-                )
-                .bright_red()
-            );
-
-            exit(1);
+            return Err(AatiError::ReadFile {
+                code: 28,
+                path: aati_config_path_buf,
+                error,
+            });
         }
     };
 
-    Some(aati_config.trim().into())
+    Ok(aati_config.trim().into())
 }
 
-pub fn prompt(prompt_text: &str) -> String {
+// rc.toml parsed as TOML, failing with the given code if it isn't valid
+pub fn parse_aati_config(code: u16) -> Result<Value, AatiError> {
+    match get_aati_config()?.parse() {
+        Ok(aati_config) => Ok(aati_config),
+        Err(error) => Err(AatiError::ParseToml {
+            code,
+            path: get_aati_config_path_buf(),
+            error: Box::new(error),
+        }),
+    }
+}
+
+pub fn prompt(prompt_text: &str) -> Result<String, AatiError> {
     print!("{}", format!("{} ", prompt_text).as_str().bright_blue());
     stdout().flush().unwrap();
 
@@ -368,19 +558,13 @@ pub fn prompt(prompt_text: &str) -> String {
     match stdin().read_line(&mut input) {
         Ok(_) => {}
 
-        Err(error) => {
-            eprintln!(
-                "{}",
-                format!("- DIDN'T RECEIVE VALID INPUT! ERROR[3]: {}", error).bright_red()
-            );
-            exit(1)
-        }
+        Err(error) => return Err(AatiError::Input { code: 3, error }),
     };
 
-    input.trim().into()
+    Ok(input.trim().into())
 }
 
-pub fn prompt_yn(prompt_text: &str) -> bool {
+pub fn prompt_yn(prompt_text: &str) -> Result<bool, AatiError> {
     print!("{}", format!("{} [Y/n] ", prompt_text).as_str().yellow());
     stdout().flush().unwrap();
 
@@ -388,20 +572,104 @@ pub fn prompt_yn(prompt_text: &str) -> bool {
     match stdin().read_line(&mut input) {
         Ok(_) => {}
 
-        Err(error) => {
-            eprintln!(
-                "{}",
-                format!("- DIDN'T RECEIVE VALID INPUT! ERROR[4]: {}", error).bright_red()
-            );
-            exit(1)
-        }
+        Err(error) => return Err(AatiError::Input { code: 4, error }),
     };
 
-    input.trim().is_empty() || input.trim().to_lowercase() == "y"
+    Ok(input.trim().is_empty() || input.trim().to_lowercase() == "y")
+}
+
+// A yes/no question that defaults to no: only an explicit "y" or "yes" counts,
+// and nothing is asked when stdin isn't a terminal, e.g. in a cron job
+pub fn confirm(prompt_text: &str) -> io::Result<bool> {
+    let stdin = stdin();
+    let is_terminal = stdin.is_terminal();
+
+    read_confirmation(prompt_text, &mut stdin.lock(), is_terminal)
+}
+
+fn read_confirmation(
+    prompt_text: &str,
+    input: &mut impl BufRead,
+    is_terminal: bool,
+) -> io::Result<bool> {
+    if !is_terminal {
+        return Ok(false);
+    }
+
+    print!("{}", format!("{} [y/N] ", prompt_text).as_str().yellow());
+    stdout().flush().unwrap();
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+// Point a package found using extract_package() at the newest version that
+// satisfies the requirement
+pub fn apply_requirement(
+    extracted_package: &mut [String],
+    requirement: &str,
+    added_repos: &[Value],
+) -> Result<(), AatiError> {
+    let version_req = parse_requirement(requirement)?;
+
+    let matching_version = added_repos
+        .iter()
+        .filter(|repo| repo["repo"]["name"].as_str().unwrap() == extracted_package[0])
+        .flat_map(|repo| repo["index"]["packages"].as_array().unwrap())
+        .find(|pkg| {
+            pkg["name"].as_str().unwrap() == extracted_package[1]
+                && pkg["target"].as_str().unwrap() == extracted_package[3]
+        })
+        .and_then(|pkg| get_matching_version(pkg["versions"].as_array().unwrap(), &version_req));
+
+    match matching_version {
+        Some(version) => {
+            extracted_package[2] = version["tag"].as_str().unwrap().into();
+
+            Ok(())
+        }
+        None => Err(AatiError::NoMatchingVersion {
+            package_name: extracted_package[1].clone(),
+            requirement: requirement.into(),
+        }),
+    }
+}
+
+// The newest version an installed package can be upgraded to, staying
+// within the requirement it was installed with if there's one
+pub fn get_allowed_version<'a>(
+    installed_package: &Value,
+    versions: &'a [Value],
+) -> Option<&'a Value> {
+    match installed_package
+        .get("requirement")
+        .and_then(|r| r.as_str())
+    {
+        Some(requirement) => get_matching_version(versions, &VersionReq::parse(requirement).ok()?),
+        None => get_latest_version(versions),
+    }
 }
 
 // This function goes hard. Feel free to copy & paste.
-pub fn extract_package(text: &str, added_repos: &Vec<Value>) -> Option<Vec<String>> {
+pub fn extract_package(
+    text: &str,
+    added_repos: &Vec<Value>,
+) -> Result<Option<Vec<String>>, AatiError> {
+    // name@requirement: find the package as usual, then swap its latest
+    // version for the newest one that satisfies the requirement
+    if let (package, Some(requirement)) = split_requirement(text) {
+        let mut extracted_package = match extract_package(package, added_repos)? {
+            Some(extracted_package) => extracted_package,
+            None => return Ok(None),
+        };
+
+        apply_requirement(&mut extracted_package, requirement, added_repos)?;
+
+        return Ok(Some(extracted_package));
+    }
+
     let mut repo_name = "$unprovided$";
     let mut name;
     let mut version;
@@ -445,16 +713,21 @@ pub fn extract_package(text: &str, added_repos: &Vec<Value>) -> Option<Vec<Strin
                             {
                                 results.push(Package {
                                     name: available_package["name"].as_str().unwrap().into(),
-                                    version: available_package["versions"]
-                                        .as_array()
-                                        .unwrap()
-                                        .first()
-                                        .unwrap()["tag"]
+                                    version: get_latest_version(
+                                        available_package["versions"].as_array().unwrap(),
+                                    )
+                                    .unwrap()["tag"]
                                         .as_str()
                                         .unwrap()
                                         .into(),
                                     source: added_repo["repo"]["name"].as_str().unwrap().into(),
                                     target: available_package["target"].as_str().unwrap().into(),
+                                    reason: InstallReason::Explicit,
+                                    requirement: None,
+                                    held: false,
+                                    checksum: None,
+                                    dependencies: vec![],
+                                    files: vec![],
                                     pkgfile: Pkgfile {
                                         data: HashMap::new(),
                                         installation_lines: vec![],
@@ -479,16 +752,21 @@ pub fn extract_package(text: &str, added_repos: &Vec<Value>) -> Option<Vec<Strin
                             {
                                 results.push(Package {
                                     name: available_package["name"].as_str().unwrap().into(),
-                                    version: available_package["versions"]
-                                        .as_array()
-                                        .unwrap()
-                                        .first()
-                                        .unwrap()["tag"]
+                                    version: get_latest_version(
+                                        available_package["versions"].as_array().unwrap(),
+                                    )
+                                    .unwrap()["tag"]
                                         .as_str()
                                         .unwrap()
                                         .into(),
                                     source: added_repo["repo"]["name"].as_str().unwrap().into(),
                                     target: available_package["target"].as_str().unwrap().into(),
+                                    reason: InstallReason::Explicit,
+                                    requirement: None,
+                                    held: false,
+                                    checksum: None,
+                                    dependencies: vec![],
+                                    files: vec![],
                                     pkgfile: Pkgfile {
                                         data: HashMap::new(),
                                         installation_lines: vec![],
@@ -529,6 +807,12 @@ pub fn extract_package(text: &str, added_repos: &Vec<Value>) -> Option<Vec<Strin
                                                 .as_str()
                                                 .unwrap()
                                                 .into(),
+                                            reason: InstallReason::Explicit,
+                                            requirement: None,
+                                            held: false,
+                                            checksum: None,
+                                            dependencies: vec![],
+                                            files: vec![],
                                             pkgfile: Pkgfile {
                                                 data: HashMap::new(),
                                                 installation_lines: vec![],
@@ -553,16 +837,21 @@ pub fn extract_package(text: &str, added_repos: &Vec<Value>) -> Option<Vec<Strin
                         {
                             results.push(Package {
                                 name: available_package["name"].as_str().unwrap().into(),
-                                version: available_package["versions"]
-                                    .as_array()
-                                    .unwrap()
-                                    .first()
-                                    .unwrap()["tag"]
+                                version: get_latest_version(
+                                    available_package["versions"].as_array().unwrap(),
+                                )
+                                .unwrap()["tag"]
                                     .as_str()
                                     .unwrap()
                                     .into(),
                                 source: added_repo["repo"]["name"].as_str().unwrap().into(),
                                 target: available_package["target"].as_str().unwrap().into(),
+                                reason: InstallReason::Explicit,
+                                requirement: None,
+                                held: false,
+                                checksum: None,
+                                dependencies: vec![],
+                                files: vec![],
                                 pkgfile: Pkgfile {
                                     data: HashMap::new(),
                                     installation_lines: vec![],
@@ -586,16 +875,21 @@ pub fn extract_package(text: &str, added_repos: &Vec<Value>) -> Option<Vec<Strin
                         {
                             results.push(Package {
                                 name: available_package["name"].as_str().unwrap().into(),
-                                version: available_package["versions"]
-                                    .as_array()
-                                    .unwrap()
-                                    .first()
-                                    .unwrap()["tag"]
+                                version: get_latest_version(
+                                    available_package["versions"].as_array().unwrap(),
+                                )
+                                .unwrap()["tag"]
                                     .as_str()
                                     .unwrap()
                                     .into(),
                                 source: added_repo["repo"]["name"].as_str().unwrap().into(),
                                 target: available_package["target"].as_str().unwrap().into(),
+                                reason: InstallReason::Explicit,
+                                requirement: None,
+                                held: false,
+                                checksum: None,
+                                dependencies: vec![],
+                                files: vec![],
                                 pkgfile: Pkgfile {
                                     data: HashMap::new(),
                                     installation_lines: vec![],
@@ -626,6 +920,12 @@ pub fn extract_package(text: &str, added_repos: &Vec<Value>) -> Option<Vec<Strin
                                             .as_str()
                                             .unwrap()
                                             .into(),
+                                        reason: InstallReason::Explicit,
+                                        requirement: None,
+                                        held: false,
+                                        checksum: None,
+                                        dependencies: vec![],
+                                        files: vec![],
                                         pkgfile: Pkgfile {
                                             data: HashMap::new(),
                                             installation_lines: vec![],
@@ -641,11 +941,7 @@ pub fn extract_package(text: &str, added_repos: &Vec<Value>) -> Option<Vec<Strin
                 }
             }
         } else {
-            eprintln!(
-                "{}",
-                "- YOU HAVE NO REPOSITORIES SET! TRY: aati repo add <repo url>".bright_red()
-            );
-            exit(1);
+            return Err(AatiError::NoRepositories);
         }
 
         // Check for conflicts
@@ -668,11 +964,10 @@ pub fn extract_package(text: &str, added_repos: &Vec<Value>) -> Option<Vec<Strin
                         if available_package["name"].as_str().unwrap() == name
                             && is_supported(available_package["target"].as_str().unwrap())
                         {
-                            version = available_package["versions"]
-                                .as_array()
-                                .unwrap()
-                                .first()
-                                .unwrap()["tag"]
+                            version = get_latest_version(
+                                available_package["versions"].as_array().unwrap(),
+                            )
+                            .unwrap()["tag"]
                                 .as_str()
                                 .unwrap();
                         }
@@ -684,23 +979,22 @@ pub fn extract_package(text: &str, added_repos: &Vec<Value>) -> Option<Vec<Strin
                         if available_package["name"].as_str().unwrap() == text
                             && is_supported(available_package["target"].as_str().unwrap())
                         {
-                            version = available_package["versions"]
-                                .as_array()
-                                .unwrap()
-                                .first()
-                                .unwrap()["tag"]
+                            version = get_latest_version(
+                                available_package["versions"].as_array().unwrap(),
+                            )
+                            .unwrap()["tag"]
                                 .as_str()
                                 .unwrap();
                         }
                     }
                 }
 
-                Some(vec![
+                Ok(Some(vec![
                     repo_name.into(),
                     name.into(),
                     version.into(),
                     found_package.target.clone(),
-                ])
+                ]))
             } else if repo_name == "$unprovided$" {
                 let conflicts: Vec<_> = results
                     .iter()
@@ -734,7 +1028,7 @@ pub fn extract_package(text: &str, added_repos: &Vec<Value>) -> Option<Vec<Strin
                         conflict[4]
                     );
                 }
-                let input = prompt("* Enter the number of the package you choose:");
+                let input = prompt("* Enter the number of the package you choose:")?;
                 match input.parse::<usize>() {
                     Ok(response) => {
                         let mut is_valid = false;
@@ -745,45 +1039,34 @@ pub fn extract_package(text: &str, added_repos: &Vec<Value>) -> Option<Vec<Strin
                         }
                         if is_valid {
                             let result_package = conflicts[response - 1].clone();
-                            Some(vec![
+                            Ok(Some(vec![
                                 result_package[3].clone(),
                                 result_package[1].clone(),
                                 result_package[2].clone(),
                                 result_package[4].clone(),
-                            ])
+                            ]))
                         } else {
-                            eprintln!("{}", "- INVALID CHOICE!".bright_red());
-                            exit(1);
+                            Err(AatiError::InvalidChoice)
                         }
                     }
-                    Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!("- FAILED TO PARSE INPUT! ERROR[10]: {}", error).bright_red()
-                        );
-                        exit(1);
-                    }
+                    Err(error) => Err(AatiError::ParseInput { code: 10, error }),
                 }
             } else {
                 match results.iter().find(|pkg| pkg.source == repo_name) {
-                    Some(result_package) => Some(vec![
+                    Some(result_package) => Ok(Some(vec![
                         result_package.source.clone(),
                         result_package.name.clone(),
                         result_package.version.clone(),
                         result_package.target.clone(),
-                    ]),
-                    None => {
-                        eprintln!("{}", "- PACKAGE REPOSITORY NOT FOUND!".bright_red());
-                        exit(1);
-                    }
+                    ])),
+                    None => Err(AatiError::PackageNotFound(text.into())),
                 }
             }
         } else {
-            None
+            Ok(None)
         }
     } else {
-        eprintln!("{}", "- UNEXPECTED BEHAVIOUR!".bright_red());
-        exit(1);
+        Err(AatiError::PackageNotFound(text.into()))
     }
 }
 
@@ -809,67 +1092,67 @@ packages = [
     let added_repos = vec![repo_config];
 
     assert_eq!(
-        extract_package("calculator", &added_repos),
+        extract_package("calculator", &added_repos).unwrap(),
         Some(vec![
             "testing".into(),
             "calculator".into(),
             "0.1.1".into(),
-            get_target().into()
+            get_target()
         ])
     );
 
     assert_eq!(
-        extract_package("calculator-0.1.0", &added_repos),
+        extract_package("calculator-0.1.0", &added_repos).unwrap(),
         Some(vec![
             "testing".into(),
             "calculator".into(),
             "0.1.0".into(),
-            get_target().into()
+            get_target()
         ])
     );
 
     assert_eq!(
-        extract_package("calculator-0.1.1", &added_repos),
+        extract_package("calculator-0.1.1", &added_repos).unwrap(),
         Some(vec![
             "testing".into(),
             "calculator".into(),
             "0.1.1".into(),
-            get_target().into()
+            get_target()
         ])
     );
 
     assert_eq!(
-        extract_package("testing/calculator", &added_repos),
+        extract_package("testing/calculator", &added_repos).unwrap(),
         Some(vec![
             "testing".into(),
             "calculator".into(),
             "0.1.1".into(),
-            get_target().into()
+            get_target()
         ])
     );
 
     assert_eq!(
-        extract_package("testing/calculator-0.1.0", &added_repos),
+        extract_package("testing/calculator-0.1.0", &added_repos).unwrap(),
         Some(vec![
             "testing".into(),
             "calculator".into(),
             "0.1.0".into(),
-            get_target().into()
+            get_target()
         ])
     );
 
     assert_eq!(
-        extract_package("testing/calculator-0.1.1", &added_repos),
+        extract_package("testing/calculator-0.1.1", &added_repos).unwrap(),
         Some(vec![
             "testing".into(),
             "calculator".into(),
             "0.1.1".into(),
-            get_target().into()
+            get_target()
         ])
     );
 
     assert_eq!(
-        extract_package("testing-package", &added_repos),
+        extract_package("testing-package", &added_repos).unwrap(),
         Some(vec![
             "testing".into(),
             "testing-package".into(),
@@ -879,7 +1162,7 @@ packages = [
     );
 
     assert_eq!(
-        extract_package("testing-package-0.1.0", &added_repos),
+        extract_package("testing-package-0.1.0", &added_repos).unwrap(),
         Some(vec![
             "testing".into(),
             "testing-package".into(),
@@ -889,7 +1172,7 @@ packages = [
     );
 
     assert_eq!(
-        extract_package("testing/testing-package", &added_repos),
+        extract_package("testing/testing-package", &added_repos).unwrap(),
         Some(vec![
             "testing".into(),
             "testing-package".into(),
@@ -899,7 +1182,7 @@ packages = [
     );
 
     assert_eq!(
-        extract_package("testing/testing-package-0.1.0", &added_repos),
+        extract_package("testing/testing-package-0.1.0", &added_repos).unwrap(),
         Some(vec![
             "testing".into(),
             "testing-package".into(),
@@ -908,10 +1191,13 @@ packages = [
         ])
     );
 
-    assert_eq!(extract_package("unknown-package", &added_repos), None);
+    assert_eq!(
+        extract_package("unknown-package", &added_repos).unwrap(),
+        None
+    );
 }
 
-pub fn make_executable(_installation_path_buf: &PathBuf) {
+pub fn make_executable(_installation_path_buf: &PathBuf) -> Result<(), ExecutionError> {
     #[cfg(not(target_os = "windows"))]
     {
         use std::{
@@ -922,39 +1208,24 @@ pub fn make_executable(_installation_path_buf: &PathBuf) {
         let metadata = match metadata(_installation_path_buf) {
             Ok(metadata) => metadata,
             Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO GET METADATA OF FILE '{}'! ERROR[42]: {}",
-                        &_installation_path_buf.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-
-                exit(1);
+                return Err(ExecutionError::Metadata {
+                    path: _installation_path_buf.clone(),
+                    error,
+                })
             }
         };
 
         let mut permissions = metadata.permissions();
         permissions.set_mode(0o755);
-        match set_permissions(_installation_path_buf, permissions) {
-            Ok(_) => {}
-            Err(error) => {
-                eprintln!(
-                    "{}",
-                    format!(
-                        "- FAILED TO SET PERMISSIONS OF FILE '{}'! ERROR[43]: {}",
-                        &_installation_path_buf.display(),
-                        error
-                    )
-                    .bright_red()
-                );
-
-                exit(1);
-            }
+        if let Err(error) = set_permissions(_installation_path_buf, permissions) {
+            return Err(ExecutionError::Permissions {
+                path: _installation_path_buf.clone(),
+                error,
+            });
         }
     }
+
+    Ok(())
 }
 
 pub fn parse_pkgfile(pkgfile: &str) -> Pkgfile {
@@ -1008,166 +1279,903 @@ pub fn parse_pkgfile(pkgfile: &str) -> Pkgfile {
     }
 }
 
+// Execute the lines of a PKGFILE section. If one fails, the changes made by
+// the previous ones are rolled back. Otherwise the transaction is handed back
+// still open, to be committed once the Lockfile agrees with what was done.
 pub fn execute_lines(
     lines: &Vec<String>,
     data: &HashMap<String, String>,
     package_directory_path_buf: Option<&PathBuf>,
+    policy: &ScriptPolicy,
     quiet: bool,
-) {
+) -> Result<(Vec<InstalledFile>, Transaction), AatiError> {
+    let mut transaction = Transaction::begin();
+    let mut installed_files: Vec<InstalledFile> = Vec::new();
+
     for line in lines {
-        let mut line = line
-            .replace("$bin_dir", get_bin_path_buf().to_str().unwrap())
-            .replace("$lib_dir", get_lib_path_buf().to_str().unwrap())
-            .replace("$home_dir", home_dir().unwrap().to_str().unwrap());
+        if let Err(error) = execute_line(
+            line,
+            data,
+            package_directory_path_buf,
+            policy,
+            &mut transaction,
+            &mut installed_files,
+            quiet,
+        ) {
+            let ran_system_commands = transaction.ran_system_commands();
+            transaction.rollback(quiet);
+
+            return Err(AatiError::Execution {
+                error,
+                ran_system_commands,
+            });
+        }
+    }
+
+    Ok((installed_files, transaction))
+}
+
+// Add a newly installed package to the Lockfile
+pub fn add_to_lockfile(package: Package, read_code: u16, write_code: u16) -> Result<(), AatiError> {
+    let aati_lock_path_buf = get_aati_lock_path_buf();
 
-        for (key, value) in data {
-            line = line.replace(&format!("${}", key), value);
+    let lock_file_str = match read_to_string(&aati_lock_path_buf) {
+        Ok(contents) => contents,
+        Err(error) => {
+            return Err(AatiError::ReadFile {
+                code: read_code,
+                path: aati_lock_path_buf,
+                error,
+            });
         }
+    };
+    let mut lock_file: LockFile = toml::from_str(&lock_file_str).unwrap();
+
+    lock_file.package.push(package);
+
+    let toml_str = toml::to_string(&lock_file).unwrap();
+    match write_atomically(&aati_lock_path_buf, &toml_str) {
+        Ok(_) => Ok(()),
+        Err(error) => Err(AatiError::WriteFile {
+            code: write_code,
+            path: aati_lock_path_buf,
+            error,
+        }),
+    }
+}
 
-        let tokens: Vec<&str> = line.split_whitespace().collect();
+// Change the Lockfile entry of an installed package, if there's one
+pub fn update_installed_package(
+    package_name: &str,
+    update: impl FnOnce(&mut Package),
+) -> Result<(), AatiError> {
+    let aati_lock_path_buf = get_aati_lock_path_buf();
 
-        match tokens[0] {
-            "install" => {
-                if let Some(ref package_directory_path_buf) = package_directory_path_buf {
-                    let mut source_path_buf = PathBuf::from(package_directory_path_buf);
-                    source_path_buf.push(tokens[1]);
-
-                    let destination = tokens[2..].join(" ");
-                    let destination_path_buf = PathBuf::from(destination);
-
-                    match copy(source_path_buf, &destination_path_buf) {
-                        Ok(_) => {}
-                        Err(error) => {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                    "- FAILED TO WRITE INTO FILE '{}'! ERROR[91]: {}",
-                                    &destination_path_buf.display(),
-                                    error
-                                )
-                                .bright_red()
-                            );
+    let lock_file_str = match read_to_string(&aati_lock_path_buf) {
+        Ok(contents) => contents,
+        Err(error) => {
+            return Err(AatiError::ReadFile {
+                code: 104,
+                path: aati_lock_path_buf,
+                error,
+            });
+        }
+    };
+    let mut lock_file: LockFile = toml::from_str(&lock_file_str).unwrap();
 
-                            exit(1);
-                        }
-                    }
+    if let Some(package) = lock_file
+        .package
+        .iter_mut()
+        .find(|package| package.name == package_name)
+    {
+        update(package);
+    }
+
+    let toml_str = toml::to_string_pretty(&lock_file).unwrap();
+    match write_atomically(&aati_lock_path_buf, &toml_str) {
+        Ok(_) => Ok(()),
+        Err(error) => Err(AatiError::WriteFile {
+            code: 106,
+            path: aati_lock_path_buf,
+            error,
+        }),
+    }
+}
 
-                    make_executable(&destination_path_buf);
+fn execute_line(
+    line: &str,
+    data: &HashMap<String, String>,
+    package_directory_path_buf: Option<&PathBuf>,
+    policy: &ScriptPolicy,
+    transaction: &mut Transaction,
+    installed_files: &mut Vec<InstalledFile>,
+    quiet: bool,
+) -> Result<(), ExecutionError> {
+    let line = expand_line(line, data);
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+
+    match tokens[0] {
+        "install" => {
+            if let Some(package_directory_path_buf) = package_directory_path_buf {
+                let mut source_path_buf = PathBuf::from(package_directory_path_buf);
+                source_path_buf.push(tokens[1]);
+
+                let destination = tokens[2..].join(" ");
+                let destination_path_buf = PathBuf::from(destination);
+
+                transaction.prepare_write(&destination_path_buf)?;
+
+                if let Err(error) = copy(source_path_buf, &destination_path_buf) {
+                    return Err(ExecutionError::Install {
+                        destination: destination_path_buf,
+                        error,
+                    });
                 }
-            }
 
-            "copy" => {
-                if let Some(ref package_directory_path_buf) = package_directory_path_buf {
-                    let mut source_path_buf = PathBuf::from(package_directory_path_buf);
-                    source_path_buf.push(tokens[1]);
-
-                    let destination = tokens[2..].join(" ");
-                    let destination_path_buf = PathBuf::from(destination);
-
-                    match copy(source_path_buf, &destination_path_buf) {
-                        Ok(_) => {}
-                        Err(error) => {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                    "- FAILED TO WRITE INTO FILE '{}'! ERROR[100]: {}",
-                                    &destination_path_buf.display(),
-                                    error
-                                )
-                                .bright_red()
-                            );
+                make_executable(&destination_path_buf)?;
 
-                            exit(1);
-                        }
-                    }
-                }
+                record_installed_file(&destination_path_buf, installed_files)?;
             }
+        }
 
-            "delete" => {
-                let path = &tokens[1..].join(" ");
+        "copy" => {
+            if let Some(package_directory_path_buf) = package_directory_path_buf {
+                let mut source_path_buf = PathBuf::from(package_directory_path_buf);
+                source_path_buf.push(tokens[1]);
 
-                match remove_file(path) {
-                    Ok(_) => {}
-                    Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!("- FAILED TO DELETE FILE {}! ERROR[92]: {}", path, error)
-                                .as_str()
-                                .bright_red()
-                        );
-                        exit(1);
-                    }
+                let destination = tokens[2..].join(" ");
+                let destination_path_buf = PathBuf::from(destination);
+
+                transaction.prepare_write(&destination_path_buf)?;
+
+                if let Err(error) = copy(source_path_buf, &destination_path_buf) {
+                    return Err(ExecutionError::Copy {
+                        destination: destination_path_buf,
+                        error,
+                    });
                 }
+
+                record_installed_file(&destination_path_buf, installed_files)?;
             }
+        }
 
-            "system" => {
-                let mut command = if !cfg!(windows) {
-                    Command::new("sh")
-                } else {
-                    Command::new("cmd.exe")
-                };
+        "delete" => {
+            let path = PathBuf::from(tokens[1..].join(" "));
 
-                if !cfg!(windows) {
-                    command.arg("-c")
-                } else {
-                    command.arg("/C")
-                };
+            transaction.delete(&path)?;
 
-                let mut line_copy = line.clone();
+            installed_files.retain(|file| Path::new(&file.path) != path);
+        }
 
-                command.arg(line_copy.split_off(7)).stderr(Stdio::inherit());
+        "system" => {
+            let system_line = &line[7..];
 
-                if !quiet {
-                    command.stdout(Stdio::inherit());
+            let is_allowed = match policy.system_commands {
+                SystemCommands::Allow => true,
+                SystemCommands::Prompt => {
+                    match confirm(&format!("/ Run the system command `{}`?", system_line)) {
+                        Ok(is_allowed) => is_allowed,
+                        Err(error) => return Err(ExecutionError::Input { line, error }),
+                    }
                 }
+                SystemCommands::Deny => false,
+            };
 
-                if let Some(package_directory_path_buf) = package_directory_path_buf {
-                    command.current_dir(package_directory_path_buf);
+            if !is_allowed {
+                if !quiet {
+                    eprintln!(
+                        "{}",
+                        format!("+ Skipped the system command `{}`", system_line).yellow()
+                    );
                 }
 
-                match command.output() {
-                    Ok(output) => output,
-                    Err(error) => {
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "- FAILED RUNNING COMMAND: '{}'! GIVEN ERROR: {}",
-                                line, error
-                            )
-                            .bright_red()
-                        );
-                        exit(1);
+                return Ok(());
+            }
+
+            transaction.record_system_command();
+
+            let mut command = if !cfg!(windows) {
+                Command::new("sh")
+            } else {
+                Command::new("cmd.exe")
+            };
+
+            if !cfg!(windows) {
+                command.arg("-c")
+            } else {
+                command.arg("/C")
+            };
+
+            command
+                .arg(system_line)
+                .stdin(Stdio::null())
+                .stderr(Stdio::inherit())
+                .stdout(if quiet {
+                    Stdio::null()
+                } else {
+                    Stdio::inherit()
+                });
+
+            // Nothing from the user's environment leaks into it except what
+            // a shell needs to work
+            command.env_clear();
+
+            for variable in SYSTEM_ENVIRONMENT {
+                if let Some(value) = env::var_os(variable) {
+                    command.env(variable, value);
+                }
+            }
+
+            if let Some(package_directory_path_buf) = package_directory_path_buf {
+                command.current_dir(package_directory_path_buf);
+            }
+
+            // In its own process group, so that whatever it starts can be
+            // killed along with it when it times out
+            #[cfg(not(target_os = "windows"))]
+            {
+                use std::os::unix::process::CommandExt;
+
+                command.process_group(0);
+            }
+
+            let mut child = match command.spawn() {
+                Ok(child) => child,
+                Err(error) => return Err(ExecutionError::Spawn { line, error }),
+            };
+
+            let started = Instant::now();
+
+            let status = loop {
+                match child.try_wait() {
+                    Ok(Some(status)) => break status,
+                    Ok(None) if started.elapsed() >= policy.timeout => {
+                        #[cfg(not(target_os = "windows"))]
+                        let _ = Command::new("kill")
+                            .args(["-KILL", "--", &format!("-{}", child.id())])
+                            .stderr(Stdio::null())
+                            .status();
+
+                        let _ = child.kill();
+                        let _ = child.wait();
+
+                        return Err(ExecutionError::Timeout {
+                            line,
+                            seconds: policy.timeout.as_secs(),
+                        });
                     }
-                };
+                    Ok(None) => thread::sleep(Duration::from_millis(50)),
+                    Err(error) => return Err(ExecutionError::Spawn { line, error }),
+                }
+            };
+
+            if !status.success() {
+                return Err(ExecutionError::Status { line, status });
+            }
+        }
+
+        _ => return Err(ExecutionError::InvalidCommand { line }),
+    }
+
+    Ok(())
+}
+
+// Substitute the directory variables and the PKGFILE's [data] into a line
+fn expand_line(line: &str, data: &HashMap<String, String>) -> String {
+    let mut line = line
+        .replace("$bin_dir", get_bin_path_buf().to_str().unwrap())
+        .replace("$lib_dir", get_lib_path_buf().to_str().unwrap())
+        .replace("$home_dir", home_dir().unwrap().to_str().unwrap());
+
+    for (key, value) in data {
+        line = line.replace(&format!("${}", key), value);
+    }
+
+    line
+}
+
+// Destinations of the `install` and `copy` lines, in the order they're written
+pub fn get_destinations(lines: &[String], data: &HashMap<String, String>) -> Vec<PathBuf> {
+    let mut destinations = Vec::new();
+
+    for line in lines {
+        let line = expand_line(line, data);
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+
+        if tokens.len() > 2 && (tokens[0] == "install" || tokens[0] == "copy") {
+            let destination = PathBuf::from(tokens[2..].join(" "));
+
+            if !destinations.contains(&destination) {
+                destinations.push(destination);
+            }
+        }
+    }
+
+    destinations
+}
+
+// A destination that installing a package would clobber. `owner` is None when
+// the file exists on disk but no installed package claims it.
+#[derive(Debug, PartialEq)]
+pub struct FileConflict {
+    pub path: PathBuf,
+    pub owner: Option<String>,
+}
+
+pub fn find_file_conflicts(
+    package_name: &str,
+    destinations: &[PathBuf],
+    installed_packages: &[Package],
+    overwrite: &[Pattern],
+) -> Vec<FileConflict> {
+    let mut conflicts = Vec::new();
+
+    for destination in destinations {
+        if overwrite
+            .iter()
+            .any(|pattern| pattern.matches_path(destination))
+        {
+            continue;
+        }
+
+        let owner = installed_packages.iter().find(|pkg| {
+            pkg.files
+                .iter()
+                .any(|file| Path::new(&file.path) == destination)
+        });
+
+        match owner {
+            // Files of the package that's being replaced are fine to overwrite
+            Some(owner) if owner.name == package_name => {}
+
+            Some(owner) => conflicts.push(FileConflict {
+                path: destination.clone(),
+                owner: Some(owner.name.clone()),
+            }),
+
+            None => {
+                if destination.symlink_metadata().is_ok() {
+                    conflicts.push(FileConflict {
+                        path: destination.clone(),
+                        owner: None,
+                    });
+                }
+            }
+        }
+    }
+
+    conflicts
+}
+
+// Make sure installing these lines won't overwrite anything that the user
+// didn't allow with `--overwrite <glob>`. Prints a report of the conflicting
+// files if it would.
+pub fn check_file_conflicts(
+    package_name: &str,
+    lines: &[String],
+    data: &HashMap<String, String>,
+    overwrite: &[String],
+) -> Result<(), AatiError> {
+    let mut patterns = Vec::new();
+
+    for glob in overwrite {
+        match Pattern::new(glob) {
+            Ok(pattern) => patterns.push(pattern),
+            Err(error) => {
+                return Err(AatiError::InvalidGlob {
+                    code: 111,
+                    pattern: glob.into(),
+                    error,
+                })
+            }
+        }
+    }
+
+    let lock_file: LockFile = toml::from_str(&get_aati_lock()?).unwrap();
+    let conflicts = find_file_conflicts(
+        package_name,
+        &get_destinations(lines, data),
+        &lock_file.package,
+        &patterns,
+    );
+
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!(
+        "{}",
+        format!(
+            "- Installing '{}' would overwrite these files:",
+            package_name
+        )
+        .bright_red()
+    );
+
+    for conflict in &conflicts {
+        match &conflict.owner {
+            Some(owner) => eprintln!(
+                "{}   {} (owned by '{}')",
+                "-".bright_red(),
+                conflict.path.display(),
+                owner
+            ),
+            None => eprintln!(
+                "{}   {} (exists on disk, not owned by any package)",
+                "-".bright_red(),
+                conflict.path.display()
+            ),
+        }
+    }
+
+    eprintln!(
+        "{}",
+        "+ Overwrite them on purpose using: --overwrite <glob>".bright_blue()
+    );
+
+    Err(AatiError::FileConflicts(package_name.into()))
+}
+
+// Write a file through a temporary sibling and a rename, so other processes
+// never see it half-written
+pub fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path_buf = PathBuf::from(temp_path);
+
+    let result = File::create(&temp_path_buf).and_then(|mut file| {
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()
+    });
+
+    match result.and_then(|_| rename(&temp_path_buf, path)) {
+        Ok(_) => Ok(()),
+        Err(error) => {
+            let _ = remove_file(&temp_path_buf);
+            Err(error)
+        }
+    }
+}
+
+#[test]
+fn test_find_file_conflicts() {
+    use std::{env::temp_dir, fs::write};
+
+    let package = |name: &str, path: &str| Package {
+        files: vec![InstalledFile {
+            path: path.into(),
+            size: 0,
+            sha256: String::new(),
+        }],
+        ..Package::test(name)
+    };
+
+    let unowned_path_buf = temp_dir().join(format!("aati-conflict-test-{}", std::process::id()));
+    write(&unowned_path_buf, "").unwrap();
+
+    let installed_packages = vec![
+        package("foo", "/nonexistent/aati/bin/foo"),
+        package("bar", "/nonexistent/aati/bin/bar"),
+    ];
+    let destinations = vec![
+        PathBuf::from("/nonexistent/aati/bin/foo"),
+        PathBuf::from("/nonexistent/aati/bin/bar"),
+        PathBuf::from("/nonexistent/aati/bin/baz"),
+        unowned_path_buf.clone(),
+    ];
+
+    let conflicts = find_file_conflicts("bar", &destinations, &installed_packages, &[]);
+
+    assert_eq!(
+        conflicts,
+        vec![
+            FileConflict {
+                path: PathBuf::from("/nonexistent/aati/bin/foo"),
+                owner: Some("foo".into()),
+            },
+            FileConflict {
+                path: unowned_path_buf.clone(),
+                owner: None,
+            },
+        ]
+    );
+
+    let overwrite = vec![
+        Pattern::new("/nonexistent/aati/bin/*").unwrap(),
+        Pattern::new(unowned_path_buf.to_str().unwrap()).unwrap(),
+    ];
+    assert!(find_file_conflicts("bar", &destinations, &installed_packages, &overwrite).is_empty());
+
+    remove_file(&unowned_path_buf).unwrap();
+}
+
+fn record_installed_file(
+    path: &Path,
+    installed_files: &mut Vec<InstalledFile>,
+) -> Result<(), ExecutionError> {
+    let installed_file = match get_installed_file(path) {
+        Ok(installed_file) => installed_file,
+        Err(error) => {
+            return Err(ExecutionError::Hash {
+                path: path.to_path_buf(),
+                error,
+            })
+        }
+    };
+
+    // A later line writing to the same destination replaces the earlier entry
+    installed_files.retain(|file| file.path != installed_file.path);
+    installed_files.push(installed_file);
+
+    Ok(())
+}
+
+// A checksum from repo.toml. It's the hex digest prefixed with the algorithm
+// it's computed with, e.g. sha512:<hex>, or bare hex for SHA-256.
+pub struct Checksum {
+    pub algorithm: &'static digest::Algorithm,
+    pub hash: String,
+}
+
+impl Checksum {
+    pub fn parse(checksum: &str) -> Option<Checksum> {
+        let (algorithm, hash) = match checksum.trim().split_once(':') {
+            Some(("sha256", hash)) => (&digest::SHA256, hash),
+            Some(("sha384", hash)) => (&digest::SHA384, hash),
+            Some(("sha512", hash)) => (&digest::SHA512, hash),
+            Some(_) => return None,
+            None => (&digest::SHA256, checksum.trim()),
+        };
+
+        if hash.len() != algorithm.output_len() * 2 || !hash.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            return None;
+        }
+
+        Some(Checksum {
+            algorithm,
+            hash: hash.to_lowercase(),
+        })
+    }
+}
+
+// Computes the digest of everything written through it, so a file is hashed
+// while it's being written instead of read back into memory afterwards
+pub struct HashingWriter<W> {
+    inner: W,
+    context: digest::Context,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W, algorithm: &'static digest::Algorithm) -> Self {
+        HashingWriter {
+            inner,
+            context: digest::Context::new(algorithm),
+        }
+    }
+
+    // Hash what's in the file already, like the part of a resumed download
+    pub fn hash_existing(&mut self, reader: &mut impl Read) -> io::Result<()> {
+        let mut buffer = [0; 8192];
+
+        loop {
+            let read = reader.read(&mut buffer)?;
+
+            if read == 0 {
+                return Ok(());
             }
 
+            self.context.update(&buffer[..read]);
+        }
+    }
+
+    pub fn finish(self) -> String {
+        hex::encode(self.context.finish().as_ref())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.context.update(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn hash_file(path: &Path, algorithm: &'static digest::Algorithm) -> io::Result<String> {
+    let mut writer = HashingWriter::new(io::sink(), algorithm);
+    writer.hash_existing(&mut File::open(path)?)?;
+
+    Ok(writer.finish())
+}
+
+// Decode a hex encoded Ed25519 public key
+pub fn parse_public_key(key: &str) -> Result<Vec<u8>, AatiError> {
+    match hex::decode(key.trim()) {
+        Ok(bytes) if bytes.len() == 32 => Ok(bytes),
+        _ => Err(AatiError::InvalidKey(key.into())),
+    }
+}
+
+// Whether a hex encoded detached signature, as found in .sig files, is a
+// valid Ed25519 signature of message by public_key
+pub fn verify_signature(public_key: &[u8], message: &[u8], signature: &str) -> bool {
+    match hex::decode(signature.trim()) {
+        Ok(signature) => signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(message, &signature)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+// What the signature of a package covers: its name, version and SHA512
+// digest, so it can be checked against a digest computed while streaming
+// the .tar.lz4 rather than the whole package read into memory
+pub fn get_signed_message(name: &str, version: &str, sha512: &str) -> String {
+    format!("{}-{} sha512:{}", name, version, sha512)
+}
+
+// A short, comparable form of a public key for showing to the user
+pub fn get_key_fingerprint(public_key: &[u8]) -> String {
+    let hash = hex::encode(digest::digest(&digest::SHA256, public_key));
+
+    hash.as_bytes()[..32]
+        .chunks(4)
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+// Seconds since the Unix epoch as a UTC date and time
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (days, seconds) = ((timestamp / 86400) as i64, timestamp % 86400);
+
+    // Days to a civil date, from Howard Hinnant's date algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+// Unpack a .tar.lz4 package straight from the LZ4 Decoder into a fresh
+// private directory and return that directory. The package's files end up in
+// its `<name>-<version>` subdirectory, nothing is written outside of it.
+pub fn unpack_package(
+    package: File,
+    package_path: &Path,
+    name: &str,
+    version: &str,
+) -> Result<PathBuf, AatiError> {
+    let extraction_directory = create_extraction_directory()?;
+
+    if let Err(error) = unpack_entries(
+        package,
+        package_path,
+        &format!("{}-{}", name, version),
+        &extraction_directory,
+    ) {
+        let _ = remove_dir_all(&extraction_directory);
+
+        return Err(error);
+    }
+
+    Ok(extraction_directory)
+}
+
+fn unpack_entries(
+    package: File,
+    package_path: &Path,
+    top_level_directory: &str,
+    extraction_directory: &Path,
+) -> Result<(), AatiError> {
+    let extract_error = |error| AatiError::Extract {
+        code: 89,
+        path: package_path.to_path_buf(),
+        error,
+    };
+
+    let decoder = match Decoder::new(package) {
+        Ok(decoder) => decoder,
+        Err(error) => {
+            return Err(AatiError::Decode {
+                code: 36,
+                path: package_path.to_path_buf(),
+                error,
+            });
+        }
+    };
+
+    let mut archive = Archive::new(decoder);
+
+    for entry in archive.entries().map_err(extract_error)? {
+        let mut entry = entry.map_err(extract_error)?;
+        let entry_path = entry.path().map_err(extract_error)?.into_owned();
+
+        let link_name = entry
+            .link_name()
+            .map_err(extract_error)?
+            .map(|link_name| link_name.into_owned());
+
+        if let Err(reason) = check_archive_entry(
+            &entry_path,
+            entry.header().entry_type(),
+            link_name.as_deref(),
+            top_level_directory,
+        ) {
+            return Err(AatiError::UnsafeArchive {
+                path: package_path.to_path_buf(),
+                reason,
+            });
+        }
+
+        entry
+            .unpack_in(extraction_directory)
+            .map_err(extract_error)?;
+    }
+
+    Ok(())
+}
+
+// Why an archive entry can't be unpacked, if it can't: it has to stay inside
+// the package's top-level directory and can't be a device node or a pipe
+pub fn check_archive_entry(
+    entry_path: &Path,
+    entry_type: EntryType,
+    link_name: Option<&Path>,
+    top_level_directory: &str,
+) -> Result<(), String> {
+    let display = entry_path.display();
+
+    let components = match normalize_components(Vec::new(), entry_path) {
+        Some(components) => components,
+        None => return Err(format!("'{}' points outside of the package", display)),
+    };
+
+    if components.first().map(|c| c.as_str()) != Some(top_level_directory) {
+        return Err(format!(
+            "'{}' is outside of the '{}' directory",
+            display, top_level_directory
+        ));
+    }
+
+    if entry_type.is_block_special() || entry_type.is_character_special() || entry_type.is_fifo() {
+        return Err(format!("'{}' is a device node or a pipe", display));
+    }
+
+    if let Some(link_name) = link_name {
+        // Symlinks are resolved from the directory they're in, hard links
+        // from the root of the archive
+        let base = if entry_type.is_symlink() {
+            components[..components.len() - 1].to_vec()
+        } else {
+            Vec::new()
+        };
+
+        match normalize_components(base, link_name) {
+            Some(target) if target.first().map(|c| c.as_str()) == Some(top_level_directory) => {}
             _ => {
-                eprintln!(
-                    "{}",
-                    format!("- INVALID PKGFILE COMMAND '{}'!", line).bright_red()
-                );
+                return Err(format!(
+                    "'{}' links to '{}' outside of the package",
+                    display,
+                    link_name.display()
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
 
-                exit(1);
+// Apply a relative path to a list of directory names without touching the
+// filesystem. None if it's absolute or climbs above the start.
+fn normalize_components(mut components: Vec<String>, path: &Path) -> Option<Vec<String>> {
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_string_lossy().into()),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                components.pop()?;
             }
+            Component::RootDir | Component::Prefix(_) => return None,
         }
     }
+
+    Some(components)
 }
 
-pub fn get_package_versions(package_name: &str) -> Option<Vec<Value>> {
-    let aati_config: Value = get_aati_config().unwrap().parse().unwrap();
+// A new directory under the temporary directory that only this transaction
+// uses, readable by the current user alone
+fn create_extraction_directory() -> Result<PathBuf, AatiError> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    loop {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.subsec_nanos())
+            .unwrap_or(0);
+
+        let path = temp_dir().join(format!(
+            "aati-{}-{}-{}",
+            process::id(),
+            nanos,
+            COUNTER.fetch_add(1, AtomicOrdering::Relaxed)
+        ));
+
+        let mut builder = DirBuilder::new();
+
+        #[cfg(not(target_os = "windows"))]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+
+            builder.mode(0o700);
+        }
+
+        match builder.create(&path) {
+            Ok(_) => return Ok(path),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(error) => {
+                return Err(AatiError::CreateDirectory {
+                    code: 127,
+                    path,
+                    error,
+                });
+            }
+        }
+    }
+}
+
+// Size and SHA256 checksum of a file on disk, as recorded in the Lockfile
+pub fn get_installed_file(path: &Path) -> io::Result<InstalledFile> {
+    let mut writer = HashingWriter::new(io::sink(), &digest::SHA256);
+    let size = io::copy(&mut File::open(path)?, &mut writer)?;
+
+    Ok(InstalledFile {
+        path: path.to_str().unwrap().into(),
+        size,
+        sha256: writer.finish(),
+    })
+}
+
+pub fn get_package_versions(package_name: &str) -> Result<Option<Vec<Value>>, AatiError> {
+    let aati_config: Value = parse_aati_config(146)?;
     let repo_list = aati_config["sources"]["repos"].as_array().unwrap();
     let mut added_repos: Vec<Value> = Vec::new();
     let mut versions: Vec<Value> = Vec::new();
 
     for repo_info in repo_list {
         added_repos.push(
-            get_repo_config(repo_info["name"].as_str().unwrap())
+            get_repo_config(repo_info["name"].as_str().unwrap())?
                 .parse::<Value>()
                 .unwrap(),
         );
     }
 
-    match extract_package(package_name, &added_repos) {
+    match extract_package(package_name, &added_repos)? {
         Some(package_vec) => {
             let repo_name = &package_vec[0];
             let package_name = &package_vec[1];
@@ -1184,8 +2192,302 @@ pub fn get_package_versions(package_name: &str) -> Option<Vec<Value>> {
                 }
             }
 
-            Some(versions)
+            Ok(Some(versions))
         }
-        None => None,
+        None => Ok(None),
     }
 }
+
+// Find the `versions` entry of a package that was found using extract_package()
+pub fn get_version_entry(extracted_package: &[String], added_repos: &[Value]) -> Option<Value> {
+    let repo = added_repos
+        .iter()
+        .find(|r| r["repo"]["name"].as_str().unwrap() == extracted_package[0])?;
+
+    repo["index"]["packages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|pkg| {
+            pkg["name"].as_str().unwrap() == extracted_package[1]
+                && pkg["target"].as_str().unwrap() == extracted_package[3]
+        })
+        .flat_map(|pkg| pkg["versions"].as_array().unwrap())
+        .find(|version| version["tag"].as_str().unwrap() == extracted_package[2])
+        .cloned()
+}
+
+// Dependencies declared in the [data] section of a PKGFILE, e.g:
+//   depends libfoo, testing/bar-0.1.0
+pub fn get_pkgfile_dependencies(pkgfile: &Pkgfile) -> Vec<String> {
+    match pkgfile.data.get("depends") {
+        Some(depends) => depends
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|dependency| !dependency.is_empty())
+            .map(|dependency| dependency.into())
+            .collect(),
+        None => vec![],
+    }
+}
+
+// Tests that touch the aati home point HOME at a scratch directory, one at a
+// time since every path under it is derived from the environment
+#[cfg(test)]
+static TEST_HOME: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[cfg(test)]
+pub fn use_test_home(name: &str) -> (std::sync::MutexGuard<'static, ()>, PathBuf) {
+    let guard = TEST_HOME.lock().unwrap_or_else(|error| error.into_inner());
+
+    let home = temp_dir().join(format!("aati-test-home-{}-{}", name, process::id()));
+    let _ = remove_dir_all(&home);
+    create_dir_all(&home).unwrap();
+    env::set_var("HOME", &home);
+
+    get_aati_lock().unwrap();
+    get_aati_config().unwrap();
+
+    (guard, home)
+}
+
+#[test]
+fn test_compare_versions() {
+    assert_eq!(compare_versions("0.10.0", "0.9.1"), Ordering::Greater);
+    assert_eq!(compare_versions("1.0.0-beta.1", "1.0.0"), Ordering::Less);
+    assert_eq!(compare_versions("v1.2.0", "1.2.0"), Ordering::Equal);
+
+    // Tags that aren't semver fall back to comparing their components
+    assert_eq!(compare_versions("1.10", "1.9"), Ordering::Greater);
+    assert_eq!(compare_versions("2023.10.1", "2023.9"), Ordering::Greater);
+    assert_eq!(compare_versions("1.0", "1.0.1"), Ordering::Less);
+
+    let versions: Vec<Value> = vec![
+        "tag = \"0.9.0\"".parse().unwrap(),
+        "tag = \"0.10.0\"".parse().unwrap(),
+        "tag = \"0.2.0\"".parse().unwrap(),
+    ];
+
+    assert_eq!(
+        get_latest_version(&versions).unwrap()["tag"].as_str(),
+        Some("0.10.0")
+    );
+}
+
+#[test]
+fn test_get_matching_version() {
+    let versions: Vec<Value> = vec![
+        "tag = \"1.3.0\"".parse().unwrap(),
+        "tag = \"2.0.0\"".parse().unwrap(),
+        "tag = \"1.2.5\"".parse().unwrap(),
+        "tag = \"nightly\"".parse().unwrap(),
+    ];
+
+    let matching_tag = |requirement: &str| {
+        get_matching_version(&versions, &parse_requirement(requirement).unwrap())
+            .map(|version| version["tag"].as_str().unwrap().to_string())
+    };
+
+    assert_eq!(matching_tag("^1"), Some("1.3.0".into()));
+    assert_eq!(matching_tag(">=1.2,<1.3"), Some("1.2.5".into()));
+    assert_eq!(matching_tag("~2.0"), Some("2.0.0".into()));
+    assert_eq!(matching_tag("^3"), None);
+
+    assert_eq!(
+        split_requirement("repo/tool@>=1.2,<2"),
+        ("repo/tool", Some(">=1.2,<2"))
+    );
+    assert_eq!(split_requirement("tool-0.1.0"), ("tool-0.1.0", None));
+    assert!(parse_requirement("!!").is_err());
+}
+
+#[test]
+fn test_hashing_writer() {
+    let abc_sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    let mut written = Vec::new();
+    let mut writer = HashingWriter::new(&mut written, &digest::SHA256);
+    writer.write_all(b"abc").unwrap();
+    assert_eq!(writer.finish(), abc_sha256);
+    assert_eq!(written, b"abc");
+
+    // A resumed download hashes its part first, then what's appended
+    let mut writer = HashingWriter::new(io::sink(), &digest::SHA256);
+    writer.hash_existing(&mut &b"ab"[..]).unwrap();
+    writer.write_all(b"c").unwrap();
+    assert_eq!(writer.finish(), abc_sha256);
+}
+
+#[test]
+fn test_check_archive_entry() {
+    let check = |path: &str, entry_type: EntryType, link_name: Option<&str>| {
+        check_archive_entry(
+            Path::new(path),
+            entry_type,
+            link_name.map(Path::new),
+            "pkg-0.1.0",
+        )
+    };
+
+    assert!(check("pkg-0.1.0/bin/pkg", EntryType::Regular, None).is_ok());
+    assert!(check("./pkg-0.1.0/", EntryType::Directory, None).is_ok());
+    assert!(check("pkg-0.1.0/lib/a/../b", EntryType::Regular, None).is_ok());
+
+    assert!(check("/etc/passwd", EntryType::Regular, None).is_err());
+    assert!(check("pkg-0.1.0/../../x", EntryType::Regular, None).is_err());
+    assert!(check("pkg-0.1.0/../other/x", EntryType::Regular, None).is_err());
+    assert!(check("other-0.1.0/x", EntryType::Regular, None).is_err());
+    assert!(check("pkg-0.1.0/dev", EntryType::Char, None).is_err());
+    assert!(check("pkg-0.1.0/dev", EntryType::Block, None).is_err());
+    assert!(check("pkg-0.1.0/pipe", EntryType::Fifo, None).is_err());
+
+    // Symlinks are relative to their directory, hard links to the archive
+    assert!(check("pkg-0.1.0/bin/l", EntryType::Symlink, Some("../lib/x")).is_ok());
+    assert!(check("pkg-0.1.0/bin/l", EntryType::Symlink, Some("../../x")).is_err());
+    assert!(check("pkg-0.1.0/l", EntryType::Symlink, Some("/etc/passwd")).is_err());
+    assert!(check("pkg-0.1.0/l", EntryType::Link, Some("pkg-0.1.0/bin/pkg")).is_ok());
+    assert!(check("pkg-0.1.0/l", EntryType::Link, Some("/etc/shadow")).is_err());
+}
+
+#[test]
+fn test_verify_signature() {
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    let random = SystemRandom::new();
+    let key_pair = |random| {
+        Ed25519KeyPair::from_pkcs8(Ed25519KeyPair::generate_pkcs8(random).unwrap().as_ref())
+            .unwrap()
+    };
+    let (trusted, other) = (key_pair(&random), key_pair(&random));
+
+    let public_key = parse_public_key(&hex::encode(trusted.public_key())).unwrap();
+    let message = b"[repo]\nname = \"test\"\n";
+    let signature = hex::encode(trusted.sign(message));
+
+    assert!(verify_signature(
+        &public_key,
+        message,
+        &format!("{}\n", signature)
+    ));
+    assert!(!verify_signature(&public_key, b"[repo]\n", &signature));
+    assert!(!verify_signature(
+        &public_key,
+        message,
+        &hex::encode(other.sign(message))
+    ));
+    assert!(!verify_signature(&public_key, message, "not hex"));
+    assert!(parse_public_key("abcd").is_err());
+}
+
+#[test]
+fn test_format_timestamp() {
+    assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+    assert_eq!(format_timestamp(951782400), "2000-02-29 00:00:00 UTC");
+    assert_eq!(format_timestamp(1738324800), "2025-01-31 12:00:00 UTC");
+    assert_eq!(format_timestamp(4102444799), "2099-12-31 23:59:59 UTC");
+}
+
+#[test]
+fn test_parse_checksum() {
+    let abc_sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    let abc_sha384 = "cb00753f45a35e8bb5a03d699ac65007272c32ab0eded1631a8b605a43ff5bed8086072ba1e7cc2358baeca134c825a7";
+
+    let checksum = Checksum::parse(abc_sha256).unwrap();
+    assert_eq!(checksum.algorithm, &digest::SHA256);
+    assert_eq!(checksum.hash, abc_sha256);
+
+    let checksum = Checksum::parse(&format!("sha384:{}", abc_sha384.to_uppercase())).unwrap();
+    assert_eq!(checksum.algorithm, &digest::SHA384);
+    assert_eq!(checksum.hash, abc_sha384);
+
+    assert_eq!(
+        hex::encode(digest::digest(checksum.algorithm, b"abc")),
+        checksum.hash
+    );
+
+    assert!(Checksum::parse(&format!("sha512:{}", abc_sha256)).is_none());
+    assert!(Checksum::parse(&format!("md5:{}", abc_sha256)).is_none());
+    assert!(Checksum::parse("checksum-placeholder").is_none());
+}
+
+#[test]
+#[cfg(not(target_os = "windows"))]
+fn test_system_policy() {
+    let data = HashMap::new();
+    let policy = |system_commands, timeout| ScriptPolicy {
+        system_commands,
+        timeout: Duration::from_secs(timeout),
+    };
+
+    // Denied lines are skipped rather than ran
+    let lines = vec!["system exit 1".to_string()];
+    assert!(execute_lines(&lines, &data, None, &policy(SystemCommands::Deny, 5), true).is_ok());
+    assert!(matches!(
+        execute_lines(&lines, &data, None, &policy(SystemCommands::Allow, 5), true),
+        Err(AatiError::Execution {
+            error: ExecutionError::Status { .. },
+            ran_system_commands: true
+        })
+    ));
+
+    let lines = vec!["system sleep 5".to_string()];
+    assert!(matches!(
+        execute_lines(&lines, &data, None, &policy(SystemCommands::Allow, 0), true),
+        Err(AatiError::Execution {
+            error: ExecutionError::Timeout { .. },
+            ran_system_commands: true
+        })
+    ));
+
+    // Only the minimal environment is passed on
+    let lines = vec![r#"system test -z "$AATI_TEST_SECRET" && test -n "$PATH""#.to_string()];
+    env::set_var("AATI_TEST_SECRET", "secret");
+    assert!(execute_lines(&lines, &data, None, &policy(SystemCommands::Allow, 5), true).is_ok());
+}
+
+#[test]
+#[cfg(not(target_os = "windows"))]
+fn test_read_confirmation() {
+    use std::io::{BufReader, Cursor};
+
+    let dev_null = File::open("/dev/null").unwrap();
+    assert!(!read_confirmation(
+        "/ Run it?",
+        &mut BufReader::new(&dev_null),
+        dev_null.is_terminal()
+    )
+    .unwrap());
+
+    // EOF on a terminal is a no as well
+    assert!(!read_confirmation("/ Run it?", &mut BufReader::new(&dev_null), true).unwrap());
+    assert!(!read_confirmation("/ Run it?", &mut Cursor::new("\n"), true).unwrap());
+    assert!(!read_confirmation("/ Run it?", &mut Cursor::new("nope\n"), true).unwrap());
+    assert!(read_confirmation("/ Run it?", &mut Cursor::new("Yes\n"), true).unwrap());
+    assert!(read_confirmation("/ Run it?", &mut Cursor::new("y\n"), true).unwrap());
+
+    // Prompted `system` lines are skipped when nobody can answer
+    let lines = vec!["system exit 1".to_string()];
+    let policy = ScriptPolicy {
+        system_commands: SystemCommands::Prompt,
+        timeout: Duration::from_secs(5),
+    };
+    if !stdin().is_terminal() {
+        assert!(execute_lines(&lines, &HashMap::new(), None, &policy, true).is_ok());
+    }
+}
+
+#[test]
+fn test_malformed_config() {
+    let (_guard, home) = use_test_home("malformed-config");
+
+    std::fs::write(get_aati_config_path_buf(), "[sources\nrepos = []").unwrap();
+
+    assert!(matches!(
+        is_offline(),
+        Err(AatiError::ParseToml { code: 145, .. })
+    ));
+
+    let _ = remove_dir_all(&home);
+}